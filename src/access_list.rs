@@ -0,0 +1,157 @@
+pub mod access_list {
+    use std::{net::IpAddr, str::FromStr};
+
+    use crate::{IOError, SpecOptions};
+
+    /// An address block such as `10.0.0.0/8` or `2001:db8::/32`. A bare
+    /// address is a block of its own.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Cidr {
+        network: IpAddr,
+        prefix: u8,
+    }
+
+    impl Cidr {
+        pub fn contains(&self, ip: IpAddr) -> bool {
+            // an IPv4 client of a dual stack listener shows as ::ffff:a.b.c.d
+            match (self.network, ip.to_canonical()) {
+                (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                    u32::from(network) & mask == u32::from(ip) & mask
+                }
+                (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                    u128::from(network) & mask == u128::from(ip) & mask
+                }
+                _ => false,
+            }
+        }
+    }
+
+    impl FromStr for Cidr {
+        type Err = String;
+
+        fn from_str(value: &str) -> Result<Cidr, String> {
+            let invalid = || format!("invalid address block : {}. use ip or ip/prefix", value);
+            let (network, prefix) = match value.split_once('/') {
+                Some((network, prefix)) => (network, Some(prefix)),
+                None => (value, None),
+            };
+            let network = IpAddr::from_str(network.trim()).map_err(|_| invalid())?;
+            let max = match network {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128,
+            };
+            let prefix = match prefix {
+                Some(prefix) => u8::from_str(prefix.trim()).map_err(|_| invalid())?,
+                None => max,
+            };
+            if prefix > max {
+                return Err(invalid());
+            }
+            Ok(Cidr { network, prefix })
+        }
+    }
+
+    /// Who may connect to an entry. `?allow=` and `deny=` take comma
+    /// separated address blocks. A client in a denied block is refused, and
+    /// once anything is allowed, so is a client outside the allowed blocks.
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct AccessList {
+        pub allow: Vec<Cidr>,
+        pub deny: Vec<Cidr>,
+    }
+
+    impl AccessList {
+        pub fn from_options(options: &SpecOptions) -> Result<AccessList, IOError> {
+            let blocks = |key: &str| -> Result<Vec<Cidr>, IOError> {
+                options
+                    .get_str(key)
+                    .unwrap_or("")
+                    .split(',')
+                    .filter(|block| !block.trim().is_empty())
+                    .map(|block| Cidr::from_str(block).map_err(IOError::InvalidConfig))
+                    .collect()
+            };
+            Ok(AccessList {
+                allow: blocks("allow")?,
+                deny: blocks("deny")?,
+            })
+        }
+
+        pub fn permits(&self, ip: IpAddr) -> bool {
+            if self.deny.iter().any(|block| block.contains(ip)) {
+                return false;
+            }
+            self.allow.is_empty() || self.allow.iter().any(|block| block.contains(ip))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn contains(block: &str, ip: &str) -> bool {
+            Cidr::from_str(block).unwrap().contains(ip.parse().unwrap())
+        }
+
+        #[test]
+        fn v4_prefixes() {
+            assert!(contains("0.0.0.0/0", "203.0.113.9"));
+            assert!(!contains("0.0.0.0/0", "::1"));
+            assert!(contains("10.1.2.3/32", "10.1.2.3"));
+            assert!(!contains("10.1.2.3/32", "10.1.2.4"));
+            assert!(contains("10.1.2.3", "10.1.2.3"));
+            assert!(contains("10.0.0.0/8", "10.255.255.255"));
+            assert!(!contains("10.0.0.0/8", "11.0.0.0"));
+            assert!(contains("192.168.1.0/23", "192.168.0.200"));
+            assert!(!contains("192.168.1.0/23", "192.168.2.1"));
+        }
+
+        #[test]
+        fn v6_prefixes() {
+            assert!(contains("::/0", "2001:db8::1"));
+            assert!(!contains("::/0", "127.0.0.1"));
+            assert!(contains("2001:db8::1/128", "2001:db8::1"));
+            assert!(!contains("2001:db8::1/128", "2001:db8::2"));
+            assert!(contains("::1", "::1"));
+            assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+            assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        }
+
+        #[test]
+        fn mapped_v4_clients_match_v4_blocks() {
+            assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+            assert!(!contains("10.0.0.0/8", "::ffff:11.1.2.3"));
+            assert!(contains("0.0.0.0/0", "::ffff:203.0.113.9"));
+        }
+
+        #[test]
+        fn invalid_blocks_are_refused() {
+            for block in [
+                "10.0.0.0/33",
+                "::/129",
+                "10.0.0/8",
+                "10.0.0.0/x",
+                "host/8",
+                "",
+            ] {
+                assert!(Cidr::from_str(block).is_err(), "{}", block);
+            }
+        }
+
+        #[test]
+        fn deny_wins_and_allow_closes_the_rest() {
+            let options = SpecOptions::parse("tcp://0.0.0.0:1?allow=10.0.0.0/8,::1&deny=10.0.0.9");
+            let access = AccessList::from_options(&options).unwrap();
+            assert!(access.permits("10.0.0.1".parse().unwrap()));
+            assert!(access.permits("::ffff:10.0.0.1".parse().unwrap()));
+            assert!(access.permits("::1".parse().unwrap()));
+            assert!(!access.permits("10.0.0.9".parse().unwrap()));
+            assert!(!access.permits("192.168.0.1".parse().unwrap()));
+
+            let open = AccessList::from_options(&SpecOptions::parse("tcp://0.0.0.0:1")).unwrap();
+            assert!(open.permits("192.168.0.1".parse().unwrap()));
+        }
+    }
+}
@@ -34,14 +34,16 @@ pub mod base64 {
             self.pipeline_direction = direction;
         }
 
-        fn start(&mut self) {}
+        fn start(&mut self) -> Result<(), IOError> {
+            Ok(())
+        }
     }
 
     impl BoxedClone for Base64 {
         fn bclone(&self) -> Box<dyn PipelineStep> {
             match self.work_mode {
-                PipelineDirection::Forward => Box::new(Base64::new(Some("fw"))),
-                PipelineDirection::Backward => Box::new(Base64::new(Some("bw"))),
+                PipelineDirection::Forward => Box::new(Base64::new(Some("fw")).unwrap()),
+                PipelineDirection::Backward => Box::new(Base64::new(Some("bw")).unwrap()),
             }
         }
     }
@@ -77,7 +79,7 @@ pub mod base64 {
                         self.forward_buffer.extend(data);
                         Ok(data.len())
                     } else {
-                        let b64 = B64_ENGINE.decode(local_buf).unwrap();
+                        let b64 = B64_ENGINE.decode(local_buf).map_err(invalid_data)?;
                         self.forward_buffer.extend(b64.as_slice());
                         Ok(b64.len())
                     }
@@ -89,8 +91,8 @@ pub mod base64 {
                         self.backward_buffer.extend(data);
                         Ok(data.len())
                     } else {
-                        let s = std::str::from_utf8(local_buf).unwrap();
-                        let b64 = B64_ENGINE.decode(s).unwrap();
+                        let s = std::str::from_utf8(local_buf).map_err(invalid_data)?;
+                        let b64 = B64_ENGINE.decode(s).map_err(invalid_data)?;
                         self.backward_buffer.extend(b64.as_slice());
                         Ok(b64.len())
                     }
@@ -104,21 +106,28 @@ pub mod base64 {
     }
 
     impl Base64 {
-        pub fn new(config: Option<&str>) -> Base64 {
+        pub fn new(config: Option<&str>) -> Result<Base64, IOError> {
             let mut work_mode: PipelineDirection = PipelineDirection::Forward;
             match config {
                 Some("fw") => work_mode = PipelineDirection::Forward,
                 Some("bw") => work_mode = PipelineDirection::Backward,
                 Some(_) | None => {
-                    panic!("Base64Encoder : Unknown Work Mode")
+                    return Err(IOError::InvalidStep(format!(
+                        "b64 : unknown work mode : {}",
+                        config.unwrap_or("")
+                    )));
                 }
             }
-            Base64 {
+            Ok(Base64 {
                 forward_buffer: vec![0; 0],
                 backward_buffer: vec![0; 0],
                 work_mode: work_mode,
                 pipeline_direction: PipelineDirection::Forward,
-            }
+            })
         }
     }
+
+    fn invalid_data<E: std::error::Error + Send + Sync + 'static>(error: E) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+    }
 }
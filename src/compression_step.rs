@@ -0,0 +1,465 @@
+pub mod compression_step {
+    use flate2::{write::DeflateEncoder, Decompress, FlushDecompress};
+    use std::io::{self, Write};
+    use zstd::stream::raw::Operation;
+
+    use crate::{
+        pipeline_module::pipeline::{PipelineDirection, PipelineStep},
+        BoxedClone, IOError, Read, SpecOptions,
+    };
+
+    pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+    pub const DEFAULT_DEFLATE_LEVEL: i32 = 6;
+    /// Largest lz4 block, bigger writes are split. Also bounds what a peer
+    /// can make us allocate for one block.
+    pub const MAX_LZ4_BLOCK: usize = 4 * 1024 * 1024;
+    /// Most data one write may decompress to. A few bytes of zstd or deflate
+    /// can stand for gigabytes, the step gives up past this.
+    pub const MAX_DECOMPRESSED: usize = 16 * 1024 * 1024;
+    /// Output produced at once while decompressing.
+    const DECOMPRESS_CHUNK: usize = 64 * 1024;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Algorithm {
+        Zstd,
+        Deflate,
+        Lz4,
+    }
+
+    impl Algorithm {
+        pub fn from_name(name: &str) -> Option<Algorithm> {
+            match name {
+                "zstd" => Some(Algorithm::Zstd),
+                "deflate" => Some(Algorithm::Deflate),
+                "lz4" => Some(Algorithm::Lz4),
+                _ => None,
+            }
+        }
+
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                Algorithm::Zstd => "zstd",
+                Algorithm::Deflate => "deflate",
+                Algorithm::Lz4 => "lz4",
+            }
+        }
+    }
+
+    /// Compresses one direction of a connection as a single stream.
+    enum Encoder {
+        Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+        Deflate(DeflateEncoder<Vec<u8>>),
+        /// Each write is an independent block, prefixed with its length.
+        Lz4,
+    }
+
+    impl Encoder {
+        fn new(algorithm: Algorithm, level: Option<i32>) -> io::Result<Encoder> {
+            match algorithm {
+                Algorithm::Zstd => Ok(Encoder::Zstd(zstd::stream::write::Encoder::new(
+                    Vec::new(),
+                    level.unwrap_or(DEFAULT_ZSTD_LEVEL),
+                )?)),
+                Algorithm::Deflate => Ok(Encoder::Deflate(DeflateEncoder::new(
+                    Vec::new(),
+                    flate2::Compression::new(level.unwrap_or(DEFAULT_DEFLATE_LEVEL) as u32),
+                ))),
+                Algorithm::Lz4 => Ok(Encoder::Lz4),
+            }
+        }
+
+        /// Compresses `data` and flushes the stream, so that the peer can
+        /// decompress all of it without waiting for the next write.
+        fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+            if data.is_empty() {
+                return Ok(Vec::new());
+            }
+            match self {
+                Encoder::Zstd(encoder) => {
+                    encoder.write_all(data)?;
+                    encoder.flush()?;
+                    Ok(std::mem::take(encoder.get_mut()))
+                }
+                Encoder::Deflate(encoder) => {
+                    encoder.write_all(data)?;
+                    encoder.flush()?;
+                    Ok(std::mem::take(encoder.get_mut()))
+                }
+                Encoder::Lz4 => {
+                    let mut output = Vec::new();
+                    for chunk in data.chunks(MAX_LZ4_BLOCK) {
+                        let block = lz4_flex::block::compress_prepend_size(chunk);
+                        output.extend_from_slice(&(block.len() as u32).to_be_bytes());
+                        output.extend_from_slice(&block);
+                    }
+                    Ok(output)
+                }
+            }
+        }
+    }
+
+    /// Decompresses one direction of a connection, whatever way the stream
+    /// was cut into writes.
+    enum Decoder {
+        Zstd(zstd::stream::raw::Decoder<'static>),
+        Deflate(Decompress),
+        /// What was received of a block not complete yet.
+        Lz4(Vec<u8>),
+    }
+
+    impl Decoder {
+        fn new(algorithm: Algorithm) -> io::Result<Decoder> {
+            match algorithm {
+                Algorithm::Zstd => Ok(Decoder::Zstd(zstd::stream::raw::Decoder::new()?)),
+                Algorithm::Deflate => Ok(Decoder::Deflate(Decompress::new(false))),
+                Algorithm::Lz4 => Ok(Decoder::Lz4(Vec::new())),
+            }
+        }
+
+        fn decompress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+            match self {
+                Decoder::Zstd(decoder) => bounded(data, |input, output| {
+                    let status = decoder.run_on_buffers(input, output)?;
+                    Ok((status.bytes_read, status.bytes_written))
+                }),
+                Decoder::Deflate(decoder) => bounded(data, |input, output| {
+                    let (read, written) = (decoder.total_in(), decoder.total_out());
+                    decoder
+                        .decompress(input, output, FlushDecompress::None)
+                        .map_err(io::Error::other)?;
+                    Ok((
+                        (decoder.total_in() - read) as usize,
+                        (decoder.total_out() - written) as usize,
+                    ))
+                }),
+                Decoder::Lz4(pending) => {
+                    pending.extend_from_slice(data);
+                    let max_block = lz4_flex::block::get_maximum_output_size(MAX_LZ4_BLOCK) + 4;
+                    let mut output = Vec::new();
+                    let mut offset = 0;
+                    while let Some(length) = pending.get(offset..offset + 4) {
+                        let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+                        if length > max_block {
+                            return Err(invalid_data(format!("lz4 block of {} bytes", length)));
+                        }
+                        let block = match pending.get(offset + 4..offset + 4 + length) {
+                            Some(block) => block,
+                            None => break,
+                        };
+                        let size = block
+                            .get(..4)
+                            .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize)
+                            .unwrap_or(usize::MAX);
+                        if size > MAX_LZ4_BLOCK {
+                            return Err(invalid_data(format!("lz4 block of {} bytes", size)));
+                        }
+                        output.extend(
+                            lz4_flex::block::decompress_size_prepended(block)
+                                .map_err(|e| invalid_data(e.to_string()))?,
+                        );
+                        offset += 4 + length;
+                    }
+                    pending.drain(..offset);
+                    Ok(output)
+                }
+            }
+        }
+    }
+
+    /// Runs `step`, which decompresses what it can of its input into its
+    /// output and tells how much of each it used, until all of `data` is
+    /// through or the output goes past `MAX_DECOMPRESSED`.
+    fn bounded(
+        data: &[u8],
+        mut step: impl FnMut(&[u8], &mut [u8]) -> io::Result<(usize, usize)>,
+    ) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let mut chunk = vec![0u8; DECOMPRESS_CHUNK];
+        let mut input = data;
+        loop {
+            let (read, written) = step(input, &mut chunk)?;
+            input = &input[read..];
+            output.extend_from_slice(&chunk[..written]);
+            if output.len() > MAX_DECOMPRESSED {
+                return Err(invalid_data(format!(
+                    "one write decompresses to more than {} bytes",
+                    MAX_DECOMPRESSED
+                )));
+            }
+            let drained = input.is_empty() && written < chunk.len();
+            if drained || (read == 0 && written == 0) {
+                return Ok(output);
+            }
+        }
+    }
+
+    /// `zstd:fw`, `deflate:bw`, `lz4:fw`... compresses what goes in its work
+    /// direction and decompresses what comes back, `?level=n` for zstd and
+    /// deflate. Each write is flushed, so interactive sessions do not wait
+    /// for more data to fill a block.
+    pub struct CompressionStep {
+        algorithm: Algorithm,
+        level: Option<i32>,
+        /// Streams of the connection, made on first use.
+        encoder: Option<Encoder>,
+        decoder: Option<Decoder>,
+        forward_buffer: Vec<u8>,
+        backward_buffer: Vec<u8>,
+        work_mode: PipelineDirection,
+        pipeline_direction: PipelineDirection,
+    }
+
+    impl PipelineStep for CompressionStep {
+        fn len(&mut self) -> std::io::Result<usize> {
+            match self.pipeline_direction {
+                PipelineDirection::Forward => Ok(self.forward_buffer.len()),
+                PipelineDirection::Backward => Ok(self.backward_buffer.len()),
+            }
+        }
+
+        fn set_pipeline_direction(&mut self, direction: PipelineDirection) {
+            self.pipeline_direction = direction;
+        }
+
+        fn start(&mut self) -> Result<(), IOError> {
+            Ok(())
+        }
+
+        /// The far end of a `fw` step starts new streams on the new
+        /// connection. A `bw` step reconnects toward its destination, its
+        /// peer stays.
+        fn on_reconnect(&mut self) -> Result<(), IOError> {
+            if self.work_mode != PipelineDirection::Forward {
+                return Ok(());
+            }
+            self.encoder = None;
+            self.decoder = None;
+            self.forward_buffer.clear();
+            self.backward_buffer.clear();
+            Ok(())
+        }
+
+        /// A zstd or deflate stream only decompresses from its start, lz4
+        /// blocks stand alone.
+        fn is_connection_bound(&self) -> bool {
+            self.work_mode == PipelineDirection::Forward && self.algorithm != Algorithm::Lz4
+        }
+    }
+
+    impl BoxedClone for CompressionStep {
+        fn bclone(&self) -> Box<dyn PipelineStep> {
+            Box::new(CompressionStep::with(
+                self.algorithm,
+                self.level,
+                self.work_mode,
+            ))
+        }
+    }
+
+    impl Read for CompressionStep {
+        fn read(&mut self) -> Result<Vec<u8>, IOError> {
+            match self.pipeline_direction {
+                PipelineDirection::Forward => Ok(std::mem::take(&mut self.forward_buffer)),
+                PipelineDirection::Backward => Ok(std::mem::take(&mut self.backward_buffer)),
+            }
+        }
+    }
+
+    impl Write for CompressionStep {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let data = if self.work_mode == self.pipeline_direction {
+                self.encoder()?.compress(buf)?
+            } else {
+                self.decoder()?.decompress(buf).map_err(|e| {
+                    invalid_data(format!(
+                        "{} : cannot decompress : {}",
+                        self.algorithm.as_str(),
+                        e
+                    ))
+                })?
+            };
+            match self.pipeline_direction {
+                PipelineDirection::Forward => self.forward_buffer.extend(data),
+                PipelineDirection::Backward => self.backward_buffer.extend(data),
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CompressionStep {
+        pub fn new(spec: &str) -> Result<CompressionStep, IOError> {
+            let (name, config) = spec.split_once(':').unwrap_or((spec, ""));
+            let algorithm = Algorithm::from_name(name)
+                .ok_or_else(|| IOError::InvalidStep(format!("unknown compression : {}", name)))?;
+            let work_mode = match config.split('?').next() {
+                Some("fw") => PipelineDirection::Forward,
+                Some("bw") => PipelineDirection::Backward,
+                mode => {
+                    return Err(IOError::InvalidStep(format!(
+                        "{} : unknown work mode : {}",
+                        name,
+                        mode.unwrap_or("")
+                    )))
+                }
+            };
+
+            let options = SpecOptions::parse(spec);
+            let level = options.get::<i32>("level")?;
+            let range = match algorithm {
+                Algorithm::Zstd => zstd::compression_level_range(),
+                Algorithm::Deflate => 0..=9,
+                Algorithm::Lz4 if level.is_some() => {
+                    return Err(IOError::InvalidConfig("lz4 has no level".to_string()))
+                }
+                Algorithm::Lz4 => 0..=0,
+            };
+            if let Some(level) = level.filter(|level| !range.contains(level)) {
+                return Err(IOError::InvalidConfig(format!(
+                    "invalid {} level : {}. use {} to {}",
+                    name,
+                    level,
+                    range.start(),
+                    range.end()
+                )));
+            }
+
+            Ok(CompressionStep::with(algorithm, level, work_mode))
+        }
+
+        fn with(
+            algorithm: Algorithm,
+            level: Option<i32>,
+            work_mode: PipelineDirection,
+        ) -> CompressionStep {
+            CompressionStep {
+                algorithm,
+                level,
+                encoder: None,
+                decoder: None,
+                forward_buffer: Vec::new(),
+                backward_buffer: Vec::new(),
+                work_mode,
+                pipeline_direction: PipelineDirection::Forward,
+            }
+        }
+
+        fn encoder(&mut self) -> io::Result<&mut Encoder> {
+            if self.encoder.is_none() {
+                self.encoder = Some(Encoder::new(self.algorithm, self.level)?);
+            }
+            Ok(self.encoder.as_mut().unwrap())
+        }
+
+        fn decoder(&mut self) -> io::Result<&mut Decoder> {
+            if self.decoder.is_none() {
+                self.decoder = Some(Decoder::new(self.algorithm)?);
+            }
+            Ok(self.decoder.as_mut().unwrap())
+        }
+    }
+
+    fn invalid_data(msg: String) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const ALGORITHMS: [Algorithm; 3] = [Algorithm::Zstd, Algorithm::Deflate, Algorithm::Lz4];
+
+        fn pair(algorithm: Algorithm) -> (CompressionStep, CompressionStep) {
+            (
+                CompressionStep::with(algorithm, None, PipelineDirection::Forward),
+                CompressionStep::with(algorithm, None, PipelineDirection::Backward),
+            )
+        }
+
+        fn compress(step: &mut CompressionStep, data: &[u8]) -> Vec<u8> {
+            step.set_pipeline_direction(PipelineDirection::Forward);
+            step.write(data).unwrap();
+            step.read().unwrap()
+        }
+
+        fn decompress(step: &mut CompressionStep, data: &[u8]) -> io::Result<Vec<u8>> {
+            step.set_pipeline_direction(PipelineDirection::Forward);
+            step.write(data)?;
+            Ok(step.read().unwrap())
+        }
+
+        #[test]
+        fn round_trip_write_by_write() {
+            for algorithm in ALGORITHMS {
+                let (mut fw, mut bw) = pair(algorithm);
+                for message in [&b"ls -la\n"[..], &[b'x'; 100_000], b"exit\n"] {
+                    let compressed = compress(&mut fw, message);
+                    assert_eq!(decompress(&mut bw, &compressed).unwrap(), message);
+                }
+            }
+        }
+
+        #[test]
+        fn round_trip_cut_anywhere() {
+            let message: Vec<u8> = (0..50_000u32)
+                .flat_map(|i| (i % 251).to_be_bytes())
+                .collect();
+            for algorithm in ALGORITHMS {
+                let (mut fw, mut bw) = pair(algorithm);
+                let compressed = compress(&mut fw, &message);
+                let mut output = Vec::new();
+                for piece in compressed.chunks(7) {
+                    output.extend(decompress(&mut bw, piece).unwrap());
+                }
+                assert_eq!(output, message);
+            }
+        }
+
+        #[test]
+        fn output_of_one_write_is_capped() {
+            let bomb = vec![0u8; MAX_DECOMPRESSED + 1];
+            for algorithm in [Algorithm::Zstd, Algorithm::Deflate] {
+                let (mut fw, mut bw) = pair(algorithm);
+                let compressed = compress(&mut fw, &bomb);
+                assert!(compressed.len() < 1024 * 1024);
+                let error = decompress(&mut bw, &compressed).unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            }
+
+            let (mut fw, mut bw) = pair(Algorithm::Zstd);
+            let compressed = compress(&mut fw, &vec![0u8; MAX_DECOMPRESSED]);
+            assert_eq!(
+                decompress(&mut bw, &compressed).unwrap().len(),
+                MAX_DECOMPRESSED
+            );
+        }
+
+        #[test]
+        fn lz4_rejects_oversized_blocks() {
+            let (_, mut bw) = pair(Algorithm::Lz4);
+            let mut block = (8u32).to_be_bytes().to_vec();
+            block.extend_from_slice(&(MAX_LZ4_BLOCK as u32 + 1).to_le_bytes());
+            block.extend_from_slice(&[0; 4]);
+            assert!(decompress(&mut bw, &block).is_err());
+        }
+
+        #[test]
+        fn reconnect_starts_new_streams() {
+            let (mut fw, mut bw) = pair(Algorithm::Zstd);
+            assert!(fw.is_connection_bound());
+            assert!(!bw.is_connection_bound());
+            decompress(&mut bw, &compress(&mut fw, b"first connection")).unwrap();
+
+            fw.on_reconnect().unwrap();
+            let (_, mut fresh) = pair(Algorithm::Zstd);
+            let compressed = compress(&mut fw, b"second connection");
+            assert_eq!(
+                decompress(&mut fresh, &compressed).unwrap(),
+                b"second connection"
+            );
+        }
+    }
+}
@@ -0,0 +1,948 @@
+pub mod config {
+    use http::Uri;
+    use serde::Deserialize;
+    use std::{collections::HashSet, fs, path::Path, time::Duration};
+
+    use crate::{percent_encode, BalancePolicy, Cidr, HttpMode, IOError, ProbeKind};
+
+    /// Seconds connections get to finish after a tunnel stops accepting.
+    pub const DEFAULT_GRACE_PERIOD: u64 = 30;
+
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Config {
+        pub grace_period: Option<u64>,
+        #[serde(default, rename = "tunnel")]
+        pub tunnels: Vec<TunnelConfig>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct TunnelConfig {
+        pub name: String,
+        pub entry: EntryConfig,
+        #[serde(default, rename = "step")]
+        pub steps: Vec<StepConfig>,
+        pub buffer_size: Option<usize>,
+        pub loop_time: Option<u64>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+    pub enum EntryConfig {
+        Stdio,
+        Tcp {
+            address: String,
+            #[serde(default)]
+            rate: Option<RateConfig>,
+            #[serde(default)]
+            limits: Option<LimitConfig>,
+            #[serde(default)]
+            access: Option<AccessConfig>,
+        },
+        Ws {
+            address: String,
+            #[serde(default)]
+            keepalive: Option<KeepaliveConfig>,
+            #[serde(default)]
+            deflate: Option<DeflateConfig>,
+            #[serde(default)]
+            rate: Option<RateConfig>,
+            #[serde(default)]
+            limits: Option<LimitConfig>,
+            #[serde(default)]
+            access: Option<AccessConfig>,
+        },
+        Http {
+            address: String,
+            /// Signs the session tokens.
+            salt: String,
+            session_timeout: u64,
+            /// Seconds a session token stays valid.
+            token_lifetime: Option<u64>,
+            /// Where clients send the token : `header:name`, `cookie:name` or
+            /// `query:name`.
+            token: Option<String>,
+            #[serde(default)]
+            rate: Option<RateConfig>,
+            #[serde(default)]
+            limits: Option<LimitConfig>,
+            #[serde(default)]
+            access: Option<AccessConfig>,
+        },
+    }
+
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+    pub enum StepConfig {
+        Stdio,
+        Ws {
+            address: Addresses,
+            policy: Option<BalancePolicy>,
+            #[serde(default)]
+            reconnect: Option<ReconnectConfig>,
+            #[serde(default)]
+            health: Option<HealthConfig>,
+            #[serde(default)]
+            keepalive: Option<KeepaliveConfig>,
+            #[serde(default)]
+            deflate: Option<DeflateConfig>,
+        },
+        Wss {
+            address: String,
+            #[serde(default)]
+            keepalive: Option<KeepaliveConfig>,
+            #[serde(default)]
+            deflate: Option<DeflateConfig>,
+        },
+        Tcp {
+            address: Addresses,
+            policy: Option<BalancePolicy>,
+            #[serde(default)]
+            reconnect: Option<ReconnectConfig>,
+            #[serde(default)]
+            health: Option<HealthConfig>,
+        },
+        Http {
+            address: String,
+            mode: Option<HttpMode>,
+            /// Milliseconds the entry may hold a long-poll GET.
+            poll_timeout: Option<u64>,
+            /// Milliseconds to wait for an answer before retrying.
+            request_timeout: Option<u64>,
+            retries: Option<u32>,
+            /// Requests go to one of them at random.
+            paths: Option<Vec<String>>,
+            /// `post` or `put`, for uploads in long-poll mode.
+            method: Option<String>,
+            host: Option<String>,
+            user_agent: Option<String>,
+            content_type: Option<String>,
+            token: Option<String>,
+        },
+        B64 { mode: WorkMode },
+        Salt { mode: WorkMode, length: usize },
+        Zstd { mode: WorkMode, level: Option<i32> },
+        Deflate { mode: WorkMode, level: Option<i32> },
+        Lz4 { mode: WorkMode },
+        Hmac { mode: WorkMode, key: String },
+        Shape {
+            mode: WorkMode,
+            /// Chunk sizes, `n` or `min-max`, one picked at random.
+            sizes: Option<Vec<String>>,
+            /// Milliseconds without traffic before a dummy chunk.
+            dummy: Option<u64>,
+            /// Most milliseconds a write is delayed.
+            jitter: Option<u64>,
+        },
+        /// Keys are standard base64.
+        Kx {
+            mode: WorkMode,
+            psk: Option<String>,
+            key: Option<String>,
+            peer: Option<String>,
+        },
+    }
+
+    /// One upstream, or several for the step to balance between.
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(untagged)]
+    pub enum Addresses {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    impl Addresses {
+        pub fn to_vec(&self) -> Vec<&str> {
+            match self {
+                Addresses::One(address) => vec![address.as_str()],
+                Addresses::Many(addresses) => addresses.iter().map(|a| a.as_str()).collect(),
+            }
+        }
+
+        pub fn join(&self) -> String {
+            self.to_vec().join(",")
+        }
+    }
+
+    /// Reconnection of a destination step, see `ReconnectPolicy`.
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct ReconnectConfig {
+        /// 0 retries forever.
+        pub max_attempts: u32,
+        pub backoff: Option<u64>,
+        pub max_backoff: Option<u64>,
+        pub max_pending: Option<usize>,
+        pub connect_timeout: Option<u64>,
+    }
+
+    impl ReconnectConfig {
+        fn options(&self) -> Vec<String> {
+            let mut options = vec![format!("reconnect={}", self.max_attempts)];
+            if let Some(backoff) = self.backoff {
+                options.push(format!("backoff={}", backoff));
+            }
+            if let Some(max_backoff) = self.max_backoff {
+                options.push(format!("max_backoff={}", max_backoff));
+            }
+            if let Some(max_pending) = self.max_pending {
+                options.push(format!("max_pending={}", max_pending));
+            }
+            if let Some(connect_timeout) = self.connect_timeout {
+                options.push(format!("connect_timeout={}", connect_timeout));
+            }
+            options
+        }
+    }
+
+    /// Active health checks of a destination step, see `HealthCheck`.
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct HealthConfig {
+        pub probe: ProbeKind,
+        pub interval: Option<u64>,
+        pub timeout: Option<u64>,
+        pub rise: Option<u32>,
+        pub fall: Option<u32>,
+    }
+
+    impl HealthConfig {
+        fn options(&self) -> Vec<String> {
+            let mut options = vec![format!("health={}", self.probe.as_str())];
+            if let Some(interval) = self.interval {
+                options.push(format!("health_interval={}", interval));
+            }
+            if let Some(timeout) = self.timeout {
+                options.push(format!("health_timeout={}", timeout));
+            }
+            if let Some(rise) = self.rise {
+                options.push(format!("health_rise={}", rise));
+            }
+            if let Some(fall) = self.fall {
+                options.push(format!("health_fall={}", fall));
+            }
+            options
+        }
+    }
+
+    /// Websocket pings, see `KeepalivePolicy`. Off unless a field is set.
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct KeepaliveConfig {
+        /// 0 turns pings off.
+        pub ping_interval: Option<u64>,
+        pub pong_timeout: Option<u64>,
+    }
+
+    impl KeepaliveConfig {
+        fn options(&self) -> Vec<String> {
+            let mut options = Vec::new();
+            if let Some(ping_interval) = self.ping_interval {
+                options.push(format!("ping_interval={}", ping_interval));
+            }
+            if let Some(pong_timeout) = self.pong_timeout {
+                options.push(format!("pong_timeout={}", pong_timeout));
+            }
+            options
+        }
+    }
+
+    /// permessage-deflate compression, see `DeflatePolicy`. Having the table
+    /// turns it on.
+    #[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct DeflateConfig {
+        #[serde(default)]
+        pub server_no_context_takeover: bool,
+        #[serde(default)]
+        pub client_no_context_takeover: bool,
+    }
+
+    impl DeflateConfig {
+        fn options(&self) -> Vec<String> {
+            let mut options = vec!["deflate=true".to_string()];
+            if self.server_no_context_takeover {
+                options.push("server_no_context_takeover=true".to_string());
+            }
+            if self.client_no_context_takeover {
+                options.push("client_no_context_takeover=true".to_string());
+            }
+            options
+        }
+    }
+
+    /// Bandwidth caps of an entry in bytes per second, see `RatePolicy`.
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct RateConfig {
+        /// All the clients together.
+        pub upload: Option<u64>,
+        pub download: Option<u64>,
+        /// Each client.
+        pub client_upload: Option<u64>,
+        pub client_download: Option<u64>,
+    }
+
+    impl RateConfig {
+        fn options(&self) -> Vec<String> {
+            [
+                ("upload_rate", self.upload),
+                ("download_rate", self.download),
+                ("client_upload_rate", self.client_upload),
+                ("client_download_rate", self.client_download),
+            ]
+            .iter()
+            .filter_map(|(key, rate)| rate.map(|rate| format!("{}={}", key, rate)))
+            .collect()
+        }
+    }
+
+    /// Caps on the connections of an entry, see `LimitPolicy`.
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct LimitConfig {
+        pub max_connections: Option<usize>,
+        pub max_connections_per_ip: Option<usize>,
+        /// New connections per second.
+        pub accept_rate: Option<u64>,
+    }
+
+    impl LimitConfig {
+        fn options(&self) -> Vec<String> {
+            let mut options = Vec::new();
+            if let Some(max_connections) = self.max_connections {
+                options.push(format!("max_connections={}", max_connections));
+            }
+            if let Some(max_connections_per_ip) = self.max_connections_per_ip {
+                options.push(format!("max_connections_per_ip={}", max_connections_per_ip));
+            }
+            if let Some(accept_rate) = self.accept_rate {
+                options.push(format!("accept_rate={}", accept_rate));
+            }
+            options
+        }
+    }
+
+    /// Who may connect to an entry, see `AccessList`.
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct AccessConfig {
+        /// Address blocks such as `10.0.0.0/8` or `::1`.
+        #[serde(default)]
+        pub allow: Vec<String>,
+        #[serde(default)]
+        pub deny: Vec<String>,
+    }
+
+    impl AccessConfig {
+        fn options(&self) -> Vec<String> {
+            let mut options = Vec::new();
+            if !self.allow.is_empty() {
+                options.push(format!("allow={}", percent_encode(&self.allow.join(","))));
+            }
+            if !self.deny.is_empty() {
+                options.push(format!("deny={}", percent_encode(&self.deny.join(","))));
+            }
+            options
+        }
+
+        fn validate(&self) -> Result<(), String> {
+            for block in self.allow.iter().chain(self.deny.iter()) {
+                block.parse::<Cidr>()?;
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum WorkMode {
+        Fw,
+        Bw,
+    }
+
+    impl WorkMode {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                WorkMode::Fw => "fw",
+                WorkMode::Bw => "bw",
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, IOError> {
+            let content = fs::read_to_string(path.as_ref())?;
+            Config::parse(&content)
+        }
+
+        pub fn parse(content: &str) -> Result<Config, IOError> {
+            toml::from_str::<Config>(content).map_err(|e| IOError::InvalidConfig(e.to_string()))
+        }
+
+        pub fn grace_period(&self) -> Duration {
+            Duration::from_secs(self.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD))
+        }
+
+        /// Checks every tunnel without touching the network, so a bad file is
+        /// rejected before any listener is bound.
+        pub fn validate(&self) -> Result<(), IOError> {
+            if self.tunnels.is_empty() {
+                return Err(IOError::InvalidConfig("no tunnel defined".to_string()));
+            }
+
+            let mut names = HashSet::new();
+            let mut addresses = HashSet::new();
+            for tunnel in self.tunnels.iter() {
+                tunnel.validate()?;
+
+                if !names.insert(tunnel.name.as_str()) {
+                    return Err(IOError::InvalidConfig(format!(
+                        "duplicate tunnel name : {}",
+                        tunnel.name
+                    )));
+                }
+
+                let address = tunnel.entry.address();
+                if !addresses.insert(address) {
+                    return Err(IOError::InvalidConfig(format!(
+                        "tunnel {} : entry {} is already used by another tunnel",
+                        tunnel.name, address
+                    )));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl TunnelConfig {
+        pub fn validate(&self) -> Result<(), IOError> {
+            if self.name.is_empty() {
+                return Err(IOError::InvalidConfig("tunnel name is empty".to_string()));
+            }
+            let invalid = |msg: String| IOError::InvalidConfig(format!("tunnel {} : {}", self.name, msg));
+
+            if self.buffer_size == Some(0) {
+                return Err(invalid("buffer_size must be greater than zero".to_string()));
+            }
+            if self.steps.is_empty() {
+                return Err(invalid("no step defined".to_string()));
+            }
+
+            match &self.entry {
+                EntryConfig::Stdio => {}
+                EntryConfig::Tcp { address, .. } | EntryConfig::Ws { address, .. } => {
+                    check_address(address, true).map_err(invalid)?;
+                }
+                EntryConfig::Http { address, salt, .. } => {
+                    check_address(address, true).map_err(invalid)?;
+                    if salt.is_empty() || salt.contains('-') {
+                        return Err(invalid(format!(
+                            "invalid salt : {}. salt must be non-empty and must not contain '-'",
+                            salt
+                        )));
+                    }
+                }
+            }
+            if let Some(access) = self.entry.access() {
+                access.validate().map_err(invalid)?;
+            }
+
+            for (index, step) in self.steps.iter().enumerate() {
+                let result = match step {
+                    StepConfig::Stdio
+                    | StepConfig::B64 { .. }
+                    | StepConfig::Salt { .. }
+                    | StepConfig::Zstd { .. }
+                    | StepConfig::Deflate { .. }
+                    | StepConfig::Lz4 { .. }
+                    | StepConfig::Hmac { .. }
+                    | StepConfig::Shape { .. }
+                    | StepConfig::Kx { .. } => Ok(()),
+                    StepConfig::Http { address, .. } => check_address(address, true),
+                    StepConfig::Wss { address, .. } => check_address(address, false),
+                    StepConfig::Tcp { address, .. } => check_addresses(address, true),
+                    StepConfig::Ws { address, .. } => check_addresses(address, false),
+                };
+                result.map_err(|msg| invalid(format!("step {} : {}", index, msg)))?;
+            }
+            Ok(())
+        }
+
+        pub fn loop_time(&self) -> u64 {
+            self.loop_time.unwrap_or(10)
+        }
+
+        pub fn step_specs(&self) -> Vec<String> {
+            self.steps.iter().map(|step| step.spec()).collect()
+        }
+    }
+
+    impl EntryConfig {
+        /// The listen address, used to tell whether two entries would clash.
+        pub fn address(&self) -> &str {
+            match self {
+                EntryConfig::Stdio => "stdio",
+                EntryConfig::Tcp { address, .. }
+                | EntryConfig::Ws { address, .. }
+                | EntryConfig::Http { address, .. } => address.as_str(),
+            }
+        }
+
+        pub fn access(&self) -> Option<&AccessConfig> {
+            match self {
+                EntryConfig::Stdio => None,
+                EntryConfig::Tcp { access, .. }
+                | EntryConfig::Ws { access, .. }
+                | EntryConfig::Http { access, .. } => access.as_ref(),
+            }
+        }
+
+        /// Converts the entry into the same spec string accepted by `-e`.
+        pub fn spec(&self) -> String {
+            match self {
+                EntryConfig::Stdio => "stdio:".to_string(),
+                EntryConfig::Tcp {
+                    address,
+                    rate,
+                    limits,
+                    access,
+                } => format!(
+                    "tcp://{}{}",
+                    address,
+                    query(
+                        rate.iter()
+                            .flat_map(|r| r.options())
+                            .chain(limits.iter().flat_map(|l| l.options()))
+                            .chain(access.iter().flat_map(|a| a.options()))
+                            .collect()
+                    )
+                ),
+                EntryConfig::Ws {
+                    address,
+                    keepalive,
+                    deflate,
+                    rate,
+                    limits,
+                    access,
+                } => format!(
+                    "ws://{}{}",
+                    address,
+                    query(
+                        keepalive
+                            .iter()
+                            .flat_map(|k| k.options())
+                            .chain(deflate.iter().flat_map(|d| d.options()))
+                            .chain(rate.iter().flat_map(|r| r.options()))
+                            .chain(limits.iter().flat_map(|l| l.options()))
+                            .chain(access.iter().flat_map(|a| a.options()))
+                            .collect()
+                    )
+                ),
+                EntryConfig::Http {
+                    address,
+                    salt,
+                    session_timeout,
+                    token_lifetime,
+                    token,
+                    rate,
+                    limits,
+                    access,
+                } => {
+                    let mut options = Vec::new();
+                    if let Some(token_lifetime) = token_lifetime {
+                        options.push(format!("token_lifetime={}", token_lifetime));
+                    }
+                    if let Some(token) = token {
+                        options.push(format!("token={}", percent_encode(token)));
+                    }
+                    options.extend(rate.iter().flat_map(|r| r.options()));
+                    options.extend(limits.iter().flat_map(|l| l.options()));
+                    options.extend(access.iter().flat_map(|a| a.options()));
+                    format!(
+                        "http://{}-{}-{}{}",
+                        address,
+                        salt,
+                        session_timeout,
+                        query(options)
+                    )
+                }
+            }
+        }
+    }
+
+    impl StepConfig {
+        /// Converts the step into the same spec string accepted by `-s`.
+        pub fn spec(&self) -> String {
+            match self {
+                StepConfig::Stdio => "stdio:".to_string(),
+                StepConfig::Ws {
+                    address,
+                    policy,
+                    reconnect,
+                    health,
+                    keepalive,
+                    deflate,
+                } => format!(
+                    "ws://{}{}",
+                    address.join(),
+                    step_options(policy, reconnect, health, keepalive, deflate)
+                ),
+                StepConfig::Wss {
+                    address,
+                    keepalive,
+                    deflate,
+                } => format!(
+                    "wss://{}{}",
+                    address,
+                    query(
+                        keepalive
+                            .iter()
+                            .flat_map(|k| k.options())
+                            .chain(deflate.iter().flat_map(|d| d.options()))
+                            .collect()
+                    )
+                ),
+                StepConfig::Tcp {
+                    address,
+                    policy,
+                    reconnect,
+                    health,
+                } => format!(
+                    "tcp://{}{}",
+                    address.join(),
+                    step_options(policy, reconnect, health, &None, &None)
+                ),
+                StepConfig::Http {
+                    address,
+                    mode,
+                    poll_timeout,
+                    request_timeout,
+                    retries,
+                    paths,
+                    method,
+                    host,
+                    user_agent,
+                    content_type,
+                    token,
+                } => {
+                    let mut options = Vec::new();
+                    if let Some(mode) = mode {
+                        options.push(format!("mode={}", mode.as_str()));
+                    }
+                    if let Some(poll_timeout) = poll_timeout {
+                        options.push(format!("poll_timeout={}", poll_timeout));
+                    }
+                    if let Some(request_timeout) = request_timeout {
+                        options.push(format!("request_timeout={}", request_timeout));
+                    }
+                    if let Some(retries) = retries {
+                        options.push(format!("retries={}", retries));
+                    }
+                    if let Some(paths) = paths {
+                        let paths: Vec<String> =
+                            paths.iter().map(|path| percent_encode(path)).collect();
+                        options.push(format!("paths={}", paths.join(",")));
+                    }
+                    let texts = [
+                        ("method", method),
+                        ("host", host),
+                        ("user_agent", user_agent),
+                        ("content_type", content_type),
+                        ("token", token),
+                    ];
+                    for (key, value) in texts {
+                        if let Some(value) = value {
+                            options.push(format!("{}={}", key, percent_encode(value)));
+                        }
+                    }
+                    format!("http://{}{}", address, query(options))
+                }
+                StepConfig::B64 { mode } => format!("b64:{}", mode.as_str()),
+                StepConfig::Salt { mode, length } => format!("salt:{}-{}", mode.as_str(), length),
+                StepConfig::Zstd { mode, level } => {
+                    format!("zstd:{}{}", mode.as_str(), level_option(level))
+                }
+                StepConfig::Deflate { mode, level } => {
+                    format!("deflate:{}{}", mode.as_str(), level_option(level))
+                }
+                StepConfig::Lz4 { mode } => format!("lz4:{}", mode.as_str()),
+                StepConfig::Hmac { mode, key } => {
+                    format!("hmac:{}-{}", mode.as_str(), percent_encode(key))
+                }
+                StepConfig::Shape {
+                    mode,
+                    sizes,
+                    dummy,
+                    jitter,
+                } => {
+                    let mut options = Vec::new();
+                    if let Some(sizes) = sizes {
+                        options.push(format!("sizes={}", sizes.join(",")));
+                    }
+                    if let Some(dummy) = dummy {
+                        options.push(format!("dummy={}", dummy));
+                    }
+                    if let Some(jitter) = jitter {
+                        options.push(format!("jitter={}", jitter));
+                    }
+                    format!("shape:{}{}", mode.as_str(), query(options))
+                }
+                StepConfig::Kx {
+                    mode,
+                    psk,
+                    key,
+                    peer,
+                } => {
+                    let options = [("psk", psk), ("key", key), ("peer", peer)]
+                        .into_iter()
+                        .filter_map(|(name, value)| {
+                            value
+                                .as_ref()
+                                .map(|value| format!("{}={}", name, percent_encode(value)))
+                        })
+                        .collect();
+                    format!("kx:{}{}", mode.as_str(), query(options))
+                }
+            }
+        }
+    }
+
+    fn step_options(
+        policy: &Option<BalancePolicy>,
+        reconnect: &Option<ReconnectConfig>,
+        health: &Option<HealthConfig>,
+        keepalive: &Option<KeepaliveConfig>,
+        deflate: &Option<DeflateConfig>,
+    ) -> String {
+        let mut options = Vec::new();
+        if let Some(policy) = policy {
+            options.push(format!("policy={}", policy.as_str()));
+        }
+        if let Some(reconnect) = reconnect {
+            options.extend(reconnect.options());
+        }
+        if let Some(health) = health {
+            options.extend(health.options());
+        }
+        if let Some(keepalive) = keepalive {
+            options.extend(keepalive.options());
+        }
+        if let Some(deflate) = deflate {
+            options.extend(deflate.options());
+        }
+        query(options)
+    }
+
+    fn level_option(level: &Option<i32>) -> String {
+        query(level.iter().map(|level| format!("level={}", level)).collect())
+    }
+
+    fn query(options: Vec<String>) -> String {
+        if options.is_empty() {
+            String::new()
+        } else {
+            format!("?{}", options.join("&"))
+        }
+    }
+
+    fn check_addresses(addresses: &Addresses, port_required: bool) -> Result<(), String> {
+        let addresses = addresses.to_vec();
+        if addresses.is_empty() {
+            return Err("address list is empty".to_string());
+        }
+        for address in addresses {
+            check_address(address, port_required)?;
+        }
+        Ok(())
+    }
+
+    fn check_address(address: &str, port_required: bool) -> Result<(), String> {
+        let uri = format!("tcp://{}", address)
+            .parse::<Uri>()
+            .map_err(|e| format!("invalid address {} : {}", address, e))?;
+        if uri.host().unwrap_or("").is_empty() {
+            return Err(format!("invalid address {} : host is missing", address));
+        }
+        if port_required && uri.port().is_none() {
+            return Err(format!("invalid address {} : port is missing", address));
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{create_step, percent_decode};
+
+        const SAMPLE: &str = r#"
+            grace_period = 30
+
+            [[tunnel]]
+            name = "ssh"
+            buffer_size = 1024
+            loop_time = 10
+            entry = { type = "tcp", address = "0.0.0.0:2222" }
+
+            [[tunnel.step]]
+            type = "salt"
+            mode = "fw"
+            length = 8
+
+            [[tunnel.step]]
+            type = "tcp"
+            address = ["relay-a.example.com:22", "relay-b.example.com:22"]
+            policy = "failover"
+            reconnect = { max_attempts = 10, backoff = 100, max_backoff = 30000 }
+            health = { probe = "tcp", interval = 5000, timeout = 2000, rise = 2, fall = 3 }
+
+            [[tunnel]]
+            name = "web"
+            entry = { type = "http", address = "0.0.0.0:8080", salt = "pepper", session_timeout = 60, token = "cookie:sid" }
+
+            [[tunnel.step]]
+            type = "kx"
+            mode = "bw"
+            psk = "c2VjcmV0+c2VjcmV0/c2VjcmV0="
+
+            [[tunnel.step]]
+            type = "hmac"
+            mode = "bw"
+            key = "se?cr&et %"
+
+            [[tunnel.step]]
+            type = "ws"
+            address = "127.0.0.1:9000/tunnel"
+            keepalive = { ping_interval = 1000 }
+            deflate = { server_no_context_takeover = true }
+        "#;
+
+        fn rejected(content: &str) -> String {
+            match Config::parse(content).unwrap().validate() {
+                Err(IOError::InvalidConfig(msg)) => msg,
+                other => panic!("expected an invalid config, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn sample_gives_the_cli_specs() {
+            let config = Config::parse(SAMPLE).unwrap();
+            config.validate().unwrap();
+            assert_eq!(config.grace_period(), Duration::from_secs(30));
+
+            let ssh = &config.tunnels[0];
+            assert_eq!(ssh.entry.spec(), "tcp://0.0.0.0:2222");
+            assert_eq!(ssh.buffer_size, Some(1024));
+            assert_eq!(
+                ssh.step_specs(),
+                vec![
+                    "salt:fw-8".to_string(),
+                    "tcp://relay-a.example.com:22,relay-b.example.com:22?policy=failover\
+                     &reconnect=10&backoff=100&max_backoff=30000&health=tcp\
+                     &health_interval=5000&health_timeout=2000&health_rise=2&health_fall=3"
+                        .to_string(),
+                ]
+            );
+
+            let web = &config.tunnels[1];
+            assert_eq!(
+                web.entry.spec(),
+                "http://0.0.0.0:8080-pepper-60?token=cookie:sid"
+            );
+            assert_eq!(
+                web.step_specs(),
+                vec![
+                    "kx:bw?psk=c2VjcmV0%2Bc2VjcmV0/c2VjcmV0%3D".to_string(),
+                    "hmac:bw-se%3Fcr%26et%20%25".to_string(),
+                    "ws://127.0.0.1:9000/tunnel?ping_interval=1000&deflate=true\
+                     &server_no_context_takeover=true"
+                        .to_string(),
+                ]
+            );
+        }
+
+        #[test]
+        fn hmac_key_survives_the_spec() {
+            let config = Config::parse(SAMPLE).unwrap();
+            let spec = &config.tunnels[1].step_specs()[1];
+            assert_eq!(
+                percent_decode(spec.strip_prefix("hmac:bw-").unwrap()),
+                "se?cr&et %"
+            );
+            assert!(create_step(spec).is_ok());
+        }
+
+        #[test]
+        fn bad_addresses_are_rejected() {
+            let tunnel = |entry: &str, step: &str| {
+                format!(
+                    "[[tunnel]]\nname = \"t\"\nentry = {}\n[[tunnel.step]]\n{}\n",
+                    entry, step
+                )
+            };
+            let tcp_entry = r#"{ type = "tcp", address = "127.0.0.1:2222" }"#;
+
+            let msg = rejected(&tunnel(
+                r#"{ type = "tcp", address = "127.0.0.1" }"#,
+                "type = \"stdio\"",
+            ));
+            assert!(msg.contains("port is missing"), "{}", msg);
+
+            let msg = rejected(&tunnel(
+                tcp_entry,
+                "type = \"tcp\"\naddress = [\"127.0.0.1:22\", \"relay\"]",
+            ));
+            assert!(msg.starts_with("tunnel t : step 0 : "), "{}", msg);
+            assert!(msg.contains("relay : port is missing"), "{}", msg);
+
+            let msg = rejected(&tunnel(tcp_entry, "type = \"tcp\"\naddress = []"));
+            assert!(msg.contains("address list is empty"), "{}", msg);
+
+            let msg = rejected(&tunnel(tcp_entry, "type = \"ws\"\naddress = \":80\""));
+            assert!(msg.contains("host is missing"), "{}", msg);
+
+            // ws upstreams may leave out the port
+            Config::parse(&tunnel(
+                tcp_entry,
+                "type = \"ws\"\naddress = \"relay/path\"",
+            ))
+            .unwrap()
+            .validate()
+            .unwrap();
+        }
+
+        #[test]
+        fn tunnels_are_checked_together() {
+            assert_eq!(rejected(""), "no tunnel defined");
+
+            let tunnel = |name: &str, address: &str| {
+                format!(
+                    "[[tunnel]]\nname = \"{}\"\nentry = {{ type = \"tcp\", address = \"{}\" }}\n\
+                     [[tunnel.step]]\ntype = \"stdio\"\n",
+                    name, address
+                )
+            };
+            let msg = rejected(&(tunnel("a", "127.0.0.1:1") + &tunnel("a", "127.0.0.1:2")));
+            assert!(msg.contains("duplicate tunnel name : a"), "{}", msg);
+            let msg = rejected(&(tunnel("a", "127.0.0.1:1") + &tunnel("b", "127.0.0.1:1")));
+            assert!(msg.contains("already used"), "{}", msg);
+
+            let msg = rejected(
+                "[[tunnel]]\nname = \"t\"\nentry = { type = \"http\", address = \"127.0.0.1:80\", \
+                 salt = \"a-b\", session_timeout = 60 }\n[[tunnel.step]]\ntype = \"stdio\"\n",
+            );
+            assert!(msg.contains("invalid salt"), "{}", msg);
+
+            let msg = rejected("[[tunnel]]\nname = \"t\"\nentry = { type = \"stdio\" }\n");
+            assert_eq!(msg, "tunnel t : no step defined");
+        }
+
+        #[test]
+        fn unknown_fields_are_rejected() {
+            let content = "[[tunnel]]\nname = \"t\"\nentry = { type = \"stdio\" }\n\
+                           [[tunnel.step]]\ntype = \"salt\"\nmode = \"fw\"\nlength = 8\nlenght = 9\n";
+            assert!(Config::parse(content).is_err());
+        }
+    }
+}
@@ -0,0 +1,171 @@
+pub mod connection_limit {
+    use std::{
+        collections::HashMap,
+        fmt,
+        net::IpAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{IOError, SpecOptions, TokenBucket};
+
+    /// Caps on the connections of an entry. `?max_connections=` open at
+    /// once, `max_connections_per_ip=` open at once from one address and
+    /// `accept_rate=` new ones per second. 0 or nothing leaves it unlimited.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct LimitPolicy {
+        pub max_connections: Option<usize>,
+        pub max_per_ip: Option<usize>,
+        pub accept_rate: Option<u64>,
+    }
+
+    impl LimitPolicy {
+        pub fn from_options(options: &SpecOptions) -> Result<LimitPolicy, IOError> {
+            Ok(LimitPolicy {
+                max_connections: options
+                    .get::<usize>("max_connections")?
+                    .filter(|max| *max > 0),
+                max_per_ip: options
+                    .get::<usize>("max_connections_per_ip")?
+                    .filter(|max| *max > 0),
+                accept_rate: options.get::<u64>("accept_rate")?.filter(|rate| *rate > 0),
+            })
+        }
+    }
+
+    /// Why a connection was turned away.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Rejection {
+        MaxConnections,
+        MaxPerIp,
+        AcceptRate,
+    }
+
+    impl fmt::Display for Rejection {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Rejection::MaxConnections => write!(f, "too many connections"),
+                Rejection::MaxPerIp => write!(f, "too many connections from the address"),
+                Rejection::AcceptRate => write!(f, "over the accept rate"),
+            }
+        }
+    }
+
+    struct Admitted {
+        total: usize,
+        per_ip: HashMap<IpAddr, usize>,
+        accepts: Option<TokenBucket>,
+    }
+
+    /// Admits the connections of an entry within its `LimitPolicy`. Clones
+    /// share the counts.
+    #[derive(Clone)]
+    pub struct ConnectionLimiter {
+        policy: LimitPolicy,
+        admitted: Arc<Mutex<Admitted>>,
+    }
+
+    impl ConnectionLimiter {
+        pub fn new(policy: LimitPolicy) -> ConnectionLimiter {
+            ConnectionLimiter {
+                policy,
+                admitted: Arc::new(Mutex::new(Admitted {
+                    total: 0,
+                    per_ip: HashMap::new(),
+                    accepts: policy.accept_rate.map(TokenBucket::new),
+                })),
+            }
+        }
+
+        /// Counts a new connection from `ip` until the permit is dropped,
+        /// unless it is over a limit. Only admitted connections count against
+        /// the accept rate.
+        pub fn admit(&self, ip: IpAddr) -> Result<ConnectionPermit, Rejection> {
+            let mut admitted = self.admitted.lock().unwrap();
+            if self
+                .policy
+                .max_connections
+                .is_some_and(|max| admitted.total >= max)
+            {
+                return Err(Rejection::MaxConnections);
+            }
+            let from_ip = admitted.per_ip.get(&ip).copied().unwrap_or(0);
+            if self.policy.max_per_ip.is_some_and(|max| from_ip >= max) {
+                return Err(Rejection::MaxPerIp);
+            }
+            if let Some(accepts) = admitted.accepts.as_mut() {
+                if accepts.available() == 0 {
+                    return Err(Rejection::AcceptRate);
+                }
+                accepts.take(1);
+            }
+
+            admitted.total += 1;
+            admitted.per_ip.insert(ip, from_ip + 1);
+            Ok(ConnectionPermit {
+                admitted: self.admitted.clone(),
+                ip,
+            })
+        }
+    }
+
+    /// A connection admitted by a `ConnectionLimiter`, counted for as long
+    /// as it is alive.
+    pub struct ConnectionPermit {
+        admitted: Arc<Mutex<Admitted>>,
+        ip: IpAddr,
+    }
+
+    impl Drop for ConnectionPermit {
+        fn drop(&mut self) {
+            let mut admitted = self.admitted.lock().unwrap();
+            admitted.total -= 1;
+            if let Some(count) = admitted.per_ip.get_mut(&self.ip) {
+                *count -= 1;
+                if *count == 0 {
+                    admitted.per_ip.remove(&self.ip);
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn permits_count_until_dropped() {
+            let limiter = ConnectionLimiter::new(LimitPolicy {
+                max_connections: Some(2),
+                max_per_ip: Some(1),
+                accept_rate: None,
+            });
+            let a: IpAddr = "10.0.0.1".parse().unwrap();
+            let b: IpAddr = "10.0.0.2".parse().unwrap();
+            let c: IpAddr = "10.0.0.3".parse().unwrap();
+
+            let first = limiter.admit(a).unwrap();
+            assert_eq!(limiter.admit(a).err(), Some(Rejection::MaxPerIp));
+            let _second = limiter.admit(b).unwrap();
+            assert_eq!(limiter.admit(c).err(), Some(Rejection::MaxConnections));
+
+            drop(first);
+            assert!(limiter.admit(a).is_ok());
+        }
+
+        #[test]
+        fn only_admitted_connections_take_from_the_accept_rate() {
+            let limiter = ConnectionLimiter::new(LimitPolicy {
+                max_connections: Some(1),
+                max_per_ip: None,
+                accept_rate: Some(2),
+            });
+            let ip: IpAddr = "::1".parse().unwrap();
+            let first = limiter.admit(ip).unwrap();
+            assert_eq!(limiter.admit(ip).err(), Some(Rejection::MaxConnections));
+            drop(first);
+            let second = limiter.admit(ip).unwrap();
+            drop(second);
+            assert_eq!(limiter.admit(ip).err(), Some(Rejection::AcceptRate));
+        }
+    }
+}
@@ -1,17 +1,115 @@
 pub mod entry_module {
     use std::{
-        io::{Read, Write},
-        net::TcpStream, os::fd::AsRawFd,
+        net::TcpListener,
+        os::fd::AsRawFd,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, RwLock,
+        },
     };
 
-    use crate::Pipeline;
+    use crate::{IOError, Pipeline};
 
     pub trait Entry : Clone {
-        fn new(config: String, pipeline: Pipeline, loop_time: u64) -> Self;
+        fn new(config: String, pipeline: Pipeline, loop_time: u64) -> Result<Self, IOError>;
         // fn len(&self) -> std::io::Result<usize>;
         // fn read(&mut self);
         // fn write(&mut self);
         fn len(stream: &mut dyn AsRawFd) -> std::io::Result<usize>;
         fn listen(&mut self);
+        fn control(&self) -> EntryControl;
+    }
+
+    /// Handle shared between an entry and whoever owns it. Connections are
+    /// cloned from the current pipeline template, so replacing it only
+    /// affects connections accepted afterwards.
+    #[derive(Clone)]
+    pub struct EntryControl {
+        template: Arc<RwLock<Pipeline>>,
+        stopped: Arc<AtomicBool>,
+        closing: Arc<AtomicBool>,
+        active: Arc<AtomicUsize>,
+        rejected: Arc<AtomicUsize>,
+    }
+
+    impl EntryControl {
+        pub fn new(pipeline: Pipeline) -> Self {
+            EntryControl {
+                template: Arc::new(RwLock::new(pipeline)),
+                stopped: Arc::new(AtomicBool::new(false)),
+                closing: Arc::new(AtomicBool::new(false)),
+                active: Arc::new(AtomicUsize::new(0)),
+                rejected: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        pub fn pipeline(&self) -> Pipeline {
+            self.template.read().unwrap().clone()
+        }
+
+        pub fn set_pipeline(&self, pipeline: Pipeline) {
+            *self.template.write().unwrap() = pipeline;
+        }
+
+        /// Asks the entry to stop accepting; connections already accepted
+        /// keep running until they finish on their own.
+        pub fn stop(&self) {
+            self.stopped.store(true, Ordering::SeqCst);
+        }
+
+        pub fn is_stopped(&self) -> bool {
+            self.stopped.load(Ordering::SeqCst)
+        }
+
+        /// Asks every connection of the entry to close now, once the grace
+        /// period for draining is over.
+        pub fn close(&self) {
+            self.stop();
+            self.closing.store(true, Ordering::SeqCst);
+        }
+
+        pub fn is_closing(&self) -> bool {
+            self.closing.load(Ordering::SeqCst)
+        }
+
+        pub fn active_connections(&self) -> usize {
+            self.active.load(Ordering::SeqCst)
+        }
+
+        /// Counts a connection turned away by the limits of the entry,
+        /// returns how many were so far.
+        pub fn reject(&self) -> usize {
+            self.rejected.fetch_add(1, Ordering::SeqCst) + 1
+        }
+
+        pub fn rejected_connections(&self) -> usize {
+            self.rejected.load(Ordering::SeqCst)
+        }
+
+        pub fn connection(&self) -> ConnectionGuard {
+            self.active.fetch_add(1, Ordering::SeqCst);
+            ConnectionGuard {
+                active: self.active.clone(),
+            }
+        }
+    }
+
+    /// Counts a connection as active for as long as it is alive.
+    pub struct ConnectionGuard {
+        active: Arc<AtomicUsize>,
+    }
+
+    impl Drop for ConnectionGuard {
+        fn drop(&mut self) {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Closes a listening socket even while other handles to it are still
+    /// open, so the port can be bound again right away.
+    pub fn shutdown_listener(listener: &TcpListener) {
+        unsafe {
+            libc::shutdown(listener.as_raw_fd(), libc::SHUT_RD);
+        }
     }
 }
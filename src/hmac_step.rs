@@ -0,0 +1,444 @@
+pub mod hmac_step {
+    use openssl::{hash::MessageDigest, memcmp, pkey::PKey, rand::rand_bytes, sign::Signer};
+    use std::io::{self, Write};
+
+    use crate::{
+        percent_decode,
+        pipeline_module::pipeline::{PipelineDirection, PipelineStep},
+        BoxedClone, IOError, Read,
+    };
+
+    const TAG_LEN: usize = 32;
+    /// Length before the data, counter and tag after it.
+    const OVERHEAD: usize = 4 + 8 + TAG_LEN;
+    /// Largest chunk signed at once, bigger writes are split. Also bounds
+    /// what a peer can make us buffer for one chunk.
+    pub const MAX_CHUNK: usize = 1024 * 1024;
+    /// How far behind the highest counter seen a chunk may still come.
+    pub const REPLAY_WINDOW: u64 = 64;
+    /// Random bytes each end sends first on a connection.
+    const NONCE_LEN: usize = 16;
+    /// Most data held until the nonce of the other end came.
+    pub const MAX_HELD: usize = 1024 * 1024;
+    const LABEL: &[u8] = b"proxy hmac 1";
+
+    /// Counters seen so far, the highest and a bitmap of the ones before it.
+    #[derive(Default)]
+    struct ReplayWindow {
+        highest: Option<u64>,
+        seen: u64,
+    }
+
+    impl ReplayWindow {
+        /// Records `counter`, false when it was seen already or is too old.
+        fn accept(&mut self, counter: u64) -> bool {
+            let highest = match self.highest {
+                Some(highest) => highest,
+                None => {
+                    self.highest = Some(counter);
+                    self.seen = 1;
+                    return true;
+                }
+            };
+            if counter > highest {
+                let shift = counter - highest;
+                self.seen = match shift < REPLAY_WINDOW {
+                    true => (self.seen << shift) | 1,
+                    false => 1,
+                };
+                self.highest = Some(counter);
+                return true;
+            }
+            let age = highest - counter;
+            if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+                return false;
+            }
+            self.seen |= 1 << age;
+            true
+        }
+    }
+
+    /// `hmac:fw-<key>` appends a counter and an HMAC-SHA256 tag to each chunk
+    /// going in its work direction, and checks those coming back, rejecting
+    /// forged, replayed or too old chunks. Chunks are framed by their length,
+    /// so they survive being cut or merged by the transport. Both ends open
+    /// a connection with a random nonce and sign with a key derived from the
+    /// two, so that chunks recorded on another connection do not verify
+    /// again; counters start over with it.
+    pub struct HmacStep {
+        key: Vec<u8>,
+        nonce: [u8; NONCE_LEN],
+        /// Key of the connection, once the nonce of the other end came.
+        session: Option<Vec<u8>>,
+        /// Data written before the session key was there.
+        held: Vec<u8>,
+        counter: u64,
+        window: ReplayWindow,
+        forward_buffer: Vec<u8>,
+        backward_buffer: Vec<u8>,
+        work_mode: PipelineDirection,
+        pipeline_direction: PipelineDirection,
+    }
+
+    impl PipelineStep for HmacStep {
+        fn len(&mut self) -> std::io::Result<usize> {
+            match self.pipeline_direction {
+                PipelineDirection::Forward => Ok(self.forward_buffer.len()),
+                PipelineDirection::Backward => Ok(self.backward_buffer.len()),
+            }
+        }
+
+        fn set_pipeline_direction(&mut self, direction: PipelineDirection) {
+            self.pipeline_direction = direction;
+        }
+
+        /// Sends the nonce of this end.
+        fn start(&mut self) -> Result<(), IOError> {
+            rand_bytes(&mut self.nonce).map_err(|e| IOError::UnknownError(e.to_string()))?;
+            let nonce = self.nonce;
+            self.buffer_for(self.work_mode).extend_from_slice(&nonce);
+            Ok(())
+        }
+
+        fn has_pending(&self, direction: PipelineDirection) -> bool {
+            direction == self.work_mode
+                && match direction {
+                    PipelineDirection::Forward => !self.forward_buffer.is_empty(),
+                    PipelineDirection::Backward => !self.backward_buffer.is_empty(),
+                }
+        }
+
+        /// The far end of `hmac:fw` sees a new connection, so both start over
+        /// with new nonces, keeping what was held for the old one. `hmac:bw`
+        /// reconnects toward its destination, its peer stays.
+        fn on_reconnect(&mut self) -> Result<(), IOError> {
+            if self.work_mode != PipelineDirection::Forward {
+                return Ok(());
+            }
+            let mut fresh = HmacStep::with(self.key.clone(), self.work_mode);
+            fresh.held = std::mem::take(&mut self.held);
+            fresh.pipeline_direction = self.pipeline_direction;
+            *self = fresh;
+            self.start()
+        }
+
+        /// Chunks signed by `hmac:fw` only verify on its connection.
+        fn is_connection_bound(&self) -> bool {
+            self.work_mode == PipelineDirection::Forward
+        }
+    }
+
+    impl BoxedClone for HmacStep {
+        fn bclone(&self) -> Box<dyn PipelineStep> {
+            Box::new(HmacStep::with(self.key.clone(), self.work_mode))
+        }
+    }
+
+    impl Read for HmacStep {
+        /// Checks what came from the peer here rather than in `write`, to
+        /// report bad chunks as `InvalidData`.
+        fn read(&mut self) -> Result<Vec<u8>, IOError> {
+            if self.work_mode == self.pipeline_direction {
+                return Ok(std::mem::take(self.buffer()));
+            }
+
+            let direction = self.pipeline_direction;
+            let mut pending = std::mem::take(self.buffer());
+            if self.session.is_none() {
+                if pending.len() < NONCE_LEN {
+                    *self.buffer() = pending;
+                    return Ok(Vec::new());
+                }
+                let nonce: Vec<u8> = pending.drain(..NONCE_LEN).collect();
+                self.establish(&nonce)?;
+            }
+            let session = self.session.as_ref().unwrap();
+            let mut output = Vec::new();
+            let mut offset = 0;
+            while let Some(length) = pending.get(offset..offset + 4) {
+                let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+                if length > MAX_CHUNK {
+                    return Err(invalid(format!("chunk of {} bytes", length)));
+                }
+                let chunk = match pending.get(offset + 4..offset + OVERHEAD + length) {
+                    Some(chunk) => chunk,
+                    None => break,
+                };
+                let (data, trailer) = chunk.split_at(length);
+                let (counter, tag) = trailer.split_at(8);
+                let counter = u64::from_be_bytes(counter.try_into().unwrap());
+                if !memcmp::eq(&tag_chunk(session, direction, counter, data)?, tag) {
+                    return Err(invalid(format!("bad tag on chunk {}", counter)));
+                }
+                if !self.window.accept(counter) {
+                    return Err(invalid(format!("replayed or late chunk {}", counter)));
+                }
+                output.extend_from_slice(data);
+                offset += OVERHEAD + length;
+            }
+            *self.buffer() = pending[offset..].to_vec();
+            Ok(output)
+        }
+    }
+
+    impl Write for HmacStep {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.work_mode != self.pipeline_direction {
+                self.buffer().extend_from_slice(buf);
+                return Ok(buf.len());
+            }
+
+            if self.session.is_none() {
+                if self.held.len() + buf.len() > MAX_HELD {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "hmac : more than {} bytes written before the peer nonce",
+                            MAX_HELD
+                        ),
+                    ));
+                }
+                self.held.extend_from_slice(buf);
+                return Ok(buf.len());
+            }
+            self.sign(buf)?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl HmacStep {
+        pub fn new(config: Option<&str>) -> Result<HmacStep, IOError> {
+            let (mode, key) = config
+                .and_then(|config| config.split_once('-'))
+                .ok_or_else(|| {
+                    IOError::InvalidStep(format!(
+                        "hmac : invalid config : {}. expected fw-<key> or bw-<key>",
+                        config.unwrap_or("")
+                    ))
+                })?;
+            let work_mode = match mode {
+                "fw" => PipelineDirection::Forward,
+                "bw" => PipelineDirection::Backward,
+                _ => {
+                    return Err(IOError::InvalidStep(format!(
+                        "hmac : unknown work mode : {}",
+                        mode
+                    )))
+                }
+            };
+            if key.is_empty() {
+                return Err(IOError::InvalidConfig("hmac : key is empty".to_string()));
+            }
+            Ok(HmacStep::with(percent_decode(key).into_bytes(), work_mode))
+        }
+
+        fn with(key: Vec<u8>, work_mode: PipelineDirection) -> HmacStep {
+            HmacStep {
+                key,
+                nonce: [0; NONCE_LEN],
+                session: None,
+                held: Vec::new(),
+                counter: 0,
+                window: ReplayWindow::default(),
+                forward_buffer: Vec::new(),
+                backward_buffer: Vec::new(),
+                work_mode,
+                pipeline_direction: PipelineDirection::Forward,
+            }
+        }
+
+        fn buffer(&mut self) -> &mut Vec<u8> {
+            self.buffer_for(self.pipeline_direction)
+        }
+
+        fn buffer_for(&mut self, direction: PipelineDirection) -> &mut Vec<u8> {
+            match direction {
+                PipelineDirection::Forward => &mut self.forward_buffer,
+                PipelineDirection::Backward => &mut self.backward_buffer,
+            }
+        }
+
+        /// Derives the key of the connection from both nonces, then signs
+        /// what was held meanwhile.
+        fn establish(&mut self, peer: &[u8]) -> Result<(), IOError> {
+            let (fw, bw) = match self.work_mode {
+                PipelineDirection::Forward => (&self.nonce[..], peer),
+                PipelineDirection::Backward => (peer, &self.nonce[..]),
+            };
+            let session = hmac(&self.key, &[LABEL, fw, bw])?;
+            self.session = Some(session);
+            let held = std::mem::take(&mut self.held);
+            self.sign(&held)?;
+            Ok(())
+        }
+
+        /// Appends `length | data | counter | tag` chunks to what goes in the
+        /// work direction.
+        fn sign(&mut self, buf: &[u8]) -> io::Result<()> {
+            let direction = self.work_mode;
+            for data in buf.chunks(MAX_CHUNK) {
+                let counter = self.counter;
+                self.counter += 1;
+                let tag = tag_chunk(self.session.as_ref().unwrap(), direction, counter, data)?;
+                let output = self.buffer_for(direction);
+                output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                output.extend_from_slice(data);
+                output.extend_from_slice(&counter.to_be_bytes());
+                output.extend_from_slice(&tag);
+            }
+            Ok(())
+        }
+    }
+
+    /// Covers the direction too, so that a chunk cannot be sent back to the
+    /// end that signed it.
+    fn tag_chunk(
+        session: &[u8],
+        direction: PipelineDirection,
+        counter: u64,
+        data: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        hmac(session, &[&[direction as u8], &counter.to_be_bytes(), data])
+    }
+
+    fn hmac(key: &[u8], parts: &[&[u8]]) -> io::Result<Vec<u8>> {
+        let sign = || {
+            let key = PKey::hmac(key)?;
+            let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+            for part in parts {
+                signer.update(part)?;
+            }
+            signer.sign_to_vec()
+        };
+        sign().map_err(io::Error::other)
+    }
+
+    fn invalid(reason: String) -> IOError {
+        IOError::InvalidData(format!("hmac : {}", reason))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn window_accepts_each_counter_once() {
+            let mut window = ReplayWindow::default();
+            assert!(window.accept(5));
+            assert!(!window.accept(5));
+            assert!(window.accept(3));
+            assert!(!window.accept(3));
+            assert!(window.accept(6));
+            assert!(!window.accept(6));
+        }
+
+        #[test]
+        fn window_edges() {
+            let mut window = ReplayWindow::default();
+            assert!(window.accept(100));
+            // 63 behind is the oldest still taken, 64 behind is too old
+            assert!(window.accept(37));
+            assert!(!window.accept(36));
+            assert!(!window.accept(37));
+
+            // a jump of 64 or more forgets everything before
+            assert!(window.accept(164));
+            assert!(!window.accept(100));
+            assert!(window.accept(101));
+            assert!(window.accept(1000));
+            assert!(!window.accept(1000));
+            assert!(window.accept(999));
+        }
+
+        #[test]
+        fn window_shift_keeps_what_was_seen() {
+            let mut window = ReplayWindow::default();
+            assert!(window.accept(0));
+            assert!(window.accept(63));
+            assert!(!window.accept(0));
+            assert!(window.accept(1));
+            assert!(window.accept(64));
+            assert!(!window.accept(0));
+            assert!(!window.accept(1));
+        }
+
+        fn pair() -> (HmacStep, HmacStep) {
+            let mut fw = HmacStep::with(b"key".to_vec(), PipelineDirection::Forward);
+            let mut bw = HmacStep::with(b"key".to_vec(), PipelineDirection::Backward);
+            fw.start().unwrap();
+            bw.start().unwrap();
+            (fw, bw)
+        }
+
+        /// Moves what `from` has for `direction` over to `to`, and returns
+        /// what `to` lets through.
+        fn pass(
+            from: &mut HmacStep,
+            to: &mut HmacStep,
+            direction: PipelineDirection,
+        ) -> Result<Vec<u8>, IOError> {
+            from.set_pipeline_direction(direction);
+            let data = from.read()?;
+            to.set_pipeline_direction(direction);
+            to.write(&data)?;
+            to.read()
+        }
+
+        #[test]
+        fn round_trip_after_nonces() {
+            let (mut fw, mut bw) = pair();
+            fw.write(b"held").unwrap();
+            assert!(pass(&mut fw, &mut bw, PipelineDirection::Forward)
+                .unwrap()
+                .is_empty());
+            assert!(pass(&mut bw, &mut fw, PipelineDirection::Backward)
+                .unwrap()
+                .is_empty());
+            let opened = pass(&mut fw, &mut bw, PipelineDirection::Forward).unwrap();
+            assert_eq!(opened, b"held");
+
+            bw.set_pipeline_direction(PipelineDirection::Backward);
+            bw.write(b"back").unwrap();
+            let opened = pass(&mut bw, &mut fw, PipelineDirection::Backward).unwrap();
+            assert_eq!(opened, b"back");
+        }
+
+        #[test]
+        fn chunks_of_another_connection_are_rejected() {
+            let (mut fw, mut bw) = pair();
+            fw.set_pipeline_direction(PipelineDirection::Forward);
+            let nonce = fw.read().unwrap();
+            bw.write(&nonce).unwrap();
+            bw.read().unwrap();
+            pass(&mut bw, &mut fw, PipelineDirection::Backward).unwrap();
+            fw.set_pipeline_direction(PipelineDirection::Forward);
+            fw.write(b"recorded").unwrap();
+            let recorded = fw.read().unwrap();
+
+            // same key, same nonce of the initiator, a new one from the peer
+            let mut replayed = HmacStep::with(b"key".to_vec(), PipelineDirection::Backward);
+            replayed.start().unwrap();
+            replayed.write(&nonce).unwrap();
+            replayed.write(&recorded).unwrap();
+            assert!(replayed.read().is_err());
+        }
+
+        #[test]
+        fn forged_chunk_is_rejected() {
+            let (mut fw, mut bw) = pair();
+            pass(&mut fw, &mut bw, PipelineDirection::Forward).unwrap();
+            pass(&mut bw, &mut fw, PipelineDirection::Backward).unwrap();
+            fw.set_pipeline_direction(PipelineDirection::Forward);
+            fw.write(b"chunk").unwrap();
+            let mut chunk = fw.read().unwrap();
+            chunk[4] ^= 1;
+            bw.set_pipeline_direction(PipelineDirection::Forward);
+            bw.write(&chunk).unwrap();
+            assert!(bw.read().is_err());
+        }
+    }
+}
@@ -0,0 +1,234 @@
+pub mod http_camouflage {
+    use http::{
+        header::{CONTENT_TYPE, COOKIE, HOST, SET_COOKIE, USER_AGENT},
+        request, response, HeaderName, HeaderValue, Method, Request, Response, Uri, Version,
+    };
+    use rand::seq::SliceRandom;
+
+    use crate::{percent_decode, percent_encode, IOError, SpecOptions};
+
+    pub const DEFAULT_TOKEN_HEADER: &str = "client_token";
+    pub const DEFAULT_USER_AGENT: &str =
+        "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0";
+    pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+    /// Where the session token travels, `?token=header:name`, `cookie:name`
+    /// or `query:name`. Without it, the `client_token` header.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum TokenPlacement {
+        Header(String),
+        /// The entry hands the token over with `Set-Cookie`.
+        Cookie(String),
+        /// A parameter of the request path. The entry hands the token over
+        /// in a header of the same name.
+        Query(String),
+    }
+
+    impl Default for TokenPlacement {
+        fn default() -> Self {
+            TokenPlacement::Header(DEFAULT_TOKEN_HEADER.to_string())
+        }
+    }
+
+    impl TokenPlacement {
+        pub fn from_options(options: &SpecOptions) -> Result<TokenPlacement, IOError> {
+            let value = match options.get_str("token") {
+                Some(value) => value,
+                None => return Ok(TokenPlacement::default()),
+            };
+            let invalid = || {
+                IOError::InvalidConfig(format!(
+                    "invalid token placement : {}. use header:name, cookie:name or query:name",
+                    value
+                ))
+            };
+            let (kind, name) = value.split_once(':').ok_or_else(invalid)?;
+            if name.is_empty()
+                || !name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_".contains(&b))
+            {
+                return Err(invalid());
+            }
+            match kind {
+                "header" => Ok(TokenPlacement::Header(name.to_string())),
+                "cookie" => Ok(TokenPlacement::Cookie(name.to_string())),
+                "query" => Ok(TokenPlacement::Query(name.to_string())),
+                _ => Err(invalid()),
+            }
+        }
+
+        /// Adds `token` to `request`, in place of the one it may carry.
+        pub fn attach(&self, request: &mut Request<Vec<u8>>, token: &str) -> Result<(), IOError> {
+            let invalid = |e: http::Error| IOError::InvalidData(e.to_string());
+            match self {
+                TokenPlacement::Header(name) => {
+                    let name =
+                        HeaderName::from_bytes(name.as_bytes()).map_err(|e| invalid(e.into()))?;
+                    let value = HeaderValue::from_str(token).map_err(|e| invalid(e.into()))?;
+                    request.headers_mut().insert(name, value);
+                }
+                TokenPlacement::Cookie(name) => {
+                    let value = HeaderValue::from_str(&format!("{}={}", name, token))
+                        .map_err(|e| invalid(e.into()))?;
+                    request.headers_mut().insert(COOKIE, value);
+                }
+                TokenPlacement::Query(name) => {
+                    let path = request.uri().path().to_string();
+                    let uri = format!("{}?{}={}", path, name, percent_encode(token))
+                        .parse::<Uri>()
+                        .map_err(|e| invalid(e.into()))?;
+                    *request.uri_mut() = uri;
+                }
+            }
+            Ok(())
+        }
+
+        /// The token `request` carries.
+        pub fn find(&self, request: &Request<Vec<u8>>) -> Option<String> {
+            match self {
+                TokenPlacement::Header(name) => request
+                    .headers()
+                    .get(name.as_str())
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.to_string()),
+                TokenPlacement::Cookie(name) => request
+                    .headers()
+                    .get_all(COOKIE)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(';'))
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.to_string()),
+                TokenPlacement::Query(name) => request
+                    .uri()
+                    .query()?
+                    .split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| percent_decode(value)),
+            }
+        }
+
+        /// Hands a new `token` over in a handshake answer.
+        pub fn issue(&self, response: response::Builder, token: &str) -> response::Builder {
+            match self {
+                TokenPlacement::Header(name) | TokenPlacement::Query(name) => {
+                    response.header(name.as_str(), token)
+                }
+                TokenPlacement::Cookie(name) => {
+                    response.header(SET_COOKIE, format!("{}={}; Path=/; HttpOnly", name, token))
+                }
+            }
+        }
+
+        /// The token a handshake answer hands over.
+        pub fn received(&self, response: &Response<Vec<u8>>) -> Option<String> {
+            match self {
+                TokenPlacement::Header(name) | TokenPlacement::Query(name) => response
+                    .headers()
+                    .get(name.as_str())
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.to_string()),
+                TokenPlacement::Cookie(name) => response
+                    .headers()
+                    .get_all(SET_COOKIE)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .filter_map(|value| value.split(';').next()?.trim().split_once('='))
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.to_string()),
+            }
+        }
+    }
+
+    /// How the http step dresses its requests so they look like browsing :
+    /// `?paths=/a,/b&method=post|put&host=&user_agent=&content_type=&token=`.
+    #[derive(Clone, Debug)]
+    pub struct Camouflage {
+        /// Each request goes to one of them, picked at random.
+        pub paths: Vec<String>,
+        /// The method of uploads in long-poll mode.
+        pub upload_method: Method,
+        pub host: String,
+        pub user_agent: String,
+        /// Sent with requests that have a body.
+        pub content_type: String,
+        pub token: TokenPlacement,
+    }
+
+    impl Camouflage {
+        /// `host` is the `Host` header when the options do not set one.
+        pub fn from_options(options: &SpecOptions, host: &str) -> Result<Camouflage, IOError> {
+            let paths: Vec<String> = match options.get_str("paths") {
+                Some(paths) => paths
+                    .split(',')
+                    .filter(|path| !path.is_empty())
+                    .map(|path| path.to_string())
+                    .collect(),
+                None => vec!["/".to_string()],
+            };
+            if paths.is_empty() {
+                return Err(IOError::InvalidConfig("paths is empty".to_string()));
+            }
+            for path in &paths {
+                if !path.starts_with('/') || path.contains('?') || path.parse::<Uri>().is_err() {
+                    return Err(IOError::InvalidConfig(format!(
+                        "invalid path : {}. paths start with / and have no query",
+                        path
+                    )));
+                }
+            }
+
+            let upload_method = match options.get_str("method") {
+                None | Some("post") => Method::POST,
+                Some("put") => Method::PUT,
+                Some(method) => {
+                    return Err(IOError::InvalidConfig(format!(
+                        "unsupported upload method : {}. use post or put",
+                        method
+                    )))
+                }
+            };
+
+            let header = |key: &str, default: &str| -> Result<String, IOError> {
+                let value = options.get_str(key).unwrap_or(default);
+                HeaderValue::from_str(value).map_err(|e| {
+                    IOError::InvalidConfig(format!("invalid {} : {} : {}", key, value, e))
+                })?;
+                Ok(value.to_string())
+            };
+
+            Ok(Camouflage {
+                paths,
+                upload_method,
+                host: header("host", host)?,
+                user_agent: header("user_agent", DEFAULT_USER_AGENT)?,
+                content_type: header("content_type", DEFAULT_CONTENT_TYPE)?,
+                token: TokenPlacement::from_options(options)?,
+            })
+        }
+
+        /// A request to a random one of the paths, with the configured
+        /// headers. `with_body` adds the content type.
+        pub fn request(&self, method: Method, with_body: bool) -> request::Builder {
+            let path = self
+                .paths
+                .choose(&mut rand::thread_rng())
+                .map(|path| path.as_str())
+                .unwrap_or("/");
+            let request = Request::builder()
+                .method(method)
+                .uri(path)
+                .version(Version::HTTP_11)
+                .header(HOST, self.host.as_str())
+                .header(USER_AGENT, self.user_agent.as_str());
+            if with_body {
+                request.header(CONTENT_TYPE, self.content_type.as_str())
+            } else {
+                request
+            }
+        }
+    }
+}
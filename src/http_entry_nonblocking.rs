@@ -2,75 +2,276 @@ pub mod http_entry_nonblocking {
     use std::{
         collections::HashMap,
         io::{self, Read, Write},
-        net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+        net::{Shutdown, SocketAddr, TcpListener, TcpStream},
         os::fd::AsRawFd,
         result,
         str::FromStr,
         sync::{
+            atomic::{AtomicBool, Ordering},
             mpsc::{Receiver, Sender},
             Arc, Mutex,
         },
-        thread,
-        time::{Duration, SystemTime},
+        thread::{self, JoinHandle},
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
     };
 
-    use http::{request, Response, StatusCode, Uri};
+    use http::{request, Request, Response, StatusCode};
     use hyper::client::{self, conn};
-    use openssl::{base64, error, sha::sha256, string};
+    use openssl::{
+        base64, error, error::ErrorStack, hash::MessageDigest, memcmp, pkey::PKey, rand::rand_bytes,
+        sha::sha256, sign::Signer, string,
+    };
     use polling::{Event, Events, Poller};
     use regex::Regex;
     use std::sync::mpsc::channel;
     use threadpool::ThreadPool;
 
     use crate::{
-        pipeline_module::pipeline, read_request, write_response, Entry, IOError, Pipeline,
+        is_closed_before_message, keep_alive, peer_closed, pipeline_module::pipeline, read_request,
+        shutdown_listener, socket_address, write_response, AccessList, ClientRate, ConnectionGuard,
+        ConnectionLimiter, ConnectionPermit, Entry, EntryControl, IOError, LimitPolicy, Pipeline,
+        RateLimiter, RatePolicy, SpecOptions, TokenPlacement, Transfer,
     };
 
-    const CLIENT_TOKEN_HEADER: &str = "client_token";
+    const POLL_TIMEOUT_HEADER: &str = "poll_timeout";
+    /// Sequence number of an upload, a retried upload repeats it.
+    const SEQ_HEADER: &str = "seq";
+    /// Downstream bytes the client received so far.
+    const ACK_HEADER: &str = "ack";
+    /// Downstream byte offset of a response body.
+    const OFFSET_HEADER: &str = "offset";
+    /// Longest a GET is held, whatever the client asks for.
+    const MAX_POLL_TIMEOUT: Duration = Duration::from_secs(60);
+    /// How often a held GET looks for data.
+    const LONG_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    const REAPER_MIN_INTERVAL: Duration = Duration::from_millis(100);
+    const REAPER_MAX_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Seconds a session token stays valid, `?token_lifetime=` on the entry.
+    pub const DEFAULT_TOKEN_LIFETIME: u64 = 24 * 60 * 60;
+    /// TCP connections allowed per session the entry allows : a long-poll
+    /// client holds a GET and an upload, and retries open more.
+    pub const CONNECTIONS_PER_SESSION: usize = 4;
+    /// TCP connections open at once when sessions are not capped, as each
+    /// is served by a thread of its own.
+    pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+    /// A client session, from its handshake until it expires.
+    struct Session {
+        address: SocketAddr,
+        pipeline: Pipeline,
+        /// When the client last sent a request, for the reaper.
+        last_seen: SystemTime,
+        sequencing: Sequencing,
+        rate: ClientRate,
+        /// Keep the session counted by the entry for as long as it lives.
+        _guard: ConnectionGuard,
+        _permit: ConnectionPermit,
+    }
+
+    /// Sessions by token, each with a lock of its own so the pipeline I/O of
+    /// one does not hold up the others.
+    type Sessions = Arc<Mutex<HashMap<String, Arc<Mutex<Session>>>>>;
+
+    /// What a session already exchanged, so a retried request is neither
+    /// written twice to the pipeline nor loses the data its lost answer
+    /// carried.
+    #[derive(Default)]
+    struct Sequencing {
+        /// Sequence number of the last upload written to the pipeline.
+        last_upload: u64,
+        /// Downstream byte offset of `unacked[0]`.
+        acked: u64,
+        /// Downstream data sent and not acknowledged yet.
+        unacked: Vec<u8>,
+        /// Downstream data read from the pipeline and over the download
+        /// rate so far.
+        held: Vec<u8>,
+    }
+
+    impl Sequencing {
+        /// Whether the upload numbered `seq` was not written yet. Uploads
+        /// without a number are always new.
+        fn is_new_upload(&mut self, seq: Option<u64>) -> bool {
+            match seq {
+                Some(seq) if seq <= self.last_upload => false,
+                Some(seq) => {
+                    self.last_upload = seq;
+                    true
+                }
+                None => true,
+            }
+        }
+
+        /// Drops the data the client received. An ack past what was sent,
+        /// or before what it already acknowledged, is invalid.
+        fn acknowledge(&mut self, ack: u64) -> bool {
+            if ack < self.acked || ack - self.acked > self.unacked.len() as u64 {
+                return false;
+            }
+            self.unacked.drain(..(ack - self.acked) as usize);
+            self.acked = ack;
+            true
+        }
+    }
 
     pub struct HttpEntryNonblocking {
-        salt: String,
+        tokens: SessionTokens,
         expiration_time: Duration,
         poller: Poller,
         listener: TcpListener,
         listener_key: usize,
-        pipeline: Pipeline,
+        control: EntryControl,
         loop_time: u64,
+        rates: RateLimiter,
+        /// Counts sessions, whatever TCP connections they come over.
+        limits: ConnectionLimiter,
+        /// Counts TCP connections and holds them to the accept rate.
+        connections: ConnectionLimiter,
+        access: AccessList,
+    }
+
+    /// Issues and checks session tokens : `id.expiry.signature`, with a
+    /// random id, the expiry in unix seconds and an HMAC-SHA256 of both keyed
+    /// with the entry salt. They do not depend on the client address.
+    #[derive(Clone)]
+    struct SessionTokens {
+        key: Vec<u8>,
+        lifetime: Duration,
+        /// Where clients send them, `?token=` on the entry.
+        placement: TokenPlacement,
+    }
+
+    impl SessionTokens {
+        fn issue(&self) -> Result<String, IOError> {
+            let mut id = [0u8; 18];
+            rand_bytes(&mut id).map_err(|e| IOError::UnknownError(e.to_string()))?;
+            let expiry = (SystemTime::now() + self.lifetime)
+                .duration_since(UNIX_EPOCH)
+                .map_err(|e| IOError::UnknownError(e.to_string()))?
+                .as_secs();
+
+            let payload = format!("{}.{}", base64::encode_block(&id), expiry);
+            let signature = self
+                .sign(&payload)
+                .map_err(|e| IOError::UnknownError(e.to_string()))?;
+            Ok(format!("{}.{}", payload, base64::encode_block(&signature)))
+        }
+
+        fn verify(&self, token: &str) -> bool {
+            let (payload, signature) = match token.rsplit_once('.') {
+                Some(parts) => parts,
+                None => return false,
+            };
+            let signature = match base64::decode_block(signature) {
+                Ok(signature) => signature,
+                Err(_) => return false,
+            };
+            let expected = match self.sign(payload) {
+                Ok(expected) => expected,
+                Err(_) => return false,
+            };
+            if signature.len() != expected.len() || !memcmp::eq(&signature, &expected) {
+                return false;
+            }
+
+            let expiry = payload
+                .rsplit_once('.')
+                .and_then(|(_, expiry)| u64::from_str(expiry).ok());
+            match expiry {
+                Some(expiry) => UNIX_EPOCH + Duration::from_secs(expiry) > SystemTime::now(),
+                None => false,
+            }
+        }
+
+        fn sign(&self, payload: &str) -> Result<Vec<u8>, ErrorStack> {
+            let key = PKey::hmac(&self.key)?;
+            let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+            signer.update(payload.as_bytes())?;
+            signer.sign_to_vec()
+        }
+    }
+
+    /// Names the session of `token` in logs : the start of its random id,
+    /// which is no use without the signature, as tokens work from anywhere.
+    fn session_id(token: &str) -> &str {
+        token.get(..8).unwrap_or("")
     }
 
     impl Entry for HttpEntryNonblocking {
-        fn new(config: String, pipeline: Pipeline, loop_time: u64) -> Self {
-            let config: Vec<&str> = config.split('-').collect();
-            let timeout = Duration::from_secs(u64::from_str(config[2]).unwrap());
+        fn new(config: String, pipeline: Pipeline, loop_time: u64) -> Result<Self, IOError> {
+            let spec = config.clone();
+            let options = SpecOptions::parse(&spec);
+            let token_lifetime = options
+                .get::<u64>("token_lifetime")?
+                .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+            let rates = RatePolicy::from_options(&options)?;
+            let limits = LimitPolicy::from_options(&options)?;
+            let access = AccessList::from_options(&options)?;
+            let config: Vec<&str> = spec.split('?').next().unwrap_or("").split('-').collect();
+            if config.len() != 3 {
+                return Err(IOError::InvalidConfig(format!(
+                    "unsupported entry : {}. use with this format http://host:port-salt-timeout",
+                    spec
+                )));
+            }
+            let timeout = u64::from_str(config[2]).map_err(|e| {
+                IOError::InvalidConfig(format!("invalid session timeout {} : {}", config[2], e))
+            })?;
+            let timeout = Duration::from_secs(timeout);
+            if config[1].is_empty() {
+                return Err(IOError::InvalidConfig(format!(
+                    "{} : the salt signing session tokens is empty",
+                    spec
+                )));
+            }
 
             let re = Regex::new(r"((https|http)?:\/\/)([^:/$]{1,})(?::(\d{1,}))").unwrap();
             if !re.is_match(&config[0]) {
-                panic!(
-                    "unsupported config : {}. use with this format ws://host:port ",
-                    config[0]
-                )
+                return Err(IOError::InvalidConfig(format!(
+                    "unsupported entry : {}. use with this format http://host:port-salt-timeout",
+                    spec
+                )));
             }
 
-            let uri: Uri = config[0].parse::<Uri>().unwrap();
-            let mut addr = String::from(uri.host().unwrap());
-            addr.push_str(":");
-            addr.push_str(uri.port().unwrap().as_str());
-            let mut listener = TcpListener::bind(addr).unwrap();
-            let poller = Poller::new().unwrap();
+            let addr = socket_address(config[0])?;
+            let listener = TcpListener::bind(&addr)
+                .map_err(|e| IOError::from(e).context("http entry", &addr))?;
+            let poller = Poller::new()?;
 
             unsafe {
-                poller.add(&listener, Event::readable(1)).unwrap();
+                poller.add(&listener, Event::readable(1))?;
             }
 
-            HttpEntryNonblocking {
-                salt: String::from_str(config[1]).unwrap(),
+            Ok(HttpEntryNonblocking {
+                tokens: SessionTokens {
+                    key: config[1].as_bytes().to_vec(),
+                    lifetime: Duration::from_secs(token_lifetime),
+                    placement: TokenPlacement::from_options(&options)?,
+                },
                 poller,
                 listener,
                 listener_key: 1,
-                pipeline: pipeline,
+                control: EntryControl::new(pipeline),
                 loop_time,
                 expiration_time: timeout,
-            }
+                rates: RateLimiter::new(rates),
+                limits: ConnectionLimiter::new(LimitPolicy {
+                    accept_rate: None,
+                    ..limits
+                }),
+                connections: ConnectionLimiter::new(LimitPolicy {
+                    max_connections: Some(
+                        limits
+                            .max_connections
+                            .map_or(DEFAULT_MAX_CONNECTIONS, |max| max * CONNECTIONS_PER_SESSION),
+                    ),
+                    max_per_ip: limits.max_per_ip.map(|max| max * CONNECTIONS_PER_SESSION),
+                    accept_rate: limits.accept_rate,
+                }),
+                access,
+            })
         }
 
         fn len(stream: &mut dyn AsRawFd) -> std::io::Result<usize> {
@@ -89,71 +290,134 @@ pub mod http_entry_nonblocking {
             let mut events = Events::new();
             // let (client_channel_tx, client_channel_rx) =
             //     channel::<(PollerKey, SocketAddr, Poller)>();
-            let pipeline_mutex = Arc::new(Mutex::new(self.pipeline.clone()));
-
-            let connections: HashMap<String, (SocketAddr, Pipeline, SystemTime)> = HashMap::new();
-            let connectiond_mutex = Arc::new(Mutex::new(connections));
-
-            loop {
-                self.poller.wait(&mut events, None).unwrap();
+            let connectiond_mutex: Sessions = Arc::new(Mutex::new(HashMap::new()));
+            let reaper_stopped = Arc::new(AtomicBool::new(false));
+            let reaper = HttpEntryNonblocking::start_reaper(
+                connectiond_mutex.clone(),
+                self.expiration_time,
+                reaper_stopped.clone(),
+            );
+
+            // once stopped, keep serving the existing sessions until they expire
+            while !(self.control.is_stopped() && connectiond_mutex.lock().unwrap().is_empty()) {
+                events.clear();
+                self.poller
+                    .wait(&mut events, Some(Duration::from_millis(self.loop_time)))
+                    .unwrap();
 
                 for ev in events.iter() {
                     if ev.key == self.listener_key {
-                        let mut connection = self.listener.accept().unwrap();
-                        connection.0.set_nonblocking(true).unwrap();
+                        let accepted = self.listener.accept();
+                        self.poller
+                            .modify(&self.listener, Event::readable(self.listener_key))
+                            .unwrap();
+                        let connection = match accepted {
+                            Ok(connection) => connection,
+                            Err(e) => {
+                                println!("accept failed : {}", e);
+                                continue;
+                            }
+                        };
+                        if !self.access.permits(connection.1.ip()) {
+                            println!(
+                                "client denied, address : {} ({} rejected so far)",
+                                connection.1,
+                                self.control.reject()
+                            );
+                            let _ = connection.0.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                        // checked before a thread is spawned for the connection
+                        let permit = match self.connections.admit(connection.1.ip()) {
+                            Ok(permit) => permit,
+                            Err(reason) => {
+                                println!(
+                                    "client rejected, address : {}, {} ({} so far)",
+                                    connection.1,
+                                    reason,
+                                    self.control.reject()
+                                );
+                                let _ =
+                                    HttpEntryNonblocking::write_service_unavailable(connection.0);
+                                continue;
+                            }
+                        };
+                        if let Err(e) = connection.0.set_nonblocking(true) {
+                            println!("{} : {}", connection.1, e);
+                            continue;
+                        }
 
-                        let pipeline_mutex = pipeline_mutex.clone();
+                        let control = self.control.clone();
                         let connectiond_mutex = connectiond_mutex.clone();
-                        let salt = self.salt.clone();
+                        let tokens = self.tokens.clone();
+                        let rates = self.rates.clone();
+                        let limits = self.limits.clone();
                         thread::spawn(move || {
-                            HttpEntryNonblocking::handle_connection(
+                            let _permit = permit;
+                            if let Err(e) = HttpEntryNonblocking::handle_connection(
                                 connection.0,
                                 connection.1,
-                                pipeline_mutex,
-                                salt,
+                                control,
+                                tokens,
+                                rates,
+                                limits,
                                 connectiond_mutex,
-                            )
-                            .unwrap();
+                            ) {
+                                println!("{} : {}", connection.1, e);
+                            }
                         });
-
-                        self.poller
-                            .modify(&self.listener, Event::readable(self.listener_key))
-                            .unwrap();
                     }
                 }
 
-                HttpEntryNonblocking::check_expiration(
-                    connectiond_mutex.clone(),
-                    self.expiration_time,
-                );
+                if self.control.is_closing() {
+                    HttpEntryNonblocking::close_sessions(connectiond_mutex.clone());
+                }
             }
+
+            reaper_stopped.store(true, Ordering::SeqCst);
+            if let Some(reaper) = reaper {
+                let _ = reaper.join();
+            }
+
+            self.poller.delete(&self.listener).unwrap();
+            shutdown_listener(&self.listener);
+        }
+
+        fn control(&self) -> EntryControl {
+            self.control.clone()
         }
     }
 
     impl Clone for HttpEntryNonblocking {
         fn clone(&self) -> Self {
             Self {
-                salt: self.salt.clone(),
+                tokens: self.tokens.clone(),
                 poller: Poller::new().unwrap(),
                 listener: self.listener.try_clone().unwrap(),
                 listener_key: self.listener_key.clone(),
-                pipeline: self.pipeline.clone(),
+                control: self.control.clone(),
                 loop_time: self.loop_time,
                 expiration_time: self.expiration_time,
+                rates: self.rates.clone(),
+                limits: self.limits.clone(),
+                connections: self.connections.clone(),
+                access: self.access.clone(),
             }
         }
     }
 
     impl HttpEntryNonblocking {
-        fn write_handshake(token: &str, connection: TcpStream) -> Result<(), IOError> {
-            let response = Response::builder()
-                .status(StatusCode::OK)
-                .header(CLIENT_TOKEN_HEADER, token)
+        fn write_handshake(
+            placement: &TokenPlacement,
+            token: &str,
+            connection: TcpStream,
+        ) -> Result<(), IOError> {
+            let response = placement
+                .issue(Response::builder().status(StatusCode::OK), token)
                 .body(vec![0u8; 0])
                 .unwrap();
 
             write_response(connection, response)?;
-            println!("token: {} ", token);
             Ok(())
         }
 
@@ -179,10 +443,10 @@ pub mod http_entry_nonblocking {
             return Err(IOError::InvalidData(msg.to_string()));
         }
 
-        fn write_unsupported_http_method_error(connection: TcpStream) -> Result<(), IOError> {
-            let msg = "Unsupported Http Method";
+        fn write_service_unavailable(connection: TcpStream) -> Result<(), IOError> {
+            let msg = "Service Unavailable";
             let response = Response::builder()
-                .status(StatusCode::BAD_REQUEST)
+                .status(StatusCode::SERVICE_UNAVAILABLE)
                 .body(msg.as_bytes().to_vec())
                 .unwrap();
 
@@ -190,17 +454,35 @@ pub mod http_entry_nonblocking {
             return Err(IOError::InvalidData(msg.to_string()));
         }
 
-        fn generate_token(ip: IpAddr, salt: &str) -> String {
-            let mut hasher = openssl::sha::Sha256::new();
-            let mut client_key = String::from_str(&ip.to_string()).unwrap();
-            client_key.push_str(&salt);
-            hasher.update(client_key.as_bytes());
-            let client_key = hasher.finish();
-            base64::encode_block(&client_key)
+        fn write_bad_gateway(connection: TcpStream, error: IOError) -> Result<(), IOError> {
+            let response = Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body("Bad Gateway".as_bytes().to_vec())
+                .unwrap();
+
+            write_response(connection, response)?;
+            return Err(error);
+        }
+
+        fn write_bad_request(connection: TcpStream, msg: &str) -> Result<(), IOError> {
+            let response = Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(msg.as_bytes().to_vec())
+                .unwrap();
+
+            write_response(connection, response)?;
+            return Err(IOError::InvalidData(msg.to_string()));
         }
 
-        fn validate_token(ip: IpAddr, salt: &str, token: &str) -> bool {
-            HttpEntryNonblocking::generate_token(ip, salt) == token
+        fn write_unsupported_http_method_error(connection: TcpStream) -> Result<(), IOError> {
+            let msg = "Unsupported Http Method";
+            let response = Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(msg.as_bytes().to_vec())
+                .unwrap();
+
+            write_response(connection, response)?;
+            return Err(IOError::InvalidData(msg.to_string()));
         }
 
         fn write_response(connection: TcpStream, data: Vec<u8>) -> Result<(), IOError> {
@@ -225,113 +507,223 @@ pub mod http_entry_nonblocking {
             Ok(())
         }
 
+        /// Serves requests until the client or an error closes the
+        /// connection, or the entry stops.
         fn handle_connection(
             mut connection: TcpStream,
             address: SocketAddr,
-            pipeline_mutex: Arc<Mutex<Pipeline>>,
-            salt: String,
-            connections: Arc<Mutex<HashMap<String, (SocketAddr, Pipeline, SystemTime)>>>,
+            control: EntryControl,
+            tokens: SessionTokens,
+            rates: RateLimiter,
+            limits: ConnectionLimiter,
+            connections: Sessions,
         ) -> Result<(), IOError> {
-            let request = read_request(&mut connection)?;
-
-            if !request.headers().contains_key(CLIENT_TOKEN_HEADER) {
-                println!("new req {}", address.to_string());
-                for (key, value) in request.headers() {
-                    println!("{}:{}", key, value.to_str().unwrap());
+            let mut served = 0;
+            loop {
+                let request = match read_request(&mut connection) {
+                    Ok(request) => request,
+                    // an idle keep-alive connection the client closed or left
+                    Err(e)
+                        if served > 0
+                            && (is_closed_before_message(&e)
+                                || e.kind() == io::ErrorKind::TimedOut) =>
+                    {
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let keep_alive = keep_alive(request.version(), request.headers());
+
+                HttpEntryNonblocking::handle_request(
+                    connection.try_clone()?,
+                    request,
+                    address,
+                    &control,
+                    &tokens,
+                    &rates,
+                    &limits,
+                    &connections,
+                )?;
+                served += 1;
+
+                if !keep_alive || control.is_stopped() {
+                    return Ok(());
                 }
-                println!("+++++++++++++++++++++++++++++++++");
+            }
+        }
 
-                let token = HttpEntryNonblocking::generate_token(address.ip(), &salt);
-                let mut connections = connections.as_ref().lock().unwrap();
-                if connections.contains_key(token.clone().as_str()) {
-                    // return HttpEntryNonblocking::write_existing_pipeline_error(connection);
-                    connections.remove(token.clone().as_str());
+        fn handle_request(
+            connection: TcpStream,
+            request: Request<Vec<u8>>,
+            address: SocketAddr,
+            control: &EntryControl,
+            tokens: &SessionTokens,
+            rates: &RateLimiter,
+            limits: &ConnectionLimiter,
+            connections: &Sessions,
+        ) -> Result<(), IOError> {
+            let found = tokens.placement.find(&request);
+            if found.is_none() {
+                if control.is_stopped() {
+                    return HttpEntryNonblocking::write_service_unavailable(connection);
                 }
-                let mut pipeline = pipeline_mutex.lock().as_mut().unwrap().clone();
-                pipeline.start();
-                connections.insert(token.clone(), (address, pipeline, SystemTime::now()));
-                return HttpEntryNonblocking::write_handshake(&token.clone(), connection);
-            } else {
-                let token = request
-                    .headers()
-                    .get(CLIENT_TOKEN_HEADER)
+                let permit = match limits.admit(address.ip()) {
+                    Ok(permit) => permit,
+                    Err(reason) => {
+                        println!(
+                            "client rejected, address : {}, {} ({} so far)",
+                            address,
+                            reason,
+                            control.reject()
+                        );
+                        return HttpEntryNonblocking::write_service_unavailable(connection);
+                    }
+                };
+
+                // every handshake gets a session of its own, so clients sharing
+                // an address do not take over each other's pipeline. Starting
+                // it may wait on the upstream, the other sessions do not.
+                let token = tokens.issue()?;
+                let mut pipeline = control.pipeline();
+                if let Err(e) = pipeline.start() {
+                    return HttpEntryNonblocking::write_bad_gateway(connection, e);
+                }
+                let session = Session {
+                    address,
+                    pipeline,
+                    last_seen: SystemTime::now(),
+                    sequencing: Sequencing::default(),
+                    rate: rates.client(),
+                    _guard: control.connection(),
+                    _permit: permit,
+                };
+                connections
+                    .lock()
                     .unwrap()
-                    .as_bytes();
-                let token = std::str::from_utf8(token).unwrap();
+                    .insert(token.clone(), Arc::new(Mutex::new(session)));
+                println!(
+                    "session opened, id : {}, address : {}",
+                    session_id(&token),
+                    address
+                );
+                return HttpEntryNonblocking::write_handshake(
+                    &tokens.placement,
+                    &token,
+                    connection,
+                );
+            } else {
+                let token = found.unwrap_or_default();
+                let token = token.as_str();
 
-                if !HttpEntryNonblocking::validate_token(address.ip(), &salt, token) {
+                if !tokens.verify(token) {
                     return HttpEntryNonblocking::write_invalid_access(connection);
                 }
-                let mut connections = connections.as_ref().lock().unwrap();
 
-                match Some(request.method()) {
-                    Some(&http::Method::GET) => {
-                        if connections.contains_key(token) {
-                            let mut pipeline = connections.get_mut(token).unwrap();
-                            let data = request.body().to_vec();
-                            if data.len() > 0 {
-                                if let Err(e) = pipeline.1.write(data) {
-                                    match e {
-                                        IOError::InvalidConnection
-                                        | IOError::InvalidBindAddress
-                                        | IOError::UnknownError(_)
-                                        | IOError::IoError(_)
-                                        | IOError::ParseError
-                                        | IOError::InvalidStep(_)
-                                        | IOError::InvalidData(_)
-                                        | IOError::Error(_) => return Err(e),
-                                        IOError::EmptyData => {
-                                            return HttpEntryNonblocking::write_response(
-                                                connection,
-                                                vec![0u8; 0],
-                                            );
-                                        }
-                                    }
-                                }
-                            }
-                            pipeline.2 = SystemTime::now();
-
-                            match pipeline.1.read() {
-                                Ok(data) => {
-                                    return HttpEntryNonblocking::write_response(connection, data);
-                                }
-                                Err(e) => match e {
-                                    IOError::InvalidConnection
-                                    | IOError::InvalidBindAddress
-                                    | IOError::UnknownError(_)
-                                    | IOError::IoError(_)
-                                    | IOError::ParseError
-                                    | IOError::InvalidStep(_)
-                                    | IOError::InvalidData(_)
-                                    | IOError::Error(_) => return Err(e),
-                                    IOError::EmptyData => {
-                                        return HttpEntryNonblocking::write_response(
-                                            connection,
-                                            vec![0u8; 0],
-                                        );
-                                    }
-                                },
+                let (seq, ack) = match (
+                    HttpEntryNonblocking::header_number(&request, SEQ_HEADER),
+                    HttpEntryNonblocking::header_number(&request, ACK_HEADER),
+                ) {
+                    (Ok(seq), Ok(ack)) => (seq, ack),
+                    _ => {
+                        return HttpEntryNonblocking::write_bad_request(
+                            connection,
+                            "Invalid Sequence Number",
+                        )
+                    }
+                };
+
+                if request.method() == http::Method::GET {
+                    if let Some(timeout) = request.headers().get(POLL_TIMEOUT_HEADER) {
+                        let timeout = match timeout
+                            .to_str()
+                            .ok()
+                            .and_then(|timeout| u64::from_str(timeout).ok())
+                        {
+                            Some(timeout) => Duration::from_millis(timeout).min(MAX_POLL_TIMEOUT),
+                            None => {
+                                return HttpEntryNonblocking::write_bad_request(
+                                    connection,
+                                    "Invalid Poll Timeout",
+                                );
                             }
-                        } else {
-                            return HttpEntryNonblocking::write_invalid_access(connection);
-                        }
+                        };
+                        return HttpEntryNonblocking::long_poll(
+                            connection,
+                            token,
+                            ack,
+                            timeout,
+                            connections,
+                        );
                     }
-                    Some(&http::Method::HEAD) => {
-                        if connections.contains_key(token) {
-                            let mut pipeline = connections.get_mut(token).unwrap();
-                            // pipeline.1.write(request.body().to_vec()).unwrap();
-                            pipeline.2 = SystemTime::now();
-
-                            let pipeline_len = pipeline.1.len()?;
+                }
 
-                            return HttpEntryNonblocking::write_content_len(
+                // uploads come as POST or PUT, and as the body of a GET when
+                // polling
+                let is_upload = matches!(
+                    *request.method(),
+                    http::Method::GET | http::Method::POST | http::Method::PUT
+                );
+                let session = match HttpEntryNonblocking::session(connections, token) {
+                    Some(session) => session,
+                    None => return HttpEntryNonblocking::write_invalid_access(connection),
+                };
+                let uploaded = {
+                    let mut session = session.lock().unwrap();
+                    session.last_seen = SystemTime::now();
+                    if let Some(ack) = ack {
+                        if !session.sequencing.acknowledge(ack) {
+                            return HttpEntryNonblocking::write_bad_request(
                                 connection,
-                                pipeline_len,
+                                "Invalid Ack",
                             );
-                        } else {
-                            return HttpEntryNonblocking::write_invalid_access(connection);
                         }
                     }
+                    // a retried upload is neither written nor counted again
+                    let data = request.body();
+                    if is_upload && !data.is_empty() && session.sequencing.is_new_upload(seq) {
+                        match session.pipeline.write(data.to_vec()) {
+                            Ok(_) | Err(IOError::EmptyData) => {}
+                            Err(e) => return Err(e),
+                        }
+                        session.rate.take(Transfer::Upload, data.len());
+                        true
+                    } else {
+                        false
+                    }
+                };
+                if uploaded {
+                    HttpEntryNonblocking::wait_for_rate(&session, Transfer::Upload);
+                }
+
+                // the session may have expired meanwhile
+                let session = match HttpEntryNonblocking::session(connections, token) {
+                    Some(session) => session,
+                    None => return HttpEntryNonblocking::write_invalid_access(connection),
+                };
+                let mut session = session.lock().unwrap();
+
+                match Some(request.method()) {
+                    Some(&http::Method::GET)
+                    | Some(&http::Method::POST)
+                    | Some(&http::Method::PUT) => {
+                        if request.method() != http::Method::GET {
+                            return HttpEntryNonblocking::write_response(connection, vec![0u8; 0]);
+                        }
+
+                        let data = HttpEntryNonblocking::next_download(&mut session, ack)?;
+                        return HttpEntryNonblocking::write_download(
+                            connection, &session, ack, data,
+                        );
+                    }
+                    Some(&http::Method::HEAD) => {
+                        let pending = if ack.is_some() && !session.sequencing.unacked.is_empty() {
+                            session.sequencing.unacked.len()
+                        } else {
+                            session.sequencing.held.len() + session.pipeline.len()?
+                        };
+
+                        return HttpEntryNonblocking::write_content_len(connection, pending);
+                    }
                     Some(_) | None => {
                         return HttpEntryNonblocking::write_unsupported_http_method_error(
                             connection,
@@ -341,20 +733,212 @@ pub mod http_entry_nonblocking {
             }
         }
 
-        fn check_expiration(
-            connections: Arc<Mutex<HashMap<String, (SocketAddr, Pipeline, SystemTime)>>>,
+        /// The numeric value of header `name`, if the request has it.
+        fn header_number(request: &Request<Vec<u8>>, name: &str) -> Result<Option<u64>, ()> {
+            match request.headers().get(name) {
+                Some(value) => value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| u64::from_str(value).ok())
+                    .map(Some)
+                    .ok_or(()),
+                None => Ok(None),
+            }
+        }
+
+        fn session(connections: &Sessions, token: &str) -> Option<Arc<Mutex<Session>>> {
+            connections.lock().unwrap().get(token).cloned()
+        }
+
+        /// Holds the request until the session is back under its rate for
+        /// `transfer`, without keeping its lock meanwhile.
+        fn wait_for_rate(session: &Mutex<Session>, transfer: Transfer) {
+            loop {
+                let wait = session.lock().unwrap().rate.wait(transfer);
+                if wait.is_zero() {
+                    return;
+                }
+                thread::sleep(wait);
+            }
+        }
+
+        /// What to answer a download with : the data the client did not
+        /// acknowledge yet, or else what the pipeline has, as far as the
+        /// download rate allows. Without an `ack` the client cannot retry, so
+        /// nothing is kept.
+        fn next_download(session: &mut Session, ack: Option<u64>) -> Result<Vec<u8>, IOError> {
+            if ack.is_some() && !session.sequencing.unacked.is_empty() {
+                return Ok(session.sequencing.unacked.clone());
+            }
+            if session.sequencing.held.is_empty() {
+                session.sequencing.held = match session.pipeline.read() {
+                    Ok(data) => data,
+                    Err(IOError::EmptyData) => vec![0u8; 0],
+                    Err(e) => return Err(e),
+                };
+            }
+            let allowance = session.rate.allowance(Transfer::Download);
+            let data: Vec<u8> = match session.sequencing.held.len() > allowance {
+                true => session.sequencing.held.drain(..allowance).collect(),
+                false => std::mem::take(&mut session.sequencing.held),
+            };
+            session.rate.take(Transfer::Download, data.len());
+            if ack.is_some() {
+                session.sequencing.unacked.extend_from_slice(&data);
+            }
+            Ok(data)
+        }
+
+        fn write_download(
+            connection: TcpStream,
+            session: &Session,
+            ack: Option<u64>,
+            data: Vec<u8>,
+        ) -> Result<(), IOError> {
+            let mut response = Response::builder()
+                .status(StatusCode::OK)
+                .header(http::header::CONTENT_LENGTH, data.len());
+            if ack.is_some() {
+                response = response.header(OFFSET_HEADER, session.sequencing.acked);
+            }
+
+            write_response(connection, response.body(data).unwrap())?;
+            Ok(())
+        }
+
+        /// Answers a GET as soon as the session has data for it, or with an
+        /// empty body once `timeout` is over.
+        fn long_poll(
+            connection: TcpStream,
+            token: &str,
+            ack: Option<u64>,
+            timeout: Duration,
+            connections: &Sessions,
+        ) -> Result<(), IOError> {
+            if let Some(ack) = ack {
+                if let Some(session) = HttpEntryNonblocking::session(connections, token) {
+                    if !session.lock().unwrap().sequencing.acknowledge(ack) {
+                        return HttpEntryNonblocking::write_bad_request(connection, "Invalid Ack");
+                    }
+                }
+            }
+
+            let deadline = Instant::now() + timeout;
+            loop {
+                // the client went away, keep the data for its next poll
+                if peer_closed(&connection) {
+                    return Ok(());
+                }
+
+                let session = match HttpEntryNonblocking::session(connections, token) {
+                    Some(session) => session,
+                    None => return HttpEntryNonblocking::write_invalid_access(connection),
+                };
+                let mut session = session.lock().unwrap();
+                session.last_seen = SystemTime::now();
+                let retransmit = ack.is_some() && !session.sequencing.unacked.is_empty();
+                if retransmit
+                    || !session.sequencing.held.is_empty()
+                    || session.pipeline.read_available()
+                {
+                    match HttpEntryNonblocking::next_download(&mut session, ack) {
+                        Ok(data) if !data.is_empty() => {
+                            return HttpEntryNonblocking::write_download(
+                                connection, &session, ack, data,
+                            );
+                        }
+                        Ok(_) => {}
+                        Err(e) => return HttpEntryNonblocking::write_bad_gateway(connection, e),
+                    }
+                }
+
+                if Instant::now() >= deadline {
+                    return HttpEntryNonblocking::write_download(
+                        connection,
+                        &session,
+                        ack,
+                        vec![0u8; 0],
+                    );
+                }
+                drop(session);
+                thread::sleep(LONG_POLL_INTERVAL);
+            }
+        }
+
+        fn close_sessions(connections: Sessions) {
+            let sessions: Vec<_> = connections.lock().unwrap().drain().collect();
+            for (token, session) in sessions {
+                let mut session = session.lock().unwrap();
+                session.pipeline.close();
+                println!(
+                    "session closed, id : {}, address : {}",
+                    session_id(&token),
+                    session.address
+                );
+            }
+        }
+
+        /// Evicts the sessions idle for longer than `timeout` until `stopped`
+        /// is set.
+        fn start_reaper(
+            connections: Sessions,
             timeout: Duration,
-        ) {
-            let mut connections = connections.as_ref().lock().unwrap();
-            let mut expired_token = vec![String::new(); 0];
-            for (token, data) in connections.iter_mut() {
-                let dur = SystemTime::now().duration_since(data.2).unwrap();
-                if dur > timeout {
-                    expired_token.push(token.clone());
+            stopped: Arc<AtomicBool>,
+        ) -> Option<JoinHandle<()>> {
+            let interval = (timeout / 2).clamp(REAPER_MIN_INTERVAL, REAPER_MAX_INTERVAL);
+            let spawned = thread::Builder::new()
+                .name("http-session-reaper".to_string())
+                .spawn(move || {
+                    while !stopped.load(Ordering::SeqCst) {
+                        thread::sleep(interval);
+                        HttpEntryNonblocking::check_expiration(&connections, timeout);
+                    }
+                });
+            match spawned {
+                Ok(reaper) => Some(reaper),
+                Err(e) => {
+                    println!("failed to start the http session reaper : {}", e);
+                    None
                 }
             }
-            for token in expired_token {
-                connections.remove(&token);
+        }
+
+        fn check_expiration(connections: &Sessions, timeout: Duration) {
+            // a session busy with its pipeline is looked at without holding
+            // the map, and closing may wait on the upstream too
+            let sessions: Vec<(String, Arc<Mutex<Session>>)> = connections
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(token, session)| (token.clone(), session.clone()))
+                .collect();
+            for (token, session) in sessions {
+                let idle = session
+                    .lock()
+                    .unwrap()
+                    .last_seen
+                    .elapsed()
+                    .unwrap_or_default();
+                if idle <= timeout {
+                    continue;
+                }
+                {
+                    let mut connections = connections.lock().unwrap();
+                    match connections.get(&token) {
+                        Some(current) if Arc::ptr_eq(current, &session) => {
+                            connections.remove(&token);
+                        }
+                        _ => continue,
+                    }
+                }
+                let mut session = session.lock().unwrap();
+                session.pipeline.close();
+                println!(
+                    "session expired after {} s idle, id : {}, address : {}",
+                    idle.as_secs(),
+                    session_id(&token),
+                    session.address
+                );
             }
         }
     }
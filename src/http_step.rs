@@ -1,60 +1,118 @@
 #[allow(non_snake_case, unused_variables, dead_code)]
 pub mod http_step {
-    use http::{method, HeaderValue, Method, Response, StatusCode, Version};
+    use http::{method, HeaderValue, Method, Response, StatusCode};
+    use serde::Deserialize;
     use std::io::{self, Write};
-    use std::net::TcpStream;
+    use std::net::{Shutdown, TcpStream};
     use std::os::fd::AsRawFd;
     use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
     use std::thread;
     use std::time::Duration;
-    use tungstenite::http::{Request, Uri};
+    use tungstenite::http::Request;
     use tungstenite::protocol::{Role, WebSocketContext};
     use tungstenite::{client, Message, WebSocket};
 
     use crate::pipeline_module::pipeline::{IOError, PipelineDirection, PipelineStep, Read};
-    use crate::{read_response, write_request, BoxedClone};
+    use crate::{
+        is_closed_before_message, keep_alive, read_response, socket_address, write_request,
+        BoxedClone, Camouflage, SpecOptions,
+    };
 
-    const CLIENT_TOKEN_HEADER: &str = "client_token";
+    /// Asks the entry to hold a GET until data arrives, for at most this many
+    /// milliseconds.
+    const POLL_TIMEOUT_HEADER: &str = "poll_timeout";
+    /// Numbers uploads so the entry ignores a retried one it already wrote.
+    const SEQ_HEADER: &str = "seq";
+    /// Tells the entry how much downstream data arrived, so it can drop it
+    /// or send the rest again.
+    const ACK_HEADER: &str = "ack";
+    /// Downstream byte offset of what the entry answers.
+    const OFFSET_HEADER: &str = "offset";
+    /// Sessions opened again for one request the entry answered 403 to.
+    const MAX_HANDSHAKES: u32 = 1;
+    /// Waited before the n-th retry of a request, times n.
+    const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+    pub const DEFAULT_POLL_TIMEOUT: u64 = 20_000;
+    pub const DEFAULT_REQUEST_TIMEOUT: u64 = 30_000;
+    pub const DEFAULT_RETRIES: u32 = 3;
+
+    /// How the step moves data, given as `?mode=` on the step.
+    #[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum HttpMode {
+        /// Uploads as GET bodies, and asks for pending data with HEAD every
+        /// 100 ms.
+        Poll,
+        /// Uploads with POST, and downloads with a GET the entry holds until
+        /// there is data, on a connection of its own.
+        LongPoll,
+    }
+
+    impl HttpMode {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                HttpMode::Poll => "poll",
+                HttpMode::LongPoll => "longpoll",
+            }
+        }
+
+        pub fn from_options(options: &SpecOptions) -> Result<HttpMode, IOError> {
+            match options.get_str("mode") {
+                None | Some("poll") => Ok(HttpMode::Poll),
+                Some("longpoll") => Ok(HttpMode::LongPoll),
+                Some(mode) => Err(IOError::InvalidConfig(format!(
+                    "unknown http mode : {}. use poll or longpoll",
+                    mode
+                ))),
+            }
+        }
+    }
 
     pub struct HttpStep {
-        token: Option<String>,
+        session: Arc<Session>,
         address: String,
+        socket_address: String,
         buffer: Vec<u8>,
+        connection: HttpConnection,
+        mode: HttpMode,
+        camouflage: Camouflage,
+        poll_timeout: Duration,
+        retries: u32,
+        /// Sequence number of the last upload.
+        upload_seq: u64,
+        /// Downstream bytes received in this session.
+        received: u64,
+        /// What the long-poll thread received, in long-poll mode once started.
+        downloads: Option<Arc<Downloads>>,
     }
 
     impl PipelineStep for HttpStep {
         fn len(&mut self) -> std::io::Result<usize> {
+            if let Some(downloads) = &self.downloads {
+                return downloads.len();
+            }
             // Ok(self.buffer.len())
             if self.buffer.len() != 0 {
                 Ok(self.buffer.len())
             } else {
                 thread::sleep(Duration::from_millis(100));
-                let mut request = Request::builder()
-                    .method(http::Method::HEAD)
-                    .version(Version::HTTP_11)
+                let mut request = self
+                    .camouflage
+                    .request(Method::HEAD, false)
                     .body(vec![0u8; 0])
                     .unwrap();
                 let response = self.write_request(&mut request)?;
-                if response
-                    .headers()
-                    .contains_key(http::header::CONTENT_LENGTH)
-                {
-                    let l = response
-                        .headers()
-                        .get(http::header::CONTENT_LENGTH)
-                        .unwrap()
-                        .to_str();
-
-                    let len = usize::from_str(
-                        response
-                            .headers()
-                            .get(http::header::CONTENT_LENGTH)
-                            .unwrap()
-                            .to_str()
-                            .unwrap(),
-                    )
-                    .unwrap();
-                    Ok(len)
+                if let Some(value) = response.headers().get(http::header::CONTENT_LENGTH) {
+                    value
+                        .to_str()
+                        .ok()
+                        .and_then(|value| usize::from_str(value).ok())
+                        .ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::InvalidData, "Invalid Content-Length")
+                        })
                 } else {
                     Err(io::Error::new(
                         io::ErrorKind::NotFound,
@@ -68,32 +126,67 @@ pub mod http_step {
             // println!("{}", direction);
         }
 
-        fn start(&mut self) {}
+        fn start(&mut self) -> Result<(), IOError> {
+            if self.mode == HttpMode::LongPoll {
+                let session = self.session.clone();
+                session.reopen(None, || self.handshake())?;
+                self.start_long_poll()?;
+            }
+            Ok(())
+        }
+
+        fn close(&mut self) {
+            if let Some(downloads) = self.downloads.take() {
+                downloads.stop();
+            }
+        }
+    }
+
+    impl Drop for HttpStep {
+        fn drop(&mut self) {
+            self.close();
+        }
     }
 
     impl BoxedClone for HttpStep {
         fn bclone(&self) -> Box<dyn PipelineStep> {
-            Box::new(HttpStep::new(&self.address))
+            Box::new(HttpStep {
+                session: Arc::new(Session::default()),
+                address: self.address.clone(),
+                socket_address: self.socket_address.clone(),
+                buffer: Vec::new(),
+                connection: self.connection.fresh(),
+                mode: self.mode,
+                camouflage: self.camouflage.clone(),
+                poll_timeout: self.poll_timeout,
+                retries: self.retries,
+                upload_seq: 0,
+                received: 0,
+                downloads: None,
+            })
         }
     }
 
     impl Read for HttpStep {
         fn read(&mut self) -> Result<Vec<u8>, IOError> {
-            let mut connection = self.make_connection()?;
+            if let Some(downloads) = &self.downloads {
+                return Ok(downloads.take()?);
+            }
 
-            let mut request = Request::builder()
-                .method(Method::GET)
-                .version(Version::HTTP_11)
-                .header(http::header::CONTENT_LENGTH, self.buffer.len())
-                .body(self.buffer.to_vec())
-                .unwrap();
+            let mut request = self
+                .camouflage
+                .request(Method::GET, !self.buffer.is_empty())
+                .header(http::header::CONTENT_LENGTH, self.buffer.len());
+            if !self.buffer.is_empty() {
+                request = request.header(SEQ_HEADER, self.next_upload_seq());
+            }
+            let mut request = request.body(self.buffer.to_vec()).unwrap();
             let response = self.write_request(&mut request)?;
 
             match Some(response.status()) {
                 Some(StatusCode::OK) => {
-                    let wsize = response.body().len();
                     self.buffer.clear();
-                    Ok(response.body().clone())
+                    Ok(accept_download(&mut self.received, &response)?)
                 }
                 Some(_) | None => match std::str::from_utf8(response.body()) {
                     Ok(msg) => {
@@ -114,115 +207,475 @@ pub mod http_step {
         }
 
         fn flush(&mut self) -> std::io::Result<()> {
+            if self.mode != HttpMode::LongPoll || self.buffer.is_empty() {
+                return Ok(());
+            }
+
+            // uploads go chunked, as the bodies of clients streaming data of
+            // unknown length do
+            let mut request = self
+                .camouflage
+                .request(self.camouflage.upload_method.clone(), true)
+                .header(http::header::TRANSFER_ENCODING, "chunked")
+                .header(SEQ_HEADER, self.next_upload_seq())
+                .body(std::mem::take(&mut self.buffer))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let response = self.write_request(&mut request)?;
+            if response.status() != StatusCode::OK {
+                return Err(HttpStep::rejected(&response));
+            }
             Ok(())
         }
     }
 
     #[allow(unreachable_code)]
     impl HttpStep {
-        pub fn new(address: &str) -> Self {
-            //handle errors
-            HttpStep {
-                token: None,
-                address: String::from_str(address).unwrap(),
-                buffer: vec![0u8; 0],
+        pub fn new(address: &str) -> Result<Self, IOError> {
+            let options = SpecOptions::parse(address);
+            let socket_address = socket_address(address)?;
+            let poll_timeout = options
+                .get::<u64>("poll_timeout")?
+                .unwrap_or(DEFAULT_POLL_TIMEOUT);
+            if poll_timeout == 0 {
+                return Err(IOError::InvalidConfig(
+                    "poll_timeout must be greater than zero".to_string(),
+                ));
+            }
+            let request_timeout = options
+                .get::<u64>("request_timeout")?
+                .unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+            if request_timeout == 0 {
+                return Err(IOError::InvalidConfig(
+                    "request_timeout must be greater than zero".to_string(),
+                ));
             }
+            let retries = options.get::<u32>("retries")?.unwrap_or(DEFAULT_RETRIES);
+            let camouflage = Camouflage::from_options(&options, &socket_address)?;
+            Ok(HttpStep {
+                session: Arc::new(Session::default()),
+                address: address.to_string(),
+                connection: HttpConnection::new(
+                    &socket_address,
+                    Some(Duration::from_millis(request_timeout)),
+                    retries,
+                ),
+                socket_address,
+                buffer: vec![0u8; 0],
+                mode: HttpMode::from_options(&options)?,
+                camouflage,
+                poll_timeout: Duration::from_millis(poll_timeout),
+                retries,
+                upload_seq: 0,
+                received: 0,
+                downloads: None,
+            })
         }
 
-        fn make_connection(&self) -> io::Result<TcpStream> {
-            let uri: Uri = self.address.parse::<Uri>().unwrap();
-            let mut addr = String::from(uri.host().unwrap());
-            let port = uri.port().unwrap().as_u16();
+        fn next_upload_seq(&mut self) -> u64 {
+            self.upload_seq += 1;
+            self.upload_seq
+        }
+
+        fn send(&mut self, request: &Request<Vec<u8>>) -> io::Result<Response<Vec<u8>>> {
+            self.connection.send(request)
+        }
+
+        fn rejected(response: &Response<Vec<u8>>) -> io::Error {
+            let msg = String::from_utf8_lossy(response.body());
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("http entry answered {} {}", response.status(), msg),
+            )
+        }
+
+        fn start_long_poll(&mut self) -> Result<(), IOError> {
+            let session = self.session.clone();
+            let downloads = Arc::new(Downloads::default());
+            // the entry answers within poll_timeout, longer means it is gone
+            let read_timeout = self.poll_timeout + Duration::from_secs(10);
+            let mut connection =
+                HttpConnection::new(&self.socket_address, Some(read_timeout), self.retries);
+            let poll_timeout = self.poll_timeout;
+            let camouflage = self.camouflage.clone();
+            let shared = downloads.clone();
+            thread::Builder::new()
+                .name("http-long-poll".to_string())
+                .spawn(move || {
+                    if let Err(e) = HttpStep::long_poll(
+                        &mut connection,
+                        &camouflage,
+                        &session,
+                        poll_timeout,
+                        &shared,
+                    ) {
+                        shared.fail(e);
+                    }
+                    connection.close();
+                })?;
+            self.downloads = Some(downloads);
+            Ok(())
+        }
 
-            addr.push_str(":");
-            addr.push_str(port.to_string().as_str());
-            TcpStream::connect(addr)
+        /// Keeps a GET waiting at the entry and collects what it answers
+        /// until the step is closed. It polls in the session the step
+        /// uploads to, and opens a new one when the entry forgot it.
+        fn long_poll(
+            connection: &mut HttpConnection,
+            camouflage: &Camouflage,
+            session: &Session,
+            poll_timeout: Duration,
+            downloads: &Downloads,
+        ) -> io::Result<()> {
+            let mut polled = session.token();
+            let mut received = 0;
+            let mut handshakes = 0;
+            while !downloads.is_stopped() {
+                let token = session.token();
+                if token != polled {
+                    // a new session downloads from zero
+                    polled = token.clone();
+                    received = 0;
+                }
+                let token = token.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "http entry sent no token")
+                })?;
+                let mut request = camouflage
+                    .request(Method::GET, false)
+                    .header(POLL_TIMEOUT_HEADER, poll_timeout.as_millis().to_string())
+                    .header(ACK_HEADER, received)
+                    .body(vec![0u8; 0])
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                camouflage
+                    .token
+                    .attach(&mut request, &token)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                let response = connection.send(&request)?;
+                match response.status() {
+                    StatusCode::OK => handshakes = 0,
+                    StatusCode::FORBIDDEN if handshakes < MAX_HANDSHAKES => {
+                        handshakes += 1;
+                        session.reopen(Some(&token), || open_session(connection, camouflage))?;
+                        continue;
+                    }
+                    _ => return Err(HttpStep::rejected(&response)),
+                }
+                downloads.push(&accept_download(&mut received, &response)?);
+            }
+            Ok(())
         }
 
+        /// Sends `request` in the current session, opening one first when
+        /// there is none. When the entry forgot the session, a new one is
+        /// opened once and the request sent again.
         fn write_request(
             &mut self,
             request: &mut Request<Vec<u8>>,
         ) -> io::Result<Response<Vec<u8>>> {
-            let mut connection = self.make_connection()?;
-            match self.token.clone() {
-                Some(token) => {
+            let session = self.session.clone();
+            let mut handshakes = 0;
+            loop {
+                let token = match session.token() {
+                    Some(token) => token,
+                    None => {
+                        handshakes += 1;
+                        session.reopen(None, || self.handshake())?
+                    }
+                };
+                self.camouflage
+                    .token
+                    .attach(request, &token)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                // downloads acknowledge what arrived
+                if matches!(*request.method(), Method::GET | Method::HEAD) {
                     request
                         .headers_mut()
-                        .append(CLIENT_TOKEN_HEADER, HeaderValue::from_str(&token).unwrap());
-
-                    write_request(&mut connection, request)?;
-                    let response = read_response(&mut connection)?;
-                    match Some(response.status()) {
-                        Some(StatusCode::FORBIDDEN) => {
-                            match std::str::from_utf8(response.body()) {
-                                Ok(msg) => {
-                                    println!("{}", msg);
-                                    self.handshake(connection)?;
-                                    self.write_request(request);
-                                    // return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
-                                }
-                                Err(e) => {
-                                    return Err(io::Error::new(
-                                        io::ErrorKind::InvalidData,
-                                        "Invalid utf8",
-                                    ));
-                                }
-                            }
-                        }
-                        Some(StatusCode::OK) => {
-                            return Ok(response);
-                        }
-                        Some(_) | None => match std::str::from_utf8(response.body()) {
-                            Ok(msg) => {
-                                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
-                            }
-                            Err(e) => {
-                                return Err(io::Error::new(
-                                    io::ErrorKind::InvalidData,
-                                    "Invalid utf8",
-                                ));
-                            }
-                        },
+                        .insert(ACK_HEADER, HeaderValue::from(self.received));
+                }
+
+                let response = self.send(request)?;
+                match response.status() {
+                    StatusCode::OK => return Ok(response),
+                    StatusCode::FORBIDDEN if handshakes < MAX_HANDSHAKES => {
+                        println!("{}", String::from_utf8_lossy(response.body()));
+                        handshakes += 1;
+                        session.reopen(Some(&token), || self.handshake())?;
                     }
-                    Ok(response)
+                    _ => return Err(HttpStep::rejected(&response)),
                 }
-                None => {
-                    self.handshake(connection)?;
-                    self.write_request(request)
+            }
+        }
+
+        /// Opens a session for the step. A new session starts downloading
+        /// from zero, upload numbers only have to keep growing.
+        fn handshake(&mut self) -> io::Result<String> {
+            let token = open_session(&mut self.connection, &self.camouflage)?;
+            self.received = 0;
+            Ok(token)
+        }
+    }
+
+    /// Asks the entry for a new session and returns its token.
+    fn open_session(
+        connection: &mut HttpConnection,
+        camouflage: &Camouflage,
+    ) -> io::Result<String> {
+        let request = camouflage
+            .request(Method::GET, false)
+            .body(vec![0u8; 0])
+            .unwrap();
+
+        let response = connection.send(&request)?;
+        if response.status() != 200 {
+            match std::str::from_utf8(response.body()) {
+                Ok(msg) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                }
+                Err(e) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid utf8"));
                 }
             }
         }
 
-        fn handshake(&mut self, mut connection: TcpStream) -> io::Result<()> {
-            let request = Request::builder()
-                .method(Method::GET)
-                .version(Version::HTTP_11)
-                .body(vec![0u8; 0])
-                .unwrap();
+        camouflage
+            .token
+            .received(&response)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Token Not Found"))
+    }
 
-            write_request(&mut connection, &request)?;
-            let response = read_response(&mut connection)?;
-            if response.status() != 200 {
-                match std::str::from_utf8(response.body()) {
-                    Ok(msg) => {
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
-                    }
-                    Err(e) => {
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid utf8"));
+    /// The token of the session the step is in, shared with its long-poll
+    /// thread so both stay in the same session.
+    #[derive(Default)]
+    struct Session {
+        token: Mutex<Option<String>>,
+    }
+
+    impl Session {
+        fn token(&self) -> Option<String> {
+            self.token.lock().unwrap().clone()
+        }
+
+        /// Opens a new session with `open` in place of `stale`, unless the
+        /// other side already did, and returns the current token. The lock
+        /// is held meanwhile, so only one new session is opened.
+        fn reopen(
+            &self,
+            stale: Option<&str>,
+            open: impl FnOnce() -> io::Result<String>,
+        ) -> io::Result<String> {
+            let mut token = self.token.lock().unwrap();
+            match token.as_deref() {
+                Some(current) if Some(current) != stale => Ok(current.to_string()),
+                _ => {
+                    let opened = open()?;
+                    *token = Some(opened.clone());
+                    Ok(opened)
+                }
+            }
+        }
+    }
+
+    /// A connection to the entry, kept open between requests while the
+    /// server allows it.
+    struct HttpConnection {
+        socket_address: String,
+        read_timeout: Option<Duration>,
+        retries: u32,
+        connection: Option<TcpStream>,
+    }
+
+    impl HttpConnection {
+        fn new(socket_address: &str, read_timeout: Option<Duration>, retries: u32) -> Self {
+            HttpConnection {
+                socket_address: socket_address.to_string(),
+                read_timeout,
+                retries,
+                connection: None,
+            }
+        }
+
+        /// Same settings, not connected.
+        fn fresh(&self) -> Self {
+            HttpConnection::new(&self.socket_address, self.read_timeout, self.retries)
+        }
+
+        fn make_connection(&self) -> io::Result<TcpStream> {
+            let connection = TcpStream::connect(&self.socket_address)?;
+            connection.set_read_timeout(self.read_timeout)?;
+            Ok(connection)
+        }
+
+        /// Sends `request`, again on a new connection up to `retries` times
+        /// when the answer is lost. Only requests the entry tells apart from
+        /// a repeat are sent again : uploads carry sequence numbers and
+        /// downloads acknowledge what arrived, while a repeated handshake
+        /// would open a second session.
+        fn send(&mut self, request: &Request<Vec<u8>>) -> io::Result<Response<Vec<u8>>> {
+            let headers = request.headers();
+            let repeatable = headers.contains_key(SEQ_HEADER) || headers.contains_key(ACK_HEADER);
+            let retries = if repeatable { self.retries } else { 0 };
+            let mut attempt = 0;
+            loop {
+                match self.send_once(request) {
+                    Err(e) if attempt < retries && HttpConnection::is_retryable(&e) => {
+                        attempt += 1;
+                        println!(
+                            "http request failed, retrying {}/{} : {}",
+                            attempt, retries, e
+                        );
+                        thread::sleep(RETRY_DELAY * attempt);
                     }
+                    result => return result,
                 }
             }
+        }
 
-            match response.headers().get(CLIENT_TOKEN_HEADER) {
-                Some(token) => {
-                    self.token = Some(String::from_str(token.to_str().unwrap()).unwrap());
-                    Ok(())
+        /// Sends `request` over the kept connection, or a new one when there
+        /// is none. A kept connection the server already closed is replaced
+        /// once, as long as the request cannot have reached it.
+        fn send_once(&mut self, request: &Request<Vec<u8>>) -> io::Result<Response<Vec<u8>>> {
+            if let Some(mut connection) = self.connection.take() {
+                match HttpConnection::exchange(&mut connection, request) {
+                    Ok(response) => return Ok(self.keep(connection, response)),
+                    Err(e) if HttpConnection::is_stale(&e) => {}
+                    Err(e) => return Err(e),
                 }
-                None => Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Token Not Found",
+            }
+
+            let mut connection = self.make_connection()?;
+            let response = HttpConnection::exchange(&mut connection, request)?;
+            Ok(self.keep(connection, response))
+        }
+
+        fn exchange(
+            connection: &mut TcpStream,
+            request: &Request<Vec<u8>>,
+        ) -> io::Result<Response<Vec<u8>>> {
+            write_request(&mut *connection, request)?;
+            read_response(connection, request.method())
+        }
+
+        fn is_stale(e: &io::Error) -> bool {
+            is_closed_before_message(e)
+                || matches!(
+                    e.kind(),
+                    io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+                )
+        }
+
+        fn is_retryable(e: &io::Error) -> bool {
+            HttpConnection::is_stale(e)
+                || matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut
+                        | io::ErrorKind::WouldBlock
+                        | io::ErrorKind::UnexpectedEof
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::ConnectionRefused
+                )
+        }
+
+        fn keep(
+            &mut self,
+            connection: TcpStream,
+            response: Response<Vec<u8>>,
+        ) -> Response<Vec<u8>> {
+            if keep_alive(response.version(), response.headers()) {
+                self.connection = Some(connection);
+            }
+            response
+        }
+
+        fn close(&mut self) {
+            if let Some(connection) = self.connection.take() {
+                let _ = connection.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    /// The part of a download not received yet, going by the offset the
+    /// entry gives it, and counts it in `received`.
+    fn accept_download(received: &mut u64, response: &Response<Vec<u8>>) -> io::Result<Vec<u8>> {
+        let body = response.body();
+        let offset = match response.headers().get(OFFSET_HEADER) {
+            Some(offset) => offset
+                .to_str()
+                .ok()
+                .and_then(|offset| u64::from_str(offset).ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid Offset"))?,
+            // an entry that does not keep downloads
+            None => {
+                *received += body.len() as u64;
+                return Ok(body.clone());
+            }
+        };
+        if offset > *received {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "http download starts at {} but only {} bytes arrived",
+                    offset, received
+                ),
+            ));
+        }
+
+        let seen = ((*received - offset) as usize).min(body.len());
+        *received += (body.len() - seen) as u64;
+        Ok(body[seen..].to_vec())
+    }
+
+    /// Data the long-poll thread received and not yet read by the pipeline,
+    /// or why it stopped.
+    #[derive(Default)]
+    struct Downloads {
+        data: Mutex<Vec<u8>>,
+        error: Mutex<Option<String>>,
+        stopped: AtomicBool,
+    }
+
+    impl Downloads {
+        fn push(&self, data: &[u8]) {
+            self.data.lock().unwrap().extend_from_slice(data);
+        }
+
+        /// Pending data first, the error once it is all read.
+        fn len(&self) -> io::Result<usize> {
+            let len = self.data.lock().unwrap().len();
+            if len == 0 {
+                self.check()?;
+            }
+            Ok(len)
+        }
+
+        fn take(&self) -> io::Result<Vec<u8>> {
+            let data = std::mem::take(&mut *self.data.lock().unwrap());
+            if data.is_empty() {
+                self.check()?;
+            }
+            Ok(data)
+        }
+
+        fn check(&self) -> io::Result<()> {
+            match self.error.lock().unwrap().as_ref() {
+                Some(msg) => Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    msg.clone(),
                 )),
+                None => Ok(()),
             }
         }
+
+        fn fail(&self, error: io::Error) {
+            if !self.is_stopped() {
+                *self.error.lock().unwrap() = Some(format!("http long poll : {}", error));
+            }
+        }
+
+        fn stop(&self) {
+            self.stopped.store(true, Ordering::SeqCst);
+        }
+
+        fn is_stopped(&self) -> bool {
+            self.stopped.load(Ordering::SeqCst)
+        }
     }
 }
@@ -1,13 +1,66 @@
 pub mod http_tools {
-    use http::{request, response, version, Request, Response, Version};
-    use rand::seq;
+    use http::{
+        header::{HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING},
+        HeaderMap, Method, Request, Response, StatusCode, Version,
+    };
     use std::{
+        fmt,
         io::{self, Read, Result, Write},
-        iter::{self, Iterator},
         os::fd::AsRawFd,
-        str::{self, Utf8Error},
+        str,
+        time::Duration,
     };
-    use tungstenite::buffer;
+
+    /// Largest request or status line plus headers accepted.
+    pub const MAX_HEAD_SIZE: usize = 16 * 1024;
+    pub const MAX_HEADERS: usize = 100;
+    pub const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+    /// Longest chunk size line, extensions included.
+    pub const MAX_CHUNK_LINE: usize = 1024;
+    /// Largest chunk written, longer chunked bodies are cut.
+    pub const CHUNK_SIZE: usize = 16 * 1024;
+    /// How long a read waits for the peer on a non blocking socket.
+    pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+    const HEAD_END: &[u8] = b"\r\n\r\n";
+    const LINE_END: &[u8] = b"\r\n";
+
+    /// Payload of the error returned when the peer closes the connection
+    /// before sending anything, as it does with idle keep-alive connections.
+    #[derive(Debug)]
+    pub struct ClosedBeforeMessage;
+
+    impl fmt::Display for ClosedBeforeMessage {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "connection closed before any http data")
+        }
+    }
+
+    impl std::error::Error for ClosedBeforeMessage {}
+
+    pub fn is_closed_before_message(error: &io::Error) -> bool {
+        error
+            .get_ref()
+            .is_some_and(|error| error.is::<ClosedBeforeMessage>())
+    }
+
+    /// Whether the connection can carry another message after one with
+    /// `version` and `headers`.
+    pub fn keep_alive(version: Version, headers: &HeaderMap) -> bool {
+        let has_option = |option: &str| {
+            headers
+                .get_all(CONNECTION)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|value| value.trim().eq_ignore_ascii_case(option))
+        };
+        match version {
+            Version::HTTP_11 => !has_option("close"),
+            Version::HTTP_10 => has_option("keep-alive") && !has_option("close"),
+            _ => false,
+        }
+    }
 
     // #[test]
     // pub fn test_version() -> Result<()> {
@@ -25,15 +78,26 @@ pub mod http_tools {
         write!(buffer, "HTTP/1.1 {}\r\n", response.status(),)?;
 
         for (key, value) in response.headers() {
-            println!("{}{}", key, value.to_str().unwrap());
-            write!(buffer, "{}: {}\r\n", key, value.to_str().unwrap())?;
+            let value = value.to_str().map_err(invalid_data)?;
+            write!(buffer, "{}: {}\r\n", key, value)?;
+        }
+        // an empty body is framed too, or the client would wait for the
+        // connection to close
+        if has_body(response.status()) {
+            write_content_length(
+                &mut buffer,
+                response.headers(),
+                response.body().as_ref().len(),
+                true,
+            )?;
         }
 
         write!(buffer, "\r\n")?;
 
-        buffer.write(response.body().as_ref())?;
+        write_body(&mut buffer, response.headers(), response.body().as_ref())?;
 
-        stream.write(buffer.as_ref())
+        stream.write_all(buffer.as_ref())?;
+        Ok(buffer.len())
     }
 
     pub fn write_request<T: Write>(mut stream: T, request: &Request<Vec<u8>>) -> Result<usize> {
@@ -43,19 +107,114 @@ pub mod http_tools {
             "{} {} HTTP/1.1\r\n",
             request.method(),
             request.uri()
-        )
-        .unwrap();
+        )?;
         for (key, value) in request.headers() {
-            write!(buffer, "{}: {}\r\n", key, value.to_str().unwrap()).unwrap();
+            write!(
+                buffer,
+                "{}: {}\r\n",
+                key,
+                value.to_str().map_err(invalid_data)?
+            )?;
         }
-        write!(buffer, "\r\n").unwrap();
-        buffer.extend(request.body().into_iter());
+        write_content_length(&mut buffer, request.headers(), request.body().len(), false)?;
+        write!(buffer, "\r\n")?;
+        write_body(&mut buffer, request.headers(), request.body())?;
 
         // println!("{}", std::str::from_utf8(&buffer).unwrap());
 
-        let size = stream.write(buffer.as_slice())?;
-        stream.flush().unwrap();
-        Ok(size)
+        stream.write_all(buffer.as_slice())?;
+        stream.flush()?;
+        Ok(buffer.len())
+    }
+
+    /// Frames a body the caller did not give a length or a transfer encoding
+    /// for, so the reader on the other side knows where the message ends.
+    fn write_content_length(
+        buffer: &mut Vec<u8>,
+        headers: &HeaderMap,
+        length: usize,
+        when_empty: bool,
+    ) -> Result<()> {
+        if (length > 0 || when_empty)
+            && !headers.contains_key(CONTENT_LENGTH)
+            && !headers.contains_key(TRANSFER_ENCODING)
+        {
+            write!(buffer, "{}: {}\r\n", CONTENT_LENGTH, length)?;
+        }
+        Ok(())
+    }
+
+    /// Copies `body`, as chunks when the headers ask for chunked encoding.
+    /// Other transfer codings are not applied, so they are refused rather
+    /// than announced over a body that does not have them.
+    fn write_body(buffer: &mut Vec<u8>, headers: &HeaderMap, body: &[u8]) -> Result<()> {
+        if !headers.contains_key(TRANSFER_ENCODING) {
+            buffer.extend_from_slice(body);
+            return Ok(());
+        }
+        let mut codings = headers.get_all(TRANSFER_ENCODING).iter();
+        let chunked = codings.next().and_then(|value| value.to_str().ok());
+        if !chunked.is_some_and(|value| value.trim().eq_ignore_ascii_case("chunked"))
+            || codings.next().is_some()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only chunked transfer-encoding can be written",
+            ));
+        }
+        let mut writer = ChunkedWriter::new(buffer);
+        for chunk in body.chunks(CHUNK_SIZE) {
+            writer.write_all(chunk)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Writes a body as chunks, each write being one, for bodies whose
+    /// length is not known when they start. `finish` ends the body.
+    struct ChunkedWriter<W: Write> {
+        inner: W,
+    }
+
+    impl<W: Write> ChunkedWriter<W> {
+        fn new(inner: W) -> Self {
+            ChunkedWriter { inner }
+        }
+
+        /// Writes the last chunk, with no trailers.
+        fn finish(mut self) -> Result<W> {
+            self.inner.write_all(b"0\r\n\r\n")?;
+            Ok(self.inner)
+        }
+    }
+
+    impl<W: Write> Write for ChunkedWriter<W> {
+        fn write(&mut self, data: &[u8]) -> Result<usize> {
+            // an empty chunk would end the body
+            if data.is_empty() {
+                return Ok(0);
+            }
+            write!(self.inner, "{:x}\r\n", data.len())?;
+            self.inner.write_all(data)?;
+            self.inner.write_all(LINE_END)?;
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// True when chunked is the last transfer coding, which then frames the
+    /// body.
+    fn is_chunked(headers: &HeaderMap) -> bool {
+        headers
+            .get_all(TRANSFER_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
     }
 
     pub fn get_available_bytes<T: Read + AsRawFd>(stream: &mut T) -> Result<usize> {
@@ -70,185 +229,628 @@ pub mod http_tools {
         }
     }
 
-    fn parse_request_line(data: &[u8]) -> std::result::Result<(&str, &str, &str), Utf8Error> {
-        let mut parts = data.split(|&b| b == b' ');
+    fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
 
-        let method = str::from_utf8(parts.next().unwrap_or(&[]))?;
-        let path = str::from_utf8(parts.next().unwrap_or(&[]))?;
-        let version = str::from_utf8(parts.next().unwrap_or(&[]))?;
+    /// Reads a request head and its chunked or `Content-Length` body. Nothing
+    /// past the end of the request is consumed, so `stream` can be handed on
+    /// as is or read again for the next request.
+    pub fn read_request<T: Read + AsRawFd>(stream: &mut T) -> Result<Request<Vec<u8>>> {
+        let head = read_head(stream)?;
+        let mut lines = head_lines(&head);
+        let (method, target, version) = parse_request_line(lines.next().unwrap_or(&[]))?;
+
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(target)
+            .version(version);
+        let headers = builder.headers_mut().ok_or_else(|| {
+            invalid_data(format!("invalid http request line : {} {}", method, target))
+        })?;
+        parse_headers(lines, headers)?;
+
+        let body = match body_length(headers)? {
+            BodyLength::Chunked => read_chunked(stream)?,
+            BodyLength::Length(length) => read_body(stream, length)?,
+            BodyLength::UntilClose => Vec::new(),
+        };
+        builder.body(body).map_err(invalid_data)
+    }
 
-        Ok((method, path, version))
+    /// Reads a response head and its body, which is chunked, runs to
+    /// `Content-Length` or, without either, to the end of the connection.
+    /// Answers to `HEAD` never have a body.
+    pub fn read_response<T: Read + AsRawFd>(
+        stream: &mut T,
+        method: &Method,
+    ) -> Result<Response<Vec<u8>>> {
+        let head = parse_response_head(&read_head(stream)?)?;
+        let body = if method == Method::HEAD || !has_body(head.status()) {
+            Vec::new()
+        } else {
+            match body_length(head.headers())? {
+                BodyLength::Chunked => read_chunked(stream)?,
+                BodyLength::Length(length) => read_body(stream, length)?,
+                BodyLength::UntilClose => read_to_close(stream)?,
+            }
+        };
+        let (parts, ()) = head.into_parts();
+        Ok(Response::from_parts(parts, body))
     }
 
-    fn parse_reponse_line(data: &[u8]) -> std::result::Result<(&str, &str, &str), Utf8Error> {
-        let mut parts = data.split(|&b| b == b' ');
+    /// Reads a response head from a stream that cannot be peeked, such as a
+    /// TLS one, a byte at a time so that nothing after it is taken. The body
+    /// is left in the stream.
+    pub fn read_response_head<T: Read>(stream: &mut T) -> Result<Response<Vec<u8>>> {
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(HEAD_END) {
+            if head.len() == MAX_HEAD_SIZE {
+                return Err(invalid_data(format!(
+                    "http header is larger than {} bytes",
+                    MAX_HEAD_SIZE
+                )));
+            }
+            match stream.read(&mut byte) {
+                Ok(0) => return Err(closed_early(head.is_empty())),
+                Ok(_) => head.push(byte[0]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        head.truncate(head.len() - HEAD_END.len());
+        Ok(parse_response_head(&head)?.map(|()| Vec::new()))
+    }
+
+    fn parse_response_head(head: &[u8]) -> Result<Response<()>> {
+        let mut lines = head_lines(head);
+        let (version, status) = parse_status_line(lines.next().unwrap_or(&[]))?;
 
-        let version = str::from_utf8(parts.next().unwrap_or(&[]))?;
-        let code = str::from_utf8(parts.next().unwrap_or(&[]))?;
-        let code_msg = str::from_utf8(parts.next().unwrap_or(&[]))?;
+        let mut builder = Response::builder().status(status).version(version);
+        let headers = builder
+            .headers_mut()
+            .ok_or_else(|| invalid_data(format!("invalid http status : {}", status)))?;
+        parse_headers(lines, headers)?;
+        builder.body(()).map_err(invalid_data)
+    }
 
-        Ok((version, code, code_msg))
+    /// Reads up to and including the empty line ending a message head.
+    fn read_head<T: Read + AsRawFd>(stream: &mut T) -> Result<Vec<u8>> {
+        read_until(stream, HEAD_END, MAX_HEAD_SIZE, "http header", true)
     }
 
-    fn parse_header(data: &[u8]) -> std::result::Result<(&str, &str), Utf8Error> {
-        let mut parts = data.split(|&b| b == b':');
+    /// Reads up to and including `end`, which is left out of the result.
+    fn read_until<T: Read + AsRawFd>(
+        stream: &mut T,
+        end: &[u8],
+        limit: usize,
+        what: &str,
+        message_start: bool,
+    ) -> Result<Vec<u8>> {
+        let mut head = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let room = limit - head.len();
+            if room == 0 {
+                return Err(invalid_data(format!(
+                    "{} is larger than {} bytes",
+                    what, limit
+                )));
+            }
 
-        let key = str::from_utf8(&parts.next().unwrap_or(&[]))?;
-        let value = str::from_utf8(&parts.next().unwrap_or(&[])[1..])?;
+            // look at what arrived before taking it, so bytes that belong to
+            // whatever follows the head stay in the socket
+            let peeked = peek(stream, &mut chunk[..room.min(4096)])?;
+            if peeked == 0 {
+                return Err(closed_early(message_start && head.is_empty()));
+            }
 
-        Ok((key, value))
+            let old_len = head.len();
+            head.extend_from_slice(&chunk[..peeked]);
+            let search_from = old_len.saturating_sub(end.len() - 1);
+            let consumed = match find(&head[search_from..], end) {
+                Some(position) => search_from + position + end.len() - old_len,
+                None => peeked,
+            };
+            head.truncate(old_len);
+            head.resize(old_len + consumed, 0);
+            read_full(stream, &mut head[old_len..])?;
+
+            if head.ends_with(end) {
+                head.truncate(head.len() - end.len());
+                return Ok(head);
+            }
+        }
     }
 
-    pub fn read_request<T: Read + AsRawFd>(stream: &mut T) -> std::io::Result<Request<Vec<u8>>> {
-        let mut size = 0;
-        while size ==0 {
-            size = get_available_bytes(stream)?;
+    fn head_lines(head: &[u8]) -> impl Iterator<Item = &[u8]> {
+        head.split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+    }
+
+    fn parse_request_line(line: &[u8]) -> Result<(&str, &str, Version)> {
+        let line = str::from_utf8(line).map_err(invalid_data)?;
+        let mut parts = line.splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version))
+                if !method.is_empty() && !target.is_empty() =>
+            {
+                Ok((method, target, parse_version(version)?))
+            }
+            _ => Err(invalid_data(format!(
+                "invalid http request line : {}",
+                line
+            ))),
         }
-        // let size = get_available_bytes(stream)?;
-        let mut buffer = vec![0u8; size];
+    }
 
-        let size = stream.read(&mut buffer)?;
+    fn parse_status_line(line: &[u8]) -> Result<(Version, StatusCode)> {
+        let line = str::from_utf8(line).map_err(invalid_data)?;
+        // the reason phrase may be empty or contain spaces, it is not used
+        let mut parts = line.splitn(3, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(version), Some(code)) => {
+                let status = StatusCode::from_bytes(code.as_bytes())
+                    .map_err(|_| invalid_data(format!("invalid http status line : {}", line)))?;
+                Ok((parse_version(version)?, status))
+            }
+            _ => Err(invalid_data(format!("invalid http status line : {}", line))),
+        }
+    }
 
-        let mut sequence = vec![(0usize, 0usize); 0];
-        let buffer_iter = buffer.iter();
-        let mut separator_buf = vec![0u8; 0];
-        let mut has_body = (false, 0usize, 0usize);
+    fn parse_version(version: &str) -> Result<Version> {
+        match version {
+            "HTTP/1.0" => Ok(Version::HTTP_10),
+            "HTTP/1.1" => Ok(Version::HTTP_11),
+            version => Err(invalid_data(format!(
+                "unsupported http version : {}",
+                version
+            ))),
+        }
+    }
 
-        for (index, &value) in buffer_iter.enumerate() {
-            match value {
-                b'\n' => {
-                    separator_buf.push(value);
-                    if separator_buf.len() > 3 {
-                        if index + 1 != size {
-                            has_body = (true, index + 1, size);
-                        }
-                        break;
-                    }
-                }
-                b'\r' => {
-                    if separator_buf.len() == 0 {
-                        if sequence.len() == 0 {
-                            sequence.push((0, index));
-                        } else {
-                            sequence.push((sequence.last().unwrap().1 + 2, index));
-                        }
-                    }
-                    separator_buf.push(value);
-                }
-                _ => {
-                    separator_buf.clear();
-                }
+    fn parse_headers<'a>(
+        lines: impl Iterator<Item = &'a [u8]>,
+        headers: &mut HeaderMap,
+    ) -> Result<()> {
+        for (count, line) in lines.enumerate() {
+            if count == MAX_HEADERS {
+                return Err(invalid_data(format!(
+                    "more than {} http headers",
+                    MAX_HEADERS
+                )));
             }
+            let (name, value) = parse_header(line)?;
+            headers.append(name, value);
         }
+        Ok(())
+    }
 
-        let mut builder = request::Request::builder();
+    /// Splits `name: value` at the first colon, so values such as
+    /// `Host: example.com:80` are kept whole.
+    fn parse_header(line: &[u8]) -> Result<(HeaderName, HeaderValue)> {
+        let invalid_header = || {
+            invalid_data(format!(
+                "invalid http header : {}",
+                String::from_utf8_lossy(line)
+            ))
+        };
+        let colon = line
+            .iter()
+            .position(|&b| b == b':')
+            .ok_or_else(invalid_header)?;
+        let name = HeaderName::from_bytes(&line[..colon]).map_err(|_| invalid_header())?;
+        let value = line[colon + 1..].trim_ascii();
+        let value = HeaderValue::from_bytes(value).map_err(|_| invalid_header())?;
+        Ok((name, value))
+    }
 
-        let request_line = parse_request_line(&buffer[sequence[0].0..sequence[0].1]).unwrap();
-        builder = builder
-            .method(request_line.0)
-            .uri(request_line.1)
-            .version(|| -> Version {
-                match Some(request_line.2) {
-                    Some("HTTP/0.9") => Version::HTTP_09,
-                    Some("HTTP/1.0") => Version::HTTP_09,
-                    Some("HTTP/1.1") => Version::HTTP_09,
-                    Some("HTTP/2.0") => Version::HTTP_09,
-                    Some("HTTP/3.0") => Version::HTTP_09,
-                    Some(_) | None => {
-                        panic!("Unknown Http Version")
-                    }
-                }
-            }());
+    /// Whether a response with `status` can carry a body at all.
+    fn has_body(status: StatusCode) -> bool {
+        !(status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED)
+    }
 
-        for (chunk_index, index) in sequence[1..].iter().enumerate() {
-            let header = parse_header(&buffer[index.0..index.1]).unwrap();
-            builder = builder.header(header.0, header.1);
+    enum BodyLength {
+        Chunked,
+        Length(usize),
+        /// No framing : a request has no body, a response runs to the end of
+        /// the connection.
+        UntilClose,
+    }
+
+    /// How the body is framed. A transfer encoding overrides `Content-Length`
+    /// and only chunked is understood.
+    fn body_length(headers: &HeaderMap) -> Result<BodyLength> {
+        if headers.contains_key(TRANSFER_ENCODING) {
+            return if is_chunked(headers) {
+                Ok(BodyLength::Chunked)
+            } else {
+                Err(invalid_data("unsupported http transfer-encoding"))
+            };
         }
+        Ok(match content_length(headers)? {
+            Some(length) => BodyLength::Length(length),
+            None => BodyLength::UntilClose,
+        })
+    }
 
-        if has_body.0 {
-            let body = buffer[has_body.1..has_body.2].to_vec();
-            let request = builder.body(body).unwrap();
-            Ok(request)
-        } else {
-            let request = builder.body(vec![0; 0]).unwrap();
-            Ok(request)
+    /// The `Content-Length`, which must come once : a repeated one, even with
+    /// the same value, is how a body gets read differently by two parsers.
+    fn content_length(headers: &HeaderMap) -> Result<Option<usize>> {
+        let mut values = headers.get_all(CONTENT_LENGTH).iter();
+        let length = match values.next() {
+            Some(value) => value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse::<usize>().ok())
+                .ok_or_else(|| invalid_data("invalid http content-length"))?,
+            None => return Ok(None),
+        };
+        if values.next().is_some() {
+            return Err(invalid_data("repeated http content-length"));
+        }
+        if length > MAX_BODY_SIZE {
+            return Err(invalid_data(format!(
+                "http body is larger than {} bytes",
+                MAX_BODY_SIZE
+            )));
         }
+        Ok(Some(length))
+    }
+
+    fn read_body<T: Read + AsRawFd>(stream: &mut T, length: usize) -> Result<Vec<u8>> {
+        let mut body = vec![0u8; length];
+        read_full(stream, &mut body)?;
+        Ok(body)
     }
 
-    pub fn read_response<T: Read + AsRawFd>(stream: &mut T) -> std::io::Result<Response<Vec<u8>>> {
-        let mut size = 0;
-        while size ==0 {
-            size = get_available_bytes(stream)?;
+    fn read_chunked<T: Read + AsRawFd>(stream: &mut T) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let line = read_until(stream, LINE_END, MAX_CHUNK_LINE, "http chunk size", false)?;
+            let line = str::from_utf8(&line).map_err(invalid_data)?;
+            // extensions after ';' are allowed and ignored
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| invalid_data(format!("invalid http chunk size : {}", line)))?;
+
+            if size == 0 {
+                return read_trailers(stream).map(|_| body);
+            }
+            if body.len() + size > MAX_BODY_SIZE {
+                return Err(invalid_data(format!(
+                    "http body is larger than {} bytes",
+                    MAX_BODY_SIZE
+                )));
+            }
+
+            let start = body.len();
+            body.resize(start + size, 0);
+            read_full(stream, &mut body[start..])?;
+            let mut end = [0u8; 2];
+            read_full(stream, &mut end)?;
+            if end != LINE_END {
+                return Err(invalid_data("http chunk is not followed by CRLF"));
+            }
         }
-        // let size = get_available_bytes(stream)?;
+    }
 
-        let mut buffer = vec![0u8; size];
+    /// Skips the trailer fields after the last chunk, up to the empty line.
+    fn read_trailers<T: Read + AsRawFd>(stream: &mut T) -> Result<()> {
+        for _ in 0..=MAX_HEADERS {
+            let line = read_until(stream, LINE_END, MAX_HEAD_SIZE, "http trailer", false)?;
+            if line.is_empty() {
+                return Ok(());
+            }
+        }
+        Err(invalid_data(format!(
+            "more than {} http trailers",
+            MAX_HEADERS
+        )))
+    }
 
-        let size = stream.read(&mut buffer)?;
+    fn read_to_close<T: Read + AsRawFd>(stream: &mut T) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let size = read_some(stream, &mut chunk)?;
+            if size == 0 {
+                return Ok(body);
+            }
+            if body.len() + size > MAX_BODY_SIZE {
+                return Err(invalid_data(format!(
+                    "http body is larger than {} bytes",
+                    MAX_BODY_SIZE
+                )));
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    }
 
-        let mut sequence = vec![(0usize, 0usize); 0];
-        let buffer_iter = buffer.iter();
-        let mut separator_buf = vec![0u8; 0];
-        let mut has_body = (false, 0usize, 0usize);
+    fn read_full<T: Read + AsRawFd>(stream: &mut T, mut buffer: &mut [u8]) -> Result<()> {
+        while !buffer.is_empty() {
+            let size = read_some(stream, buffer)?;
+            if size == 0 {
+                return Err(closed_early(false));
+            }
+            buffer = &mut buffer[size..];
+        }
+        Ok(())
+    }
 
-        for (index, &value) in buffer_iter.enumerate() {
-            match value {
-                b'\n' => {
-                    separator_buf.push(value);
-                    if separator_buf.len() > 3 {
-                        if index + 1 != size {
-                            has_body = (true, index + 1, size);
-                        }
-                        break;
-                    }
-                }
-                b'\r' => {
-                    if separator_buf.len() == 0 {
-                        if sequence.len() == 0 {
-                            sequence.push((0, index));
-                        } else {
-                            sequence.push((sequence.last().unwrap().1 + 2, index));
-                        }
+    /// `read` that also waits on non blocking sockets.
+    fn read_some<T: Read + AsRawFd>(stream: &mut T, buffer: &mut [u8]) -> Result<usize> {
+        loop {
+            match stream.read(buffer) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => wait_readable(stream)?,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                result => return result,
+            }
+        }
+    }
+
+    fn peek<T: AsRawFd>(stream: &T, buffer: &mut [u8]) -> Result<usize> {
+        loop {
+            let result = unsafe {
+                libc::recv(
+                    stream.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                    libc::MSG_PEEK,
+                )
+            };
+            if result >= 0 {
+                return Ok(result as usize);
+            }
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::WouldBlock => wait_readable(stream)?,
+                io::ErrorKind::Interrupted => {}
+                _ => return Err(e),
+            }
+        }
+    }
+
+    /// Waits for data on a non blocking socket. On a blocking one the
+    /// would-block came from an expired read timeout, which is final.
+    fn wait_readable<T: AsRawFd>(stream: &T) -> Result<()> {
+        let flags = unsafe { libc::fcntl(stream.as_raw_fd(), libc::F_GETFL) };
+        if flags != -1 && flags & libc::O_NONBLOCK == 0 {
+            return Err(timed_out());
+        }
+        let mut fd = libc::pollfd {
+            fd: stream.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            let result = unsafe { libc::poll(&mut fd, 1, READ_TIMEOUT.as_millis() as libc::c_int) };
+            match result {
+                0 => return Err(timed_out()),
+                -1 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
                     }
-                    separator_buf.push(value);
-                }
-                _ => {
-                    separator_buf.clear();
                 }
+                _ => return Ok(()),
             }
         }
+    }
+
+    fn timed_out() -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for http data")
+    }
+
+    fn closed_early(nothing_read: bool) -> io::Error {
+        if nothing_read {
+            io::Error::new(io::ErrorKind::UnexpectedEof, ClosedBeforeMessage)
+        } else {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed in the middle of an http message",
+            )
+        }
+    }
 
-        let mut builder = response::Response::builder();
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    }
 
-        let response_line = parse_reponse_line(&buffer[sequence[0].0..sequence[0].1]).unwrap();
-        builder = builder.status(response_line.1).version(|| -> Version {
-            match Some(response_line.0) {
-                Some("HTTP/0.9") => Version::HTTP_09,
-                Some("HTTP/1.0") => Version::HTTP_10,
-                Some("HTTP/1.1") => Version::HTTP_11,
-                Some("HTTP/2.0") => Version::HTTP_2,
-                Some("HTTP/3.0") => Version::HTTP_3,
-                Some(_) | None => {
-                    panic!("Unknown Http Version")
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::{os::unix::net::UnixStream, thread};
+
+        /// The reading end of a socket the parts are written to one by one,
+        /// closed after the last.
+        fn stream(parts: Vec<Vec<u8>>) -> UnixStream {
+            let (reader, mut writer) = UnixStream::pair().unwrap();
+            thread::spawn(move || {
+                for part in parts {
+                    // the reader may give up on the message before its end
+                    if writer.write_all(&part).is_err() {
+                        return;
+                    }
+                    thread::sleep(Duration::from_millis(20));
                 }
-            }
-        }());
+            });
+            reader
+        }
+
+        fn request(data: &str) -> Result<Request<Vec<u8>>> {
+            read_request(&mut stream(vec![data.as_bytes().to_vec()]))
+        }
 
-        builder = builder.header("Sec-WebSocket-Accept", "tQiJ4LxcB6HBu3o3dm2i5qiU9js=");
+        #[test]
+        fn head_split_across_reads() {
+            let mut stream = stream(vec![
+                b"GET /path HTTP/1.1\r\nHo".to_vec(),
+                b"st: example.com:80\r".to_vec(),
+                b"\n\r".to_vec(),
+                b"\nGET /next HTTP/1.0\r\n\r\n".to_vec(),
+            ]);
+            let first = read_request(&mut stream).unwrap();
+            assert_eq!(first.method(), Method::GET);
+            assert_eq!(first.uri(), "/path");
+            assert_eq!(first.version(), Version::HTTP_11);
+            assert_eq!(first.headers()["host"], "example.com:80");
+
+            // nothing of the next request was taken with the first
+            let second = read_request(&mut stream).unwrap();
+            assert_eq!(second.uri(), "/next");
+            assert_eq!(second.version(), Version::HTTP_10);
+
+            let error = read_request(&mut stream).unwrap_err();
+            assert!(is_closed_before_message(&error));
+        }
 
-        for (chunk_index, index) in sequence[1..].iter().enumerate() {
-            let (header, header_val) = parse_header(&buffer[index.0..index.1]).unwrap();
-            println!("{}, {}", header, header_val);
-            builder = builder.header(header, header_val);
+        #[test]
+        fn versions() {
+            assert!(request("GET / HTTP/2.0\r\n\r\n").is_err());
+            assert!(request("GET / HTTP/0.9\r\n\r\n").is_err());
+            assert!(request("GET /\r\n\r\n").is_err());
+
+            let response = read_response(
+                &mut stream(vec![b"HTTP/1.0 404 Not Found Here\r\n\r\nbody".to_vec()]),
+                &Method::GET,
+            )
+            .unwrap();
+            assert_eq!(response.version(), Version::HTTP_10);
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            // no framing, the body runs to the end of the connection
+            assert_eq!(response.body(), b"body");
         }
 
-        if has_body.0 {
-            let body = buffer[has_body.1..has_body.2].to_vec();
-            let request = builder.body(body).unwrap();
-            Ok(request)
-        } else {
-            let request = builder.body(vec![0; 0]).unwrap();
-            Ok(request)
+        #[test]
+        fn content_length() {
+            let request = request("POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcdef").unwrap();
+            assert_eq!(request.body(), b"abc");
+
+            assert!(self::request(
+                "POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc"
+            )
+            .is_err());
+            assert!(self::request(
+                "POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd"
+            )
+            .is_err());
+            assert!(self::request("POST / HTTP/1.1\r\nContent-Length: 3, 3\r\n\r\nabc").is_err());
+            assert!(self::request("POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n").is_err());
+            assert!(self::request(&format!(
+                "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+                MAX_BODY_SIZE + 1
+            ))
+            .is_err());
+        }
+
+        #[test]
+        fn head_limits() {
+            let long = format!(
+                "GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n",
+                "a".repeat(MAX_HEAD_SIZE)
+            );
+            let error = request(&long).unwrap_err();
+            assert!(error.to_string().contains("larger than"), "{}", error);
+
+            let headers: String = (0..=MAX_HEADERS)
+                .map(|i| format!("X-{}: {}\r\n", i, i))
+                .collect();
+            let error = request(&format!("GET / HTTP/1.1\r\n{}\r\n", headers)).unwrap_err();
+            assert!(error.to_string().contains("headers"), "{}", error);
+
+            assert!(request("GET / HTTP/1.1\r\nno colon\r\n\r\n").is_err());
+        }
+
+        #[test]
+        fn chunked_body() {
+            let mut stream = stream(vec![
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n3;name=value\r\nabc"
+                    .to_vec(),
+                b"\r\n5\r\nde".to_vec(),
+                b"fgh\r\n0\r\nX-Trailer: y\r\n\r\nGET /next HTTP/1.1\r\n\r\n".to_vec(),
+            ]);
+            let request = read_request(&mut stream).unwrap();
+            assert_eq!(request.body(), b"abcdefgh");
+            assert_eq!(read_request(&mut stream).unwrap().uri(), "/next");
+
+            assert!(
+                self::request("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n")
+                    .is_err()
+            );
+            assert!(self::request(&format!(
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+                MAX_BODY_SIZE + 1
+            ))
+            .is_err());
+            assert!(self::request("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n").is_err());
+        }
+
+        #[test]
+        fn chunked_writes_round_trip() {
+            let body: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+            let request = Request::post("/up")
+                .header(TRANSFER_ENCODING, "chunked")
+                .body(body.clone())
+                .unwrap();
+            let mut written = Vec::new();
+            write_request(&mut written, &request).unwrap();
+            let text = String::from_utf8_lossy(&written);
+            assert!(!text.contains("content-length"), "{}", text);
+            assert!(text.contains("\r\n\r\n4000\r\n"), "{}", text);
+            assert!(text.contains("\r\na\r\n"), "{}", text);
+            assert!(written.ends_with(b"\r\n0\r\n\r\n"));
+
+            let read = read_request(&mut stream(vec![written])).unwrap();
+            assert_eq!(read.body(), &body);
+
+            let response = Response::builder()
+                .header(TRANSFER_ENCODING, "Chunked")
+                .body(b"")
+                .unwrap();
+            let mut written = Vec::new();
+            write_response(&mut written, response).unwrap();
+            assert!(written.ends_with(b"\r\n\r\n0\r\n\r\n"));
+            let response = read_response(&mut stream(vec![written]), &Method::GET).unwrap();
+            assert!(response.body().is_empty());
+        }
+
+        #[test]
+        fn unknown_transfer_encoding_is_not_written() {
+            for coding in ["gzip", "gzip, chunked"] {
+                let request = Request::post("/")
+                    .header(TRANSFER_ENCODING, coding)
+                    .body(b"data".to_vec())
+                    .unwrap();
+                let error = write_request(Vec::new(), &request).unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            }
+        }
+
+        #[test]
+        fn empty_write_does_not_end_the_body() {
+            let mut writer = ChunkedWriter::new(Vec::new());
+            writer.write_all(b"ab").unwrap();
+            assert_eq!(writer.write(b"").unwrap(), 0);
+            writer.write_all(b"c").unwrap();
+            assert_eq!(writer.finish().unwrap(), b"2\r\nab\r\n1\r\nc\r\n0\r\n\r\n");
+        }
+
+        #[test]
+        fn head_response_has_no_body() {
+            let mut stream = stream(vec![
+                b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nHTTP/1.1 204 \r\n\r\n".to_vec(),
+            ]);
+            let response = read_response(&mut stream, &Method::HEAD).unwrap();
+            assert_eq!(response.headers()[CONTENT_LENGTH], "5");
+            assert!(response.body().is_empty());
+            let response = read_response(&mut stream, &Method::GET).unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
         }
     }
 }
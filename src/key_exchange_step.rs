@@ -0,0 +1,743 @@
+pub mod key_exchange_step {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use openssl::{
+        derive::Deriver,
+        error::ErrorStack,
+        hash::MessageDigest,
+        md::Md,
+        memcmp,
+        pkey::{Id, PKey},
+        pkey_ctx::PkeyCtx,
+        sign::Signer,
+        symm::{decrypt_aead, encrypt_aead, Cipher},
+    };
+    use std::io::{self, Write};
+
+    use crate::{
+        pipeline_module::pipeline::{PipelineDirection, PipelineStep},
+        BoxedClone, IOError, Read, SpecOptions,
+    };
+
+    const VERSION: u8 = 1;
+    /// The hello carries a tag made with the pre-shared key.
+    const FLAG_PSK: u8 = 0x01;
+    /// The initiator mixes the responder's pinned static key in.
+    const FLAG_PINNED: u8 = 0x02;
+    const KEY_LEN: usize = 32;
+    const TAG_LEN: usize = 16;
+    const MAC_LEN: usize = 32;
+    /// Version and ephemeral key of the responder, then its confirmation.
+    const REPLY_LEN: usize = 1 + KEY_LEN + MAC_LEN;
+    const LABEL: &[u8] = b"proxy kx 1";
+
+    /// Largest plaintext sealed in one record.
+    pub const MAX_RECORD: usize = 16 * 1024;
+    /// Most plaintext held while the handshake is going on.
+    pub const MAX_HELD: usize = 1024 * 1024;
+
+    /// Keys of one connection, one per direction and the one the responder
+    /// proves it derived the same ones with.
+    struct SessionKeys {
+        initiator: [u8; KEY_LEN],
+        responder: [u8; KEY_LEN],
+        confirm: [u8; KEY_LEN],
+    }
+
+    /// Seals what one end sends into `length | ciphertext | tag` records,
+    /// numbered by a counter used as nonce.
+    struct Sealer {
+        key: [u8; KEY_LEN],
+        counter: u64,
+    }
+
+    impl Sealer {
+        fn seal(&mut self, data: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+            for chunk in data.chunks(MAX_RECORD) {
+                let header = ((chunk.len() + TAG_LEN) as u32).to_be_bytes();
+                let mut tag = [0u8; TAG_LEN];
+                let sealed = encrypt_aead(
+                    Cipher::chacha20_poly1305(),
+                    &self.key,
+                    Some(&nonce(self.counter)),
+                    &header,
+                    chunk,
+                    &mut tag,
+                )
+                .map_err(crypto_error)?;
+                self.counter += 1;
+                output.extend_from_slice(&header);
+                output.extend_from_slice(&sealed);
+                output.extend_from_slice(&tag);
+            }
+            Ok(())
+        }
+    }
+
+    /// Opens the records of the other end, whatever way they were cut into
+    /// writes.
+    struct Opener {
+        key: [u8; KEY_LEN],
+        counter: u64,
+        pending: Vec<u8>,
+    }
+
+    impl Opener {
+        fn open(&mut self, data: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+            self.pending.extend_from_slice(data);
+            let mut offset = 0;
+            while let Some(header) = self.pending.get(offset..offset + 4) {
+                let header: [u8; 4] = header.try_into().unwrap();
+                let length = u32::from_be_bytes(header) as usize;
+                if !(TAG_LEN..=MAX_RECORD + TAG_LEN).contains(&length) {
+                    return Err(invalid_data(format!("record of {} bytes", length)));
+                }
+                let record = match self.pending.get(offset + 4..offset + 4 + length) {
+                    Some(record) => record,
+                    None => break,
+                };
+                let (sealed, tag) = record.split_at(length - TAG_LEN);
+                let plain = decrypt_aead(
+                    Cipher::chacha20_poly1305(),
+                    &self.key,
+                    Some(&nonce(self.counter)),
+                    &header,
+                    sealed,
+                    tag,
+                )
+                .map_err(|_| invalid_data("record failed authentication".to_string()))?;
+                self.counter += 1;
+                output.extend(plain);
+                offset += 4 + length;
+            }
+            self.pending.drain(..offset);
+            Ok(())
+        }
+    }
+
+    /// `kx:fw` and `kx:bw` agree on fresh keys with each other over X25519 on
+    /// every connection and encrypt what goes between them with
+    /// ChaCha20-Poly1305, so that a leaked long-term secret does not open
+    /// recorded traffic. `kx:fw` starts the handshake toward the `kx:bw` of
+    /// the far end. They authenticate with `?psk=<base64>` on both sides, or
+    /// with `?key=<base64 private key>` on `kx:bw` whose public key, printed
+    /// on start, is pinned by `kx:fw` with `?peer=<base64>`, or both.
+    pub struct KeyExchangeStep {
+        psk: Option<Vec<u8>>,
+        /// Static private key of the responder.
+        key: Option<[u8; KEY_LEN]>,
+        /// Pinned public key of the responder.
+        peer: Option<[u8; KEY_LEN]>,
+        ephemeral: Option<[u8; KEY_LEN]>,
+        /// Handshake message of the other end, as far as it came.
+        handshake: Vec<u8>,
+        /// Plaintext written before the keys were there.
+        held: Vec<u8>,
+        sealer: Option<Sealer>,
+        opener: Option<Opener>,
+        forward_buffer: Vec<u8>,
+        backward_buffer: Vec<u8>,
+        work_mode: PipelineDirection,
+        pipeline_direction: PipelineDirection,
+    }
+
+    impl PipelineStep for KeyExchangeStep {
+        fn len(&mut self) -> std::io::Result<usize> {
+            match self.pipeline_direction {
+                PipelineDirection::Forward => Ok(self.forward_buffer.len()),
+                PipelineDirection::Backward => Ok(self.backward_buffer.len()),
+            }
+        }
+
+        fn set_pipeline_direction(&mut self, direction: PipelineDirection) {
+            self.pipeline_direction = direction;
+        }
+
+        /// The initiator sends its hello right away, the responder waits for
+        /// it.
+        fn start(&mut self) -> Result<(), IOError> {
+            if self.is_initiator() {
+                let hello = self.hello()?;
+                self.buffer(self.work_mode).extend(hello);
+            }
+            Ok(())
+        }
+
+        fn has_pending(&self, direction: PipelineDirection) -> bool {
+            match direction {
+                PipelineDirection::Forward => !self.forward_buffer.is_empty(),
+                PipelineDirection::Backward => !self.backward_buffer.is_empty(),
+            }
+        }
+
+        /// The far end sees a new connection, so the initiator agrees on new
+        /// keys, keeping what it held for the old ones. A responder's
+        /// reconnects are toward its destination.
+        fn on_reconnect(&mut self) -> Result<(), IOError> {
+            if !self.is_initiator() {
+                return Ok(());
+            }
+            let mut fresh = self.fresh();
+            fresh.held = std::mem::take(&mut self.held);
+            fresh.pipeline_direction = self.pipeline_direction;
+            *self = fresh;
+            self.start()
+        }
+
+        /// Records sealed by the initiator only open with the keys of its
+        /// connection.
+        fn is_connection_bound(&self) -> bool {
+            self.is_initiator()
+        }
+    }
+
+    impl BoxedClone for KeyExchangeStep {
+        fn bclone(&self) -> Box<dyn PipelineStep> {
+            Box::new(self.fresh())
+        }
+    }
+
+    impl Read for KeyExchangeStep {
+        fn read(&mut self) -> Result<Vec<u8>, IOError> {
+            match self.pipeline_direction {
+                PipelineDirection::Forward => Ok(std::mem::take(&mut self.forward_buffer)),
+                PipelineDirection::Backward => Ok(std::mem::take(&mut self.backward_buffer)),
+            }
+        }
+    }
+
+    impl Write for KeyExchangeStep {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let result = if self.work_mode == self.pipeline_direction {
+                self.send(buf)
+            } else {
+                self.receive(buf)
+            };
+            result.map_err(|e| invalid_data(format!("kx : {}", e)))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl KeyExchangeStep {
+        pub fn new(spec: &str) -> Result<KeyExchangeStep, IOError> {
+            let config = spec.strip_prefix("kx:").unwrap_or("");
+            let work_mode = match config.split('?').next() {
+                Some("fw") => PipelineDirection::Forward,
+                Some("bw") => PipelineDirection::Backward,
+                mode => {
+                    return Err(IOError::InvalidStep(format!(
+                        "kx : unknown work mode : {}",
+                        mode.unwrap_or("")
+                    )))
+                }
+            };
+
+            let options = SpecOptions::parse(spec);
+            let psk = options
+                .get_str("psk")
+                .map(|psk| decode("psk", psk))
+                .transpose()?;
+            let key = options
+                .get_str("key")
+                .map(|key| decode_key("key", key))
+                .transpose()?;
+            let peer = options
+                .get_str("peer")
+                .map(|peer| decode_key("peer", peer))
+                .transpose()?;
+            if psk.as_ref().is_some_and(|psk| psk.len() < 16) {
+                return Err(IOError::InvalidConfig(
+                    "kx : psk must be at least 16 bytes".to_string(),
+                ));
+            }
+
+            let invalid = |msg: &str| Err(IOError::InvalidConfig(format!("kx : {}", msg)));
+            match work_mode {
+                PipelineDirection::Forward if key.is_some() => {
+                    return invalid("key is for kx:bw, kx:fw pins it with peer")
+                }
+                PipelineDirection::Forward if psk.is_none() && peer.is_none() => {
+                    return invalid("kx:fw needs psk or peer")
+                }
+                PipelineDirection::Backward if peer.is_some() => {
+                    return invalid("peer is for kx:fw, kx:bw uses key")
+                }
+                PipelineDirection::Backward if psk.is_none() && key.is_none() => {
+                    return invalid("kx:bw needs psk or key")
+                }
+                _ => {}
+            }
+
+            if let Some(key) = key.as_ref() {
+                let public = public_key(key).map_err(|e| IOError::InvalidConfig(e.to_string()))?;
+                println!("kx : public key : {}", STANDARD.encode(public));
+            }
+
+            Ok(KeyExchangeStep::with(psk, key, peer, work_mode))
+        }
+
+        fn with(
+            psk: Option<Vec<u8>>,
+            key: Option<[u8; KEY_LEN]>,
+            peer: Option<[u8; KEY_LEN]>,
+            work_mode: PipelineDirection,
+        ) -> KeyExchangeStep {
+            KeyExchangeStep {
+                psk,
+                key,
+                peer,
+                ephemeral: None,
+                handshake: Vec::new(),
+                held: Vec::new(),
+                sealer: None,
+                opener: None,
+                forward_buffer: Vec::new(),
+                backward_buffer: Vec::new(),
+                work_mode,
+                pipeline_direction: PipelineDirection::Forward,
+            }
+        }
+
+        /// Same configuration, no connection state.
+        fn fresh(&self) -> KeyExchangeStep {
+            KeyExchangeStep::with(self.psk.clone(), self.key, self.peer, self.work_mode)
+        }
+
+        fn is_initiator(&self) -> bool {
+            self.work_mode == PipelineDirection::Forward
+        }
+
+        fn flags(&self) -> u8 {
+            let mut flags = 0;
+            if self.psk.is_some() {
+                flags |= FLAG_PSK;
+            }
+            if self.key.is_some() || self.peer.is_some() {
+                flags |= FLAG_PINNED;
+            }
+            flags
+        }
+
+        fn buffer(&mut self, direction: PipelineDirection) -> &mut Vec<u8> {
+            match direction {
+                PipelineDirection::Forward => &mut self.forward_buffer,
+                PipelineDirection::Backward => &mut self.backward_buffer,
+            }
+        }
+
+        /// `version | flags | ephemeral key`, then a tag over them made with
+        /// the psk, so that the responder only answers who knows it.
+        fn hello(&mut self) -> io::Result<Vec<u8>> {
+            let ephemeral = generate_key()?;
+            let mut hello = vec![VERSION, self.flags()];
+            hello.extend_from_slice(&public_key(&ephemeral)?);
+            if let Some(psk) = self.psk.as_ref() {
+                let tag = hmac(psk, &[b"hello".as_slice(), &hello])?;
+                hello.extend_from_slice(&tag);
+            }
+            self.ephemeral = Some(ephemeral);
+            Ok(hello)
+        }
+
+        /// Seals what goes to the other end, or holds it until the keys are
+        /// there.
+        fn send(&mut self, data: &[u8]) -> io::Result<()> {
+            let output = match self.pipeline_direction {
+                PipelineDirection::Forward => &mut self.forward_buffer,
+                PipelineDirection::Backward => &mut self.backward_buffer,
+            };
+            match self.sealer.as_mut() {
+                Some(sealer) => sealer.seal(data, output),
+                None if self.held.len() + data.len() > MAX_HELD => Err(invalid_data(format!(
+                    "more than {} bytes written before the handshake ended",
+                    MAX_HELD
+                ))),
+                None => {
+                    self.held.extend_from_slice(data);
+                    Ok(())
+                }
+            }
+        }
+
+        /// Opens what comes from the other end, once its part of the
+        /// handshake is through.
+        fn receive(&mut self, data: &[u8]) -> io::Result<()> {
+            let data = if self.opener.is_none() {
+                self.handshake.extend_from_slice(data);
+                let length = match self.handshake_length() {
+                    Some(length) if self.handshake.len() >= length => length,
+                    _ => return Ok(()),
+                };
+                let message: Vec<u8> = self.handshake.drain(..length).collect();
+                if self.is_initiator() {
+                    self.accept_reply(&message)?;
+                } else {
+                    self.accept_hello(&message)?;
+                }
+                std::mem::take(&mut self.handshake)
+            } else {
+                data.to_vec()
+            };
+
+            let output = match self.pipeline_direction {
+                PipelineDirection::Forward => &mut self.forward_buffer,
+                PipelineDirection::Backward => &mut self.backward_buffer,
+            };
+            self.opener.as_mut().unwrap().open(&data, output)
+        }
+
+        /// Length of the handshake message expected from the other end,
+        /// `None` while it cannot be told yet.
+        fn handshake_length(&self) -> Option<usize> {
+            if self.is_initiator() {
+                return Some(REPLY_LEN);
+            }
+            let flags = *self.handshake.get(1)?;
+            let tag = if flags & FLAG_PSK != 0 { MAC_LEN } else { 0 };
+            Some(2 + KEY_LEN + tag)
+        }
+
+        /// Checks the initiator's hello, answers with `version | ephemeral key
+        /// | confirmation` and seals what was held meanwhile.
+        fn accept_hello(&mut self, hello: &[u8]) -> io::Result<()> {
+            if hello[0] != VERSION {
+                return Err(invalid_data(format!("unknown version {}", hello[0])));
+            }
+            if hello[1] != self.flags() {
+                return Err(invalid_data(format!(
+                    "peer authenticates with {}, expected {}",
+                    describe(hello[1]),
+                    describe(self.flags())
+                )));
+            }
+            let initiator: [u8; KEY_LEN] = hello[2..2 + KEY_LEN].try_into().unwrap();
+            if let Some(psk) = self.psk.as_ref() {
+                let tag = hmac(psk, &[b"hello".as_slice(), &hello[..2 + KEY_LEN]])?;
+                if !memcmp::eq(&tag, &hello[2 + KEY_LEN..]) {
+                    return Err(invalid_data("hello with a wrong psk".to_string()));
+                }
+            }
+
+            let ephemeral = generate_key()?;
+            let responder = public_key(&ephemeral)?;
+            let mut secret = diffie_hellman(&ephemeral, &initiator)?;
+            if let Some(key) = self.key.as_ref() {
+                secret.extend(diffie_hellman(key, &initiator)?);
+            }
+            let static_key = self.key.as_ref().map(public_key).transpose()?;
+            let (keys, transcript) = self.derive(&secret, &initiator, &responder, static_key)?;
+
+            let mut reply = vec![VERSION];
+            reply.extend_from_slice(&responder);
+            reply.extend(hmac(&keys.confirm, &[&transcript])?);
+            self.establish(keys.responder, keys.initiator, reply)
+        }
+
+        /// Checks the responder proved it derived the same keys, and seals
+        /// what was held meanwhile.
+        fn accept_reply(&mut self, reply: &[u8]) -> io::Result<()> {
+            if reply[0] != VERSION {
+                return Err(invalid_data(format!("unknown version {}", reply[0])));
+            }
+            let responder: [u8; KEY_LEN] = reply[1..1 + KEY_LEN].try_into().unwrap();
+            let ephemeral = self
+                .ephemeral
+                .take()
+                .ok_or_else(|| invalid_data("reply before hello".to_string()))?;
+            let initiator = public_key(&ephemeral)?;
+            let mut secret = diffie_hellman(&ephemeral, &responder)?;
+            if let Some(peer) = self.peer.as_ref() {
+                secret.extend(diffie_hellman(&ephemeral, peer)?);
+            }
+            let (keys, transcript) = self.derive(&secret, &initiator, &responder, self.peer)?;
+
+            let confirm = hmac(&keys.confirm, &[&transcript])?;
+            if !memcmp::eq(&confirm, &reply[1 + KEY_LEN..]) {
+                return Err(invalid_data(
+                    "peer failed to authenticate, wrong psk or key".to_string(),
+                ));
+            }
+            self.establish(keys.initiator, keys.responder, Vec::new())
+        }
+
+        /// HKDF-SHA256 of the shared secrets, salted with the psk, bound to
+        /// every public key of the handshake.
+        fn derive(
+            &self,
+            secret: &[u8],
+            initiator: &[u8; KEY_LEN],
+            responder: &[u8; KEY_LEN],
+            static_key: Option<[u8; KEY_LEN]>,
+        ) -> io::Result<(SessionKeys, Vec<u8>)> {
+            let mut transcript = LABEL.to_vec();
+            transcript.push(self.flags());
+            transcript.extend_from_slice(initiator);
+            transcript.extend_from_slice(responder);
+            if let Some(static_key) = static_key {
+                transcript.extend_from_slice(&static_key);
+            }
+
+            let mut okm = [0u8; 3 * KEY_LEN];
+            let mut ctx = PkeyCtx::new_id(Id::HKDF).map_err(crypto_error)?;
+            ctx.derive_init().map_err(crypto_error)?;
+            ctx.set_hkdf_md(Md::sha256()).map_err(crypto_error)?;
+            ctx.set_hkdf_key(secret).map_err(crypto_error)?;
+            if let Some(psk) = self.psk.as_ref() {
+                ctx.set_hkdf_salt(psk).map_err(crypto_error)?;
+            }
+            ctx.add_hkdf_info(&transcript).map_err(crypto_error)?;
+            ctx.derive(Some(&mut okm)).map_err(crypto_error)?;
+
+            let keys = SessionKeys {
+                initiator: okm[..KEY_LEN].try_into().unwrap(),
+                responder: okm[KEY_LEN..2 * KEY_LEN].try_into().unwrap(),
+                confirm: okm[2 * KEY_LEN..].try_into().unwrap(),
+            };
+            Ok((keys, transcript))
+        }
+
+        /// Starts sealing with `seal` and opening with `open`, after sending
+        /// `reply`.
+        fn establish(
+            &mut self,
+            seal: [u8; KEY_LEN],
+            open: [u8; KEY_LEN],
+            reply: Vec<u8>,
+        ) -> io::Result<()> {
+            let mut sealer = Sealer {
+                key: seal,
+                counter: 0,
+            };
+            let mut output = reply;
+            sealer.seal(&std::mem::take(&mut self.held), &mut output)?;
+            self.buffer(self.work_mode).extend(output);
+            self.sealer = Some(sealer);
+            self.opener = Some(Opener {
+                key: open,
+                counter: 0,
+                pending: Vec::new(),
+            });
+            Ok(())
+        }
+    }
+
+    fn generate_key() -> io::Result<[u8; KEY_LEN]> {
+        let key = PKey::generate_x25519().map_err(crypto_error)?;
+        raw_key(key.raw_private_key().map_err(crypto_error)?)
+    }
+
+    fn public_key(private: &[u8; KEY_LEN]) -> io::Result<[u8; KEY_LEN]> {
+        let key = PKey::private_key_from_raw_bytes(private, Id::X25519).map_err(crypto_error)?;
+        raw_key(key.raw_public_key().map_err(crypto_error)?)
+    }
+
+    fn diffie_hellman(private: &[u8; KEY_LEN], public: &[u8; KEY_LEN]) -> io::Result<Vec<u8>> {
+        let private =
+            PKey::private_key_from_raw_bytes(private, Id::X25519).map_err(crypto_error)?;
+        let public = PKey::public_key_from_raw_bytes(public, Id::X25519).map_err(crypto_error)?;
+        let mut deriver = Deriver::new(&private).map_err(crypto_error)?;
+        deriver.set_peer(&public).map_err(crypto_error)?;
+        let secret = deriver
+            .derive_to_vec()
+            .map_err(|_| invalid_data("invalid public key".to_string()))?;
+        // a low order point would make the secret known to anyone
+        if secret.iter().all(|byte| *byte == 0) {
+            return Err(invalid_data("invalid public key".to_string()));
+        }
+        Ok(secret)
+    }
+
+    fn hmac(key: &[u8], parts: &[&[u8]]) -> io::Result<Vec<u8>> {
+        let key = PKey::hmac(key).map_err(crypto_error)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(crypto_error)?;
+        for part in parts {
+            signer.update(part).map_err(crypto_error)?;
+        }
+        signer.sign_to_vec().map_err(crypto_error)
+    }
+
+    fn nonce(counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    fn raw_key(bytes: Vec<u8>) -> io::Result<[u8; KEY_LEN]> {
+        bytes
+            .try_into()
+            .map_err(|_| invalid_data("unexpected key length".to_string()))
+    }
+
+    fn decode(name: &str, value: &str) -> Result<Vec<u8>, IOError> {
+        STANDARD
+            .decode(value)
+            .map_err(|e| IOError::InvalidConfig(format!("kx : invalid base64 {} : {}", name, e)))
+    }
+
+    fn decode_key(name: &str, value: &str) -> Result<[u8; KEY_LEN], IOError> {
+        decode(name, value)?
+            .try_into()
+            .map_err(|_| IOError::InvalidConfig(format!("kx : {} must be {} bytes", name, KEY_LEN)))
+    }
+
+    fn describe(flags: u8) -> &'static str {
+        match flags {
+            FLAG_PSK => "psk",
+            FLAG_PINNED => "pinned key",
+            f if f == FLAG_PSK | FLAG_PINNED => "psk and pinned key",
+            _ => "nothing",
+        }
+    }
+
+    fn crypto_error(e: ErrorStack) -> io::Error {
+        io::Error::other(e)
+    }
+
+    fn invalid_data(msg: String) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const PSK: &[u8] = b"0123456789abcdef";
+
+        fn step(
+            psk: Option<&[u8]>,
+            key: Option<[u8; KEY_LEN]>,
+            peer: Option<[u8; KEY_LEN]>,
+            work_mode: PipelineDirection,
+        ) -> KeyExchangeStep {
+            KeyExchangeStep::with(psk.map(|psk| psk.to_vec()), key, peer, work_mode)
+        }
+
+        /// Moves what `from` has to send in `direction` over to `to`.
+        fn pass(
+            from: &mut KeyExchangeStep,
+            to: &mut KeyExchangeStep,
+            direction: PipelineDirection,
+        ) -> io::Result<()> {
+            from.set_pipeline_direction(direction);
+            let data = from.read().unwrap();
+            to.set_pipeline_direction(direction);
+            to.write(&data).map(|_| ())
+        }
+
+        /// Writes `data` into `fw` before the handshake and returns what
+        /// `bw` lets through once it is done.
+        fn exchange(
+            fw: &mut KeyExchangeStep,
+            bw: &mut KeyExchangeStep,
+            data: &[u8],
+        ) -> io::Result<Vec<u8>> {
+            fw.start().unwrap();
+            fw.set_pipeline_direction(PipelineDirection::Forward);
+            fw.write(data)?;
+            pass(fw, bw, PipelineDirection::Forward)?;
+            pass(bw, fw, PipelineDirection::Backward)?;
+            pass(fw, bw, PipelineDirection::Forward)?;
+            Ok(bw.read().unwrap())
+        }
+
+        #[test]
+        fn round_trip_with_psk() {
+            let mut fw = step(Some(PSK), None, None, PipelineDirection::Forward);
+            let mut bw = step(Some(PSK), None, None, PipelineDirection::Backward);
+            assert_eq!(exchange(&mut fw, &mut bw, b"request").unwrap(), b"request");
+
+            bw.set_pipeline_direction(PipelineDirection::Backward);
+            bw.write(b"response").unwrap();
+            pass(&mut bw, &mut fw, PipelineDirection::Backward).unwrap();
+            assert_eq!(fw.read().unwrap(), b"response");
+        }
+
+        #[test]
+        fn round_trip_with_pinned_key() {
+            let key = generate_key().unwrap();
+            let peer = public_key(&key).unwrap();
+            let mut fw = step(None, None, Some(peer), PipelineDirection::Forward);
+            let mut bw = step(None, Some(key), None, PipelineDirection::Backward);
+            assert_eq!(exchange(&mut fw, &mut bw, b"request").unwrap(), b"request");
+        }
+
+        #[test]
+        fn records_split_across_writes() {
+            let mut fw = step(Some(PSK), None, None, PipelineDirection::Forward);
+            let mut bw = step(Some(PSK), None, None, PipelineDirection::Backward);
+            exchange(&mut fw, &mut bw, b"").unwrap();
+
+            fw.write(b"split record").unwrap();
+            let sealed = fw.read().unwrap();
+            let mut opened = Vec::new();
+            for byte in sealed.chunks(1) {
+                bw.write(byte).unwrap();
+                opened.extend(bw.read().unwrap());
+            }
+            assert_eq!(opened, b"split record");
+        }
+
+        #[test]
+        fn wrong_psk_is_rejected() {
+            let mut fw = step(Some(PSK), None, None, PipelineDirection::Forward);
+            let mut bw = step(
+                Some(b"fedcba9876543210"),
+                None,
+                None,
+                PipelineDirection::Backward,
+            );
+            let error = exchange(&mut fw, &mut bw, b"request").unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().contains("wrong psk"));
+        }
+
+        #[test]
+        fn wrong_pinned_key_is_rejected() {
+            let pinned = public_key(&generate_key().unwrap()).unwrap();
+            let key = generate_key().unwrap();
+            let mut fw = step(None, None, Some(pinned), PipelineDirection::Forward);
+            let mut bw = step(None, Some(key), None, PipelineDirection::Backward);
+            let error = exchange(&mut fw, &mut bw, b"request").unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().contains("failed to authenticate"));
+        }
+
+        #[test]
+        fn tampered_record_is_rejected() {
+            let mut fw = step(Some(PSK), None, None, PipelineDirection::Forward);
+            let mut bw = step(Some(PSK), None, None, PipelineDirection::Backward);
+            exchange(&mut fw, &mut bw, b"").unwrap();
+
+            fw.write(b"record").unwrap();
+            let mut sealed = fw.read().unwrap();
+            sealed[5] ^= 1;
+            assert!(bw.write(&sealed).is_err());
+        }
+
+        #[test]
+        fn reconnect_agrees_on_new_keys_and_keeps_held_data() {
+            let mut fw = step(Some(PSK), None, None, PipelineDirection::Forward);
+            fw.start().unwrap();
+            fw.write(b"held").unwrap();
+            let first_hello = fw.read().unwrap();
+
+            fw.on_reconnect().unwrap();
+            assert!(fw.has_pending(PipelineDirection::Forward));
+            let mut bw = step(Some(PSK), None, None, PipelineDirection::Backward);
+            pass(&mut fw, &mut bw, PipelineDirection::Forward).unwrap();
+            pass(&mut bw, &mut fw, PipelineDirection::Backward).unwrap();
+            pass(&mut fw, &mut bw, PipelineDirection::Forward).unwrap();
+            assert_eq!(bw.read().unwrap(), b"held");
+
+            // the responder of the lost connection cannot take over
+            let mut stale = step(Some(PSK), None, None, PipelineDirection::Backward);
+            stale.write(&first_hello).unwrap();
+            stale.set_pipeline_direction(PipelineDirection::Backward);
+            fw.set_pipeline_direction(PipelineDirection::Backward);
+            assert!(fw.write(&stale.read().unwrap()).is_err());
+        }
+    }
+}
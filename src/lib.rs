@@ -1,10 +1,54 @@
 mod http_tools;
 pub use http_tools::http_tools::{
-    get_available_bytes, read_request, read_response, write_request, write_response,
+    get_available_bytes, is_closed_before_message, keep_alive, read_request, read_response,
+    read_response_head, write_request, write_response, ClosedBeforeMessage,
+};
+
+mod websocket_handshake;
+pub use websocket_handshake::websocket_handshake::{
+    accept_key, client_handshake, generate_key, tls_client_handshake,
+};
+
+mod spec_tools;
+pub use spec_tools::spec_tools::{
+    percent_decode, percent_encode, redact, socket_address, split_upstreams, SpecOptions,
+};
+
+mod http_camouflage;
+pub use http_camouflage::http_camouflage::{Camouflage, TokenPlacement};
+
+mod reconnect;
+pub use reconnect::reconnect::{peer_closed, Reconnect, ReconnectPolicy};
+
+mod websocket_deflate;
+pub use websocket_deflate::websocket_deflate::{
+    DeflatePolicy, DeflateParams, Deflater, Inflater, PERMESSAGE_DEFLATE,
+};
+
+mod websocket_frames;
+pub use websocket_frames::websocket_frames::{FrameReader, FrameWriter, MAX_FRAME_BUFFER};
+
+mod rate_limit;
+pub use rate_limit::rate_limit::{ClientRate, RateLimiter, RatePolicy, TokenBucket, Transfer};
+
+mod access_list;
+pub use access_list::access_list::{AccessList, Cidr};
+
+mod connection_limit;
+pub use connection_limit::connection_limit::{
+    ConnectionLimiter, ConnectionPermit, LimitPolicy, Rejection,
+};
+
+mod websocket_keepalive;
+pub use websocket_keepalive::websocket_keepalive::{Keepalive, KeepalivePolicy};
+
+mod upstream;
+pub use upstream::upstream::{
+    connect_timeout, BalancePolicy, HealthCheck, ProbeKind, UpstreamGuard, Upstreams,
 };
 
 mod entry_module;
-pub use entry_module::entry_module::Entry;
+pub use entry_module::entry_module::{shutdown_listener, ConnectionGuard, Entry, EntryControl};
 
 mod websocket_entry;
 pub use websocket_entry::websocket_entry::WebsocketEntry;
@@ -36,6 +80,18 @@ pub use tcp_step::tcp_step::TCPStep;
 mod random_salt_step;
 pub use random_salt_step::random_salt_step::RSult;
 
+mod compression_step;
+pub use compression_step::compression_step::{Algorithm, CompressionStep};
+
+mod key_exchange_step;
+pub use key_exchange_step::key_exchange_step::{KeyExchangeStep, MAX_HELD, MAX_RECORD};
+
+mod hmac_step;
+pub use hmac_step::hmac_step::{HmacStep, MAX_CHUNK, REPLAY_WINDOW};
+
+mod shape_step;
+pub use shape_step::shape_step::{ShapePolicy, ShapeStep, DEFAULT_SIZES};
+
 mod tcp_entry_nonblocking;
 pub use tcp_entry_nonblocking::tcp_entry_nonblocking::TcpEntryNonBlocking;
 
@@ -46,4 +102,13 @@ mod http_entry_nonblocking;
 pub use http_entry_nonblocking::http_entry_nonblocking::HttpEntryNonblocking;
 
 mod http_step;
-pub use http_step::http_step::HttpStep;
+pub use http_step::http_step::{HttpMode, HttpStep};
+
+mod config;
+pub use config::config::{
+    Addresses, Config, DeflateConfig, EntryConfig, HealthConfig, KeepaliveConfig, ReconnectConfig,
+    StepConfig, TunnelConfig, DEFAULT_GRACE_PERIOD,
+};
+
+mod tunnel;
+pub use tunnel::tunnel::{create_pipeline, create_step, shutdown, BoundTunnel, Tunnel, TunnelSet};
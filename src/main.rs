@@ -1,12 +1,11 @@
-use openssl::conf;
-use proxy::{
-    Base64, Entry, HttpEntryNonblocking, Pipeline, PipelineStep, RSult, STDioEntry, STDioStep,
-    TCPEntry, TCPStep, TcpEntryNonBlocking, WSEntryNonBlocking, WebsocketDestination,
-    WebsocketEntry, WssDestination, HttpStep
-};
+use proxy::{shutdown, Config, Tunnel, TunnelSet, DEFAULT_GRACE_PERIOD};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
 use std::{
-    str::FromStr,
-    sync::{Arc, Mutex},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     thread,
     time::Duration,
 };
@@ -19,13 +18,31 @@ Options:
   -e entry
   -s define step 
   -t loop_time(default is 10ms)          
+  -g grace period in seconds for open connections on SIGTERM/SIGINT (default is 30)
+  -c, --config path to a toml file describing one or more named tunnels,
+               reloaded on SIGHUP
   -h, --help     Print help
 
 Entries:
   ws://address
   stdio:
   tcp://address
-  http://address-salt
+  http://address-salt-timeout
+  sessions get random tokens signed with the salt, valid for
+  ?token_lifetime=seconds (default is 86400)
+  which clients send in the header, cookie or query parameter given by
+  ?token=header:name|cookie:name|query:name (default is header:client_token)
+  ws, tcp and http entries cap their bandwidth in bytes per second with
+  ?upload_rate=n&download_rate=n for all clients together and
+  ?client_upload_rate=n&client_download_rate=n for each one (0 is unlimited)
+  and their connections with ?max_connections=n&max_connections_per_ip=n and
+  ?accept_rate=n new ones per second (0 is unlimited). connections over a
+  limit are closed, with a 503 on ws and http entries. http entries count
+  sessions, whatever connections they come over, and answer a 503 to the
+  handshake of one too many; their TCP connections still count against
+  accept_rate and are capped at 4 per allowed session (1024 when uncapped)
+  ?allow=cidr,...&deny=cidr,... restricts who may connect, e.g.
+  allow=10.0.0.0/8,::1 (deny wins, and once anything is allowed the rest is not)
 
 Steps:
   stdio:
@@ -33,7 +50,82 @@ Steps:
   b64:fw b64:bw
   tcp://address
   salt:fw-len salf:bw-len
+  zstd:fw zstd:bw deflate:fw deflate:bw lz4:fw lz4:bw
+  kx:fw kx:bw
+  hmac:fw-key hmac:bw-key
+  shape:fw shape:bw
   http://address
+
+  tcp:// and ws:// steps take several comma separated addresses, balanced with
+  ?policy=round-robin|least-conn|failover (default is round-robin)
+  and reconnect when given
+  ?reconnect=max_attempts(0 is unlimited)&backoff=ms&max_backoff=ms&max_pending=bytes
+  &connect_timeout=ms (default 5000, for every connection attempt), replaying
+  what was written meanwhile unless a kx:fw, hmac:fw, zstd:fw or deflate:fw
+  comes before, whose output only the far end of the lost connection can read
+  and probe their upstreams when given (ws probes are ws:// only)
+  ?health=tcp|ws&health_interval=ms&health_timeout=ms&health_rise=n&health_fall=n
+
+  zstd, deflate and lz4 steps compress in their work direction and decompress
+  in the other, flushing every write so interactive sessions do not wait,
+  zstd and deflate with ?level=n (defaults are 3 and 6). A write that
+  decompresses to more than 16 MiB drops the connection
+
+  kx:fw agrees on fresh X25519 keys with the kx:bw of the far end on every
+  connection, then both encrypt what goes between them with ChaCha20-Poly1305.
+  They authenticate with the same ?psk=base64 (at least 16 bytes), or kx:bw
+  holds ?key=base64 (32 bytes, e.g. openssl rand -base64 32) and kx:fw pins
+  the public key kx:bw prints on start with ?peer=base64, or both
+
+  hmac steps append a counter and an HMAC-SHA256 tag to what they send, and
+  drop the connection on a forged chunk or on a counter seen already or more
+  than 64 behind. Both ends send a random nonce first and sign with a key
+  derived from the two, so chunks of another connection do not verify.
+  The key is %-decoded, so hmac:fw-a%26b signs with a&b
+
+  shape steps cut what they send into chunks padded to sizes picked from
+  ?sizes=n|min-max,... (default is 128-1400, 6 to 65540), send dummy chunks
+  after ?dummy=ms without traffic and delay writes by up to ?jitter=ms,
+  the peer shape step dropping padding and dummies
+
+  ws:// and wss:// steps and the ws:// entry ping their peer and drop the
+  connection when no pong comes back, once given either of
+  ?ping_interval=ms(0 turns pings off)&pong_timeout=ms (defaults are 30000 and 10000)
+
+  ws:// and wss:// steps and the ws:// entry compress messages with
+  permessage-deflate when both ends agree, otherwise they go uncompressed, with
+  ?deflate=true&server_no_context_takeover=true&client_no_context_takeover=true
+  (the last two are off by default)
+
+  http:// steps poll with HEAD by default, or upload with chunked POSTs and download
+  with a GET held by the entry using
+  ?mode=longpoll&poll_timeout=ms (default is 20000)
+  and retry a request whose answer is lost, without losing or repeating data,
+  ?request_timeout=ms&retries=n (defaults are 30000 and 3)
+  and dress their requests, with option values %-encoded where needed,
+  ?paths=/a,/b&method=post|put&host=name&user_agent=ua&content_type=type
+  &token=header:name|cookie:name|query:name (must match the entry)
+
+Config file:
+  grace_period = 30
+
+  [[tunnel]]
+  name = \"ssh\"
+  buffer_size = 1024
+  loop_time = 10
+  entry = { type = \"tcp\", address = \"0.0.0.0:2222\" }
+
+  [[tunnel.step]]
+  type = \"salt\"
+  mode = \"fw\"
+  length = 8
+
+  [[tunnel.step]]
+  type = \"tcp\"
+  address = [\"relay-a.example.com:22\", \"relay-b.example.com:22\"]
+  policy = \"failover\"
+  reconnect = { max_attempts = 10, backoff = 100, max_backoff = 30000 }
+  health = { probe = \"tcp\", interval = 5000, timeout = 2000, rise = 2, fall = 3 }
 ";
 
 fn main() {
@@ -44,74 +136,109 @@ fn main() {
         std::process::exit(0);
     }
 
-    let mut steps: Vec<Box<dyn PipelineStep>> = Vec::new();
+    let terminate = register_shutdown_signals();
+
+    let config_path = pargs
+        .opt_value_from_str::<[&str; 2], String>(["-c", "--config"])
+        .unwrap_or_else(|e| fail(e));
+    if let Some(config_path) = config_path {
+        run_config(&config_path, terminate);
+        return;
+    }
+
+    let mut steps: Vec<String> = Vec::new();
     loop {
-        let step = pargs.opt_value_from_str::<&str, String>("-s").unwrap();
+        let step = pargs
+            .opt_value_from_str::<&str, String>("-s")
+            .unwrap_or_else(|e| fail(e));
         if step == None {
             break;
         }
-        let step = step.unwrap();
-        println!("step : {step}");
-
-        let res: Vec<String> = step.split(":").map(|s| s.to_string()).collect();
-        let protocol = Some(res.get(0).unwrap().as_str());
-        let config = Some(res.get(1).unwrap().as_str());
-        match protocol {
-            Some("stdio") => {
-                steps.push(Box::new(STDioStep::new()));
-            }
-            Some("ws") => steps.push(Box::new(WebsocketDestination::new(step.as_str()))),
-            Some("wss") => steps.push(Box::new(WssDestination::new(step.as_str()))),
-            Some("b64") => steps.push(Box::new(Base64::new(config))),
-            Some("tcp") => steps.push(Box::new(TCPStep::new(step.as_str()))),
-            Some("salt") => steps.push(Box::new(RSult::new(config))),
-            Some("http") => steps.push(Box::new(HttpStep::new(step.as_str()))),
-            None | _ => {
-                print!("unknown step : {}", step);
-            }
-        }
+        steps.push(step.unwrap());
     }
-    let pipeline = Pipeline::new(steps, Some(1024));
 
     let loop_time: u64 = pargs
         .opt_value_from_str::<&str, u64>("-t")
-        .unwrap()
+        .unwrap_or_else(|e| fail(e))
         .unwrap_or(10);
 
-    let entry = pargs.opt_value_from_str::<&str, String>("-e").unwrap();
-    if entry == None {
-        panic!("no entry defined");
+    let grace_period = pargs
+        .opt_value_from_str::<&str, u64>("-g")
+        .unwrap_or_else(|e| fail(e))
+        .unwrap_or(DEFAULT_GRACE_PERIOD);
+
+    let entry = pargs
+        .opt_value_from_str::<&str, String>("-e")
+        .unwrap_or_else(|e| fail(e))
+        .unwrap_or_else(|| fail("no entry defined, see --help"));
+
+    let tunnel = Tunnel::spawn("default", entry, &steps, Some(1024), loop_time)
+        .unwrap_or_else(|e| fail(e));
+    while tunnel.is_listening() && !terminate.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(100));
     }
-    let entry = entry.unwrap();
-    println!("entry : {entry}");
-
-    let res: Vec<String> = entry.split(":").map(|s| s.to_string()).collect();
-    let protocol = Some(res.get(0).unwrap().as_str());
-    let config = Some(res.get(1).unwrap().as_str());
-    match protocol {
-        Some("ws") => {
-            let mut entry = WSEntryNonBlocking::new(entry, pipeline, loop_time);
-            entry.listen();
-        }
-        Some("stdio") => {
-            let mut entry = STDioEntry::new(String::new(), pipeline, loop_time);
-            entry.listen();
-        }
-        Some("tcp") => {
-            let mut entry = TcpEntryNonBlocking::new(entry, pipeline, loop_time);
-            entry.listen();
-        }
-        Some("http") => {
-            let mut entry = HttpEntryNonblocking::new(entry, pipeline, loop_time);
-            entry.listen();
-        }
-        None | _ => {
-            panic!("unknown entry : {}", entry);
-        }
+    if terminate.load(Ordering::SeqCst) {
+        shutdown(&[&tunnel], Duration::from_secs(grace_period));
+        std::process::exit(0);
     }
+    tunnel.join();
 
     // let remaining = pargs.finish();
     // if !remaining.is_empty() {
     //     eprintln!("Warning: unused arguments left: {:?}.", remaining);
     // }
 }
+
+/// Prints a one-line diagnostic and exits with a failure status.
+fn fail<E: Display>(error: E) -> ! {
+    eprintln!("error : {}", error);
+    std::process::exit(1);
+}
+
+/// The first SIGTERM/SIGINT starts a graceful shutdown, a second one exits
+/// right away.
+fn register_shutdown_signals() -> Arc<AtomicBool> {
+    let terminate = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, terminate.clone()).unwrap();
+        signal_hook::flag::register(signal, terminate.clone()).unwrap();
+    }
+    terminate
+}
+
+fn run_config(path: &str, terminate: Arc<AtomicBool>) {
+    let config = Config::from_file(path).unwrap_or_else(|e| {
+        eprintln!("failed to load config {} : {}", path, e);
+        std::process::exit(1);
+    });
+
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, reload.clone()).unwrap();
+
+    let mut tunnels = TunnelSet::new();
+    if let Err(e) = tunnels.apply(&config) {
+        eprintln!("invalid config {} : {}", path, e);
+        std::process::exit(1);
+    }
+
+    loop {
+        thread::sleep(Duration::from_millis(100));
+
+        if terminate.load(Ordering::SeqCst) {
+            tunnels.shutdown();
+            std::process::exit(0);
+        }
+
+        if reload.swap(false, Ordering::SeqCst) {
+            println!("reloading config {}", path);
+            let result = Config::from_file(path).and_then(|config| tunnels.apply(&config));
+            if let Err(e) = result {
+                eprintln!("failed to reload config {} : {}", path, e);
+            }
+        }
+
+        if let Err(e) = tunnels.reap() {
+            eprintln!("{}", e);
+        }
+    }
+}
@@ -2,15 +2,13 @@
 
 pub mod pipeline {
     use std::{
-        fmt::Display,
+        fmt::{self, Display},
         io::{self, Write},
-        ops::{BitAnd, Deref, DerefMut},
+        ops::{BitAnd, Deref, DerefMut, Range},
         string::ParseError,
     };
 
-    use strum::Display;
-
-    #[derive(Debug, Display)]
+    #[derive(Debug)]
     pub enum IOError {
         InvalidConnection,
         InvalidBindAddress,
@@ -19,8 +17,59 @@ pub mod pipeline {
         ParseError,
         InvalidStep(String),
         InvalidData(String),
+        InvalidConfig(String),
         EmptyData,
-        Error(Box<dyn std::error::Error + Send + Sync>)
+        Error(Box<dyn std::error::Error + Send + Sync>),
+        /// An error raised by a step or an entry, with the address it was
+        /// working on.
+        Context {
+            step: String,
+            address: String,
+            cause: Box<IOError>,
+        },
+    }
+
+    impl IOError {
+        pub fn context(self, step: &str, address: &str) -> IOError {
+            IOError::Context {
+                step: step.to_string(),
+                address: address.to_string(),
+                cause: Box::new(self),
+            }
+        }
+    }
+
+    impl Display for IOError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                IOError::InvalidConnection => write!(f, "invalid connection"),
+                IOError::InvalidBindAddress => write!(f, "invalid bind address"),
+                IOError::UnknownError(msg) => write!(f, "{}", msg),
+                IOError::IoError(e) => write!(f, "{}", e),
+                IOError::ParseError => write!(f, "parse error"),
+                IOError::InvalidStep(msg) => write!(f, "invalid step : {}", msg),
+                IOError::InvalidData(msg) => write!(f, "invalid data : {}", msg),
+                IOError::InvalidConfig(msg) => write!(f, "invalid config : {}", msg),
+                IOError::EmptyData => write!(f, "empty data"),
+                IOError::Error(e) => write!(f, "{}", e),
+                IOError::Context {
+                    step,
+                    address,
+                    cause,
+                } => write!(f, "{} {} : {}", step, address, cause),
+            }
+        }
+    }
+
+    impl std::error::Error for IOError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                IOError::IoError(e) => Some(e),
+                IOError::Error(e) => Some(e.as_ref()),
+                IOError::Context { cause, .. } => Some(cause.as_ref()),
+                _ => None,
+            }
+        }
     }
 
     impl From<std::io::Error>  for IOError{
@@ -75,9 +124,37 @@ pub mod pipeline {
     }
 
     pub trait PipelineStep: Read + Write + Send + Sync + BoxedClone {
-        fn start(&mut self);
+        fn start(&mut self) -> Result<(), IOError>;
         fn len(&mut self) -> std::io::Result<usize>;
         fn set_pipeline_direction(&mut self, direction: PipelineDirection);
+        /// Called once the pipeline is no longer used, so steps holding a
+        /// connection can close it cleanly.
+        fn close(&mut self) {}
+        /// Reports, once, that the step replaced its upstream connection.
+        fn take_reconnected(&mut self) -> bool {
+            false
+        }
+        /// Called on every step after one of them reconnected, so steps that
+        /// keep per-connection state can reset it or handshake again.
+        fn on_reconnect(&mut self) -> Result<(), IOError> {
+            Ok(())
+        }
+        /// Whether the step holds data of its own to send in `direction`,
+        /// such as a handshake or delayed chunks, that no pass would
+        /// otherwise pick up.
+        fn has_pending(&self, _direction: PipelineDirection) -> bool {
+            false
+        }
+        /// Whether what the step sends only makes sense to the peer it
+        /// started with, such as records sealed with keys agreed on the
+        /// connection.
+        fn is_connection_bound(&self) -> bool {
+            false
+        }
+        /// Tells a reconnecting step whether the data written while it is
+        /// away can be replayed on the new connection, which it cannot once
+        /// a step before is connection bound.
+        fn set_replay(&mut self, _replay: bool) {}
     }
 
     pub struct Pipeline {
@@ -110,34 +187,83 @@ pub mod pipeline {
     }
 
     impl Pipeline {
-        pub fn new(steps: Vec<Box<dyn PipelineStep>>, buffer_size: Option<usize>) -> Self {
+        pub fn new(mut steps: Vec<Box<dyn PipelineStep>>, buffer_size: Option<usize>) -> Self {
+            let mut replay = true;
+            for step in steps.iter_mut() {
+                step.set_replay(replay);
+                replay &= !step.is_connection_bound();
+            }
             Pipeline {
                 steps: steps,
                 buffer_size: Some(buffer_size.unwrap_or(1024)),
             }
         }
 
-        pub fn start(&mut self) {
+        pub fn start(&mut self) -> Result<(), IOError> {
             for i in 0..self.steps.len() {
-                self.steps[i].as_mut().start();
+                self.steps[i].as_mut().start()?;
             }
+            self.send_pending()
         }
 
-        pub fn write(&mut self, mut data: Vec<u8>) -> Result<usize, IOError> {
+        pub fn write(&mut self, data: Vec<u8>) -> Result<usize, IOError> {
             for i in 0..self.steps.len() {
                 self.steps[i].set_pipeline_direction(PipelineDirection::Forward);
             }
 
-            for i in 0..self.steps.len() {
-                self.steps[i].write(&data).unwrap();
-                self.steps[i].flush().unwrap();
+            let len = self.pass_forward(0, data)?;
+            self.check_reconnected()?;
+            self.send_pending()?;
+            Ok(len)
+        }
+
+        /// Writes `data` to the steps from `first` on. A step may hold data
+        /// back, until its handshake is done for instance, which ends the
+        /// pass.
+        fn pass_forward(&mut self, first: usize, mut data: Vec<u8>) -> Result<usize, IOError> {
+            for i in first..self.steps.len() {
+                self.steps[i].write(&data)?;
+                self.steps[i].flush()?;
                 if i != (self.steps.len() - 1) {
                     data = self.steps[i].read()?;
+                    if data.is_empty() {
+                        break;
+                    }
                 }
             }
             Ok(data.len())
         }
 
+        /// Passes on what steps have to send forward of their own accord. What
+        /// they have to send backward goes with the next `read`.
+        fn send_pending(&mut self) -> Result<(), IOError> {
+            for i in 0..self.steps.len() - 1 {
+                if !self.steps[i].has_pending(PipelineDirection::Forward) {
+                    continue;
+                }
+                for step in self.steps.iter_mut() {
+                    step.set_pipeline_direction(PipelineDirection::Forward);
+                }
+                let data = self.steps[i].read()?;
+                self.pass_forward(i + 1, data)?;
+            }
+            Ok(())
+        }
+
+        fn has_pending(&self, steps: Range<usize>, direction: PipelineDirection) -> bool {
+            self.steps[steps]
+                .iter()
+                .any(|step| step.has_pending(direction))
+        }
+
+        /// Whether steps have data of their own to send either way, which a
+        /// `read` passes on.
+        fn has_own_data(&self) -> bool {
+            let last = self.steps.len() - 1;
+            self.has_pending(0..last + 1, PipelineDirection::Backward)
+                || self.has_pending(0..last, PipelineDirection::Forward)
+        }
+
         pub fn read(&mut self) -> Result<Vec<u8>, IOError> {
             for i in 0..self.steps.len() {
                 self.steps[i].set_pipeline_direction(PipelineDirection::Backward);
@@ -146,18 +272,53 @@ pub mod pipeline {
             let mut data: Vec<u8> = Vec::new();
             data.resize(self.buffer_size.unwrap(), 0);
 
-            for i in (0..self.steps.len()).rev() {
-                data = self.steps[i].read()?;
+            // the pass may only be for what steps have to send of their own
+            let pending = self.has_own_data();
+            let last = self.steps.len() - 1;
+            for i in (0..=last).rev() {
+                let result = self.steps[i].read();
+                // steps reset before anything from a new connection reaches them
+                if i == last {
+                    self.check_reconnected()?;
+                }
+                data = match result {
+                    Err(IOError::EmptyData) if pending => Vec::new(),
+                    result => result?,
+                };
                 if data.len() > 0 && i != 0 {
-                    self.steps[i - 1].write(&data).unwrap();
-                    self.steps[i - 1].flush().unwrap();
+                    self.steps[i - 1].write(&data)?;
+                    self.steps[i - 1].flush()?;
                 }
             }
+            self.send_pending()?;
             Ok(data)
         }
 
+        fn check_reconnected(&mut self) -> Result<(), IOError> {
+            let mut reconnected = false;
+            for i in 0..self.steps.len() {
+                reconnected |= self.steps[i].take_reconnected();
+            }
+            if reconnected {
+                for i in 0..self.steps.len() {
+                    self.steps[i].on_reconnect()?;
+                }
+            }
+            Ok(())
+        }
+
+        pub fn close(&mut self) {
+            for i in 0..self.steps.len() {
+                self.steps[i].close();
+            }
+        }
+
+        /// Also true when the last step fails to tell, so that `read` reports
+        /// its error.
         pub fn read_available(&mut self) -> bool {
-            self.steps.last_mut().unwrap().len().unwrap() != 0
+            let last = self.steps.last_mut().unwrap();
+            let available = last.len().map_or(true, |len| len != 0);
+            available || self.has_own_data()
         }
 
         pub fn len(&mut self) -> std::io::Result<usize>{
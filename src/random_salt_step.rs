@@ -32,7 +32,9 @@ pub mod random_salt_step {
             self.pipeline_direction = direction;
         }
 
-        fn start(&mut self) {}
+        fn start(&mut self) -> Result<(), IOError> {
+            Ok(())
+        }
     }
 
     impl BoxedClone for RSult {
@@ -43,7 +45,7 @@ pub mod random_salt_step {
                 PipelineDirection::Backward => config.push_str("bw-"),
             }
             config.push_str(self.salt_lengh.to_string().as_str());
-            Box::new(RSult::new(Some(config.as_str())))
+            Box::new(RSult::new(Some(config.as_str())).unwrap())
         }
     }
 
@@ -106,7 +108,7 @@ pub mod random_salt_step {
     }
 
     impl RSult {
-        pub fn new(config: Option<&str>) -> RSult {
+        pub fn new(config: Option<&str>) -> Result<RSult, IOError> {
             let mut work_mode: PipelineDirection = PipelineDirection::Forward;
             let mut salt_length: usize = 0;
 
@@ -114,13 +116,19 @@ pub mod random_salt_step {
                 Some(value) => {
                     let config: Vec<String> = value.split("-").map(|s| s.to_string()).collect();
                     if config.len() != 2 {
-                        panic!("random_salt_step: invalid config ");
+                        return Err(IOError::InvalidStep(format!(
+                            "salt : invalid config : {}. expected fw-<length> or bw-<length>",
+                            value
+                        )));
                     } else {
                         match config[0].as_str() {
                             "fw" => work_mode = PipelineDirection::Forward,
                             "bw" => work_mode = PipelineDirection::Backward,
                             _ => {
-                                panic!("random_salt_step : Unknown Work Mode")
+                                return Err(IOError::InvalidStep(format!(
+                                    "salt : unknown work mode : {}",
+                                    config[0]
+                                )));
                             }
                         }
 
@@ -129,23 +137,26 @@ pub mod random_salt_step {
                                 salt_length = value;
                             }
                             Err(e) => {
-                                panic!("random_salt_step: salt length error : {}", e);
+                                return Err(IOError::InvalidStep(format!(
+                                    "salt : invalid length {} : {}",
+                                    config[1], e
+                                )));
                             }
                         }
                     }
                 }
                 None => {
-                    panic!("random_salt_step : Empty Config")
+                    return Err(IOError::InvalidStep("salt : empty config".to_string()));
                 }
             }
 
-            RSult {
+            Ok(RSult {
                 salt_lengh: salt_length,
                 forward_buffer: vec![0; 0],
                 backward_buffer: vec![0; 0],
                 work_mode: work_mode,
                 pipeline_direction: PipelineDirection::Forward,
-            }
+            })
         }
     }
 }
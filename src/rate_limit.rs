@@ -0,0 +1,253 @@
+pub mod rate_limit {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use crate::{IOError, SpecOptions};
+
+    /// Which way data goes, seen from the clients of an entry.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Transfer {
+        Upload,
+        Download,
+    }
+
+    /// Bandwidth caps of an entry, in bytes per second.
+    /// `?upload_rate=` and `download_rate=` cap all its clients together,
+    /// `client_upload_rate=` and `client_download_rate=` each one of them.
+    /// 0 or nothing leaves the direction unlimited.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct RatePolicy {
+        pub upload: Option<u64>,
+        pub download: Option<u64>,
+        pub client_upload: Option<u64>,
+        pub client_download: Option<u64>,
+    }
+
+    impl RatePolicy {
+        pub fn from_options(options: &SpecOptions) -> Result<RatePolicy, IOError> {
+            let rate = |key: &str| -> Result<Option<u64>, IOError> {
+                Ok(options.get::<u64>(key)?.filter(|rate| *rate > 0))
+            };
+            Ok(RatePolicy {
+                upload: rate("upload_rate")?,
+                download: rate("download_rate")?,
+                client_upload: rate("client_upload_rate")?,
+                client_download: rate("client_download_rate")?,
+            })
+        }
+    }
+
+    /// Refilled at `rate` tokens per second, holding at most a second of
+    /// them. Transfers take a token per byte they moved, and may go in debt
+    /// when they cannot be cut to what is left, which the next ones wait out.
+    pub struct TokenBucket {
+        rate: f64,
+        tokens: f64,
+        refilled: Instant,
+    }
+
+    impl TokenBucket {
+        pub fn new(rate: u64) -> TokenBucket {
+            TokenBucket::new_at(rate, Instant::now())
+        }
+
+        /// Whole tokens left, none while in debt.
+        pub fn available(&mut self) -> usize {
+            self.available_at(Instant::now())
+        }
+
+        /// How long until a token is left.
+        pub fn wait(&mut self) -> Duration {
+            self.wait_at(Instant::now())
+        }
+
+        pub fn take(&mut self, bytes: usize) {
+            self.take_at(bytes, Instant::now())
+        }
+
+        // the same at a given time, which tests pick
+
+        fn new_at(rate: u64, now: Instant) -> TokenBucket {
+            TokenBucket {
+                rate: rate as f64,
+                tokens: rate as f64,
+                refilled: now,
+            }
+        }
+
+        fn refill(&mut self, now: Instant) {
+            let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+            self.refilled = self.refilled.max(now);
+        }
+
+        fn available_at(&mut self, now: Instant) -> usize {
+            self.refill(now);
+            self.tokens.max(0.0) as usize
+        }
+
+        fn wait_at(&mut self, now: Instant) -> Duration {
+            self.refill(now);
+            match self.tokens < 1.0 {
+                true => Duration::from_secs_f64((1.0 - self.tokens) / self.rate),
+                false => Duration::ZERO,
+            }
+        }
+
+        fn take_at(&mut self, bytes: usize, now: Instant) {
+            self.refill(now);
+            self.tokens -= bytes as f64;
+        }
+    }
+
+    type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+    /// The buckets an entry shares between its clients. Clones share them
+    /// too, so it goes along with the entry to every connection.
+    #[derive(Clone, Default)]
+    pub struct RateLimiter {
+        policy: RatePolicy,
+        upload: Option<SharedBucket>,
+        download: Option<SharedBucket>,
+    }
+
+    impl RateLimiter {
+        pub fn new(policy: RatePolicy) -> RateLimiter {
+            let shared =
+                |rate: Option<u64>| rate.map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate))));
+            RateLimiter {
+                policy,
+                upload: shared(policy.upload),
+                download: shared(policy.download),
+            }
+        }
+
+        /// The limits of a new client : buckets of its own, and the ones of
+        /// the entry.
+        pub fn client(&self) -> ClientRate {
+            ClientRate {
+                shared: [self.upload.clone(), self.download.clone()],
+                own: [
+                    self.policy.client_upload.map(TokenBucket::new),
+                    self.policy.client_download.map(TokenBucket::new),
+                ],
+            }
+        }
+    }
+
+    /// What one client may transfer, indexed by `Transfer`.
+    pub struct ClientRate {
+        shared: [Option<SharedBucket>; 2],
+        own: [Option<TokenBucket>; 2],
+    }
+
+    impl ClientRate {
+        /// How long until `transfer` may go on, zero when it may now.
+        pub fn wait(&mut self, transfer: Transfer) -> Duration {
+            let own = match self.own[transfer as usize].as_mut() {
+                Some(bucket) => bucket.wait(),
+                None => Duration::ZERO,
+            };
+            let shared = match self.shared[transfer as usize].as_ref() {
+                Some(bucket) => bucket.lock().unwrap().wait(),
+                None => Duration::ZERO,
+            };
+            own.max(shared)
+        }
+
+        /// How many bytes `transfer` may move now, `usize::MAX` when it is
+        /// not limited.
+        pub fn allowance(&mut self, transfer: Transfer) -> usize {
+            let own = match self.own[transfer as usize].as_mut() {
+                Some(bucket) => bucket.available(),
+                None => usize::MAX,
+            };
+            let shared = match self.shared[transfer as usize].as_ref() {
+                Some(bucket) => bucket.lock().unwrap().available(),
+                None => usize::MAX,
+            };
+            own.min(shared)
+        }
+
+        /// Counts `bytes` moved by `transfer` against the buckets.
+        pub fn take(&mut self, transfer: Transfer, bytes: usize) {
+            if let Some(bucket) = self.own[transfer as usize].as_mut() {
+                bucket.take(bytes);
+            }
+            if let Some(bucket) = self.shared[transfer as usize].as_ref() {
+                bucket.lock().unwrap().take(bytes);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const MS: Duration = Duration::from_millis(1);
+
+        #[test]
+        fn bucket_starts_full_and_goes_in_debt() {
+            let start = Instant::now();
+            let mut bucket = TokenBucket::new_at(1000, start);
+            assert_eq!(bucket.available_at(start), 1000);
+            assert_eq!(bucket.wait_at(start), Duration::ZERO);
+
+            bucket.take_at(1500, start);
+            assert_eq!(bucket.available_at(start), 0);
+            // the 500 of debt and a token more
+            assert_eq!(bucket.wait_at(start), 501 * MS);
+            assert_eq!(bucket.wait_at(start + 200 * MS), 301 * MS);
+            assert_eq!(bucket.available_at(start + 600 * MS), 100);
+        }
+
+        #[test]
+        fn bucket_refills_up_to_a_second() {
+            let start = Instant::now();
+            let mut bucket = TokenBucket::new_at(1000, start);
+            bucket.take_at(1000, start);
+            assert_eq!(bucket.available_at(start + 50 * MS), 50);
+            assert_eq!(bucket.available_at(start + 250 * MS), 250);
+            assert_eq!(bucket.available_at(start + 5000 * MS), 1000);
+
+            // a time before the last refill gives nothing back
+            assert_eq!(bucket.available_at(start), 1000);
+            bucket.take_at(1000, start + 5000 * MS);
+            assert_eq!(bucket.available_at(start + 4000 * MS), 0);
+        }
+
+        #[test]
+        fn client_takes_from_its_own_and_the_shared_buckets() {
+            let limiter = RateLimiter::new(RatePolicy {
+                upload: Some(1000),
+                client_upload: Some(600),
+                ..RatePolicy::default()
+            });
+            let mut first = limiter.client();
+            let mut second = limiter.client();
+            assert_eq!(first.allowance(Transfer::Upload), 600);
+            assert_eq!(first.allowance(Transfer::Download), usize::MAX);
+
+            first.take(Transfer::Upload, 600);
+            assert_eq!(first.allowance(Transfer::Upload), 0);
+            assert!(!first.wait(Transfer::Upload).is_zero());
+            // the entry has 400 left for the others, and some more refilled
+            // since, well below the 600 of the client
+            let allowance = second.allowance(Transfer::Upload);
+            assert!((400..600).contains(&allowance), "{}", allowance);
+            assert!(second.wait(Transfer::Upload).is_zero());
+            assert!(second.wait(Transfer::Download).is_zero());
+        }
+
+        #[test]
+        fn zero_rates_are_unlimited() {
+            let options =
+                SpecOptions::parse("tcp://0.0.0.0:1?upload_rate=0&client_download_rate=5");
+            let policy = RatePolicy::from_options(&options).unwrap();
+            assert_eq!(policy.upload, None);
+            assert_eq!(policy.client_download, Some(5));
+        }
+    }
+}
@@ -0,0 +1,286 @@
+pub mod reconnect {
+    use rand::Rng;
+    use std::{
+        io,
+        net::TcpStream,
+        os::fd::AsRawFd,
+        time::{Duration, Instant},
+    };
+
+    use crate::{IOError, SpecOptions};
+
+    pub const DEFAULT_INITIAL_BACKOFF: u64 = 100;
+    pub const DEFAULT_MAX_BACKOFF: u64 = 30_000;
+    pub const DEFAULT_MAX_PENDING: usize = 64 * 1024;
+    pub const DEFAULT_CONNECT_TIMEOUT: u64 = 5_000;
+
+    /// How a destination step reconnects once its upstream goes away, read
+    /// from `?reconnect=<max attempts, 0 for unlimited>&backoff=<ms>`
+    /// `&max_backoff=<ms>&max_pending=<bytes>&connect_timeout=<ms>`. Each
+    /// connection attempt gives up after `connect_timeout`, so that a
+    /// silent upstream does not hold the pipeline.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct ReconnectPolicy {
+        pub max_attempts: u32,
+        pub initial_backoff: Duration,
+        pub max_backoff: Duration,
+        pub max_pending: usize,
+        pub connect_timeout: Duration,
+    }
+
+    impl ReconnectPolicy {
+        /// `None` when the spec does not ask for reconnection.
+        pub fn from_options(options: &SpecOptions) -> Result<Option<ReconnectPolicy>, IOError> {
+            let max_attempts = match options.get::<u32>("reconnect")? {
+                Some(max_attempts) => max_attempts,
+                None => return Ok(None),
+            };
+            let initial_backoff = options
+                .get::<u64>("backoff")?
+                .unwrap_or(DEFAULT_INITIAL_BACKOFF);
+            let max_backoff = options
+                .get::<u64>("max_backoff")?
+                .unwrap_or(DEFAULT_MAX_BACKOFF)
+                .max(initial_backoff);
+            let max_pending = options
+                .get::<usize>("max_pending")?
+                .unwrap_or(DEFAULT_MAX_PENDING);
+            let connect_timeout = options
+                .get::<u64>("connect_timeout")?
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+            if connect_timeout == 0 {
+                return Err(IOError::InvalidConfig(
+                    "connect_timeout must be greater than zero".to_string(),
+                ));
+            }
+
+            Ok(Some(ReconnectPolicy {
+                max_attempts,
+                initial_backoff: Duration::from_millis(initial_backoff),
+                max_backoff: Duration::from_millis(max_backoff),
+                max_pending,
+                connect_timeout: Duration::from_millis(connect_timeout),
+            }))
+        }
+
+        /// Exponential backoff with "equal jitter": somewhere between half and
+        /// all of the capped delay, so clients cut off together do not all
+        /// come back at the same instant.
+        pub fn delay(&self, attempt: u32) -> Duration {
+            let delay = self
+                .initial_backoff
+                .saturating_mul(2u32.saturating_pow(attempt))
+                .min(self.max_backoff);
+            let half = delay / 2;
+            let jitter = rand::thread_rng().gen_range(0..=half.as_millis() as u64);
+            half + Duration::from_millis(jitter)
+        }
+    }
+
+    /// Reconnection state of one destination step. While the upstream is
+    /// away, writes are kept in a bounded buffer and replayed once a new
+    /// connection is made, unless a step before is bound to the lost
+    /// connection : what it sent cannot be read by a new peer, so it is
+    /// dropped instead.
+    pub struct Reconnect {
+        policy: ReconnectPolicy,
+        attempt: u32,
+        next_attempt: Option<Instant>,
+        pending: Vec<u8>,
+        reconnected: bool,
+        given_up: Option<String>,
+        replay: bool,
+        dropped: usize,
+    }
+
+    impl Reconnect {
+        pub fn new(policy: ReconnectPolicy) -> Self {
+            Reconnect {
+                policy,
+                attempt: 0,
+                next_attempt: None,
+                pending: Vec::new(),
+                reconnected: false,
+                given_up: None,
+                replay: true,
+                dropped: 0,
+            }
+        }
+
+        pub fn set_replay(&mut self, replay: bool) {
+            self.replay = replay;
+        }
+
+        pub fn policy(&self) -> ReconnectPolicy {
+            self.policy
+        }
+
+        /// Records that the connection to `address` was lost and schedules
+        /// the first attempt.
+        pub fn disconnected(&mut self, address: &str, cause: &str) {
+            self.attempt = 0;
+            let delay = self.policy.delay(0);
+            println!(
+                "{} : connection lost ({}), reconnecting in {} ms",
+                address,
+                cause,
+                delay.as_millis()
+            );
+            self.next_attempt = Some(Instant::now() + delay);
+        }
+
+        /// Fails for good once the step gave up reconnecting.
+        pub fn check(&self) -> io::Result<()> {
+            match &self.given_up {
+                Some(msg) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, msg.clone())),
+                None => Ok(()),
+            }
+        }
+
+        /// True when the backoff delay of the next attempt is over.
+        pub fn should_attempt(&self) -> bool {
+            match self.next_attempt {
+                Some(next_attempt) => Instant::now() >= next_attempt,
+                None => false,
+            }
+        }
+
+        /// Schedules the next attempt, or gives up once `max_attempts` is
+        /// reached.
+        pub fn failed(&mut self, address: &str, cause: &IOError) -> io::Result<()> {
+            self.attempt += 1;
+            if self.policy.max_attempts != 0 && self.attempt >= self.policy.max_attempts {
+                self.next_attempt = None;
+                self.pending.clear();
+                self.given_up = Some(format!(
+                    "{} : giving up after {} attempts : {}",
+                    address, self.attempt, cause
+                ));
+                return self.check();
+            }
+
+            let delay = self.policy.delay(self.attempt);
+            println!(
+                "{} : reconnect attempt {} failed ({}), retrying in {} ms",
+                address,
+                self.attempt,
+                cause,
+                delay.as_millis()
+            );
+            self.next_attempt = Some(Instant::now() + delay);
+            Ok(())
+        }
+
+        /// Resets the backoff and hands back the data written meanwhile.
+        pub fn connected(&mut self, address: &str) -> Vec<u8> {
+            println!("{} : reconnected after {} attempts", address, self.attempt + 1);
+            if self.dropped > 0 {
+                println!(
+                    "{} : dropped {} bytes written for the lost connection",
+                    address, self.dropped
+                );
+                self.dropped = 0;
+            }
+            self.attempt = 0;
+            self.next_attempt = None;
+            self.reconnected = true;
+            std::mem::take(&mut self.pending)
+        }
+
+        /// True from the reconnection until the pipeline is told about it,
+        /// while what is written was still meant for the lost connection and
+        /// cannot be replayed.
+        pub fn is_stale(&self) -> bool {
+            self.reconnected && !self.replay
+        }
+
+        /// Drops `data` meant for the lost connection.
+        pub fn drop_stale(&self, address: &str, data: &[u8]) -> usize {
+            println!(
+                "{} : dropped {} bytes written for the lost connection",
+                address,
+                data.len()
+            );
+            data.len()
+        }
+
+        /// Keeps `data` until the connection is back.
+        pub fn buffer(&mut self, data: &[u8]) -> io::Result<usize> {
+            if !self.replay {
+                self.dropped += data.len();
+                return Ok(data.len());
+            }
+            if self.pending.len() + data.len() > self.policy.max_pending {
+                return Err(io::Error::new(
+                    io::ErrorKind::OutOfMemory,
+                    format!(
+                        "more than {} bytes pending while reconnecting",
+                        self.policy.max_pending
+                    ),
+                ));
+            }
+            self.pending.extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        pub fn take_reconnected(&mut self) -> bool {
+            std::mem::take(&mut self.reconnected)
+        }
+    }
+
+    /// Tells whether the peer closed or reset `stream`, without consuming
+    /// any data.
+    pub fn peer_closed(stream: &TcpStream) -> bool {
+        let mut byte = 0u8;
+        let result = unsafe {
+            libc::recv(
+                stream.as_raw_fd(),
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        match result {
+            0 => true,
+            -1 => !matches!(
+                io::Error::last_os_error().kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ),
+            _ => false,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn policy() -> ReconnectPolicy {
+            ReconnectPolicy::from_options(&SpecOptions::parse(
+                "tcp://a:1?reconnect=0&max_pending=8",
+            ))
+            .unwrap()
+            .unwrap()
+        }
+
+        #[test]
+        fn replays_what_was_buffered() {
+            let mut reconnect = Reconnect::new(policy());
+            reconnect.buffer(b"abcd").unwrap();
+            assert!(reconnect.buffer(b"efghi").is_err());
+            assert_eq!(reconnect.connected("a:1"), b"abcd");
+            assert!(!reconnect.is_stale());
+            assert!(reconnect.take_reconnected());
+        }
+
+        #[test]
+        fn drops_what_a_bound_step_sent() {
+            let mut reconnect = Reconnect::new(policy());
+            reconnect.set_replay(false);
+            assert_eq!(reconnect.buffer(b"sealed for the old peer").unwrap(), 23);
+            assert!(reconnect.connected("a:1").is_empty());
+            assert!(reconnect.is_stale());
+            assert!(reconnect.take_reconnected());
+            assert!(!reconnect.is_stale());
+        }
+    }
+}
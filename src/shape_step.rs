@@ -0,0 +1,402 @@
+pub mod shape_step {
+    use rand::Rng;
+    use std::{
+        collections::VecDeque,
+        io::{self, Write},
+        ops::RangeInclusive,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        pipeline_module::pipeline::{PipelineDirection, PipelineStep},
+        BoxedClone, IOError, Read, SpecOptions,
+    };
+
+    const DATA: u8 = 0;
+    const DUMMY: u8 = 1;
+    /// Kind, data length and padding length.
+    const HEADER_LEN: usize = 5;
+    /// Smallest chunk that still carries a byte of data.
+    pub const MIN_CHUNK: usize = HEADER_LEN + 1;
+    pub const MAX_CHUNK: usize = HEADER_LEN + u16::MAX as usize;
+    pub const DEFAULT_SIZES: &str = "128-1400";
+
+    /// How a `shape:` step cuts and pads what it sends.
+    /// `?sizes=256-512,1400` gives the sizes chunks are drawn from, a range
+    /// or a size picked at random and then a size in it, `dummy=ms` sends a
+    /// dummy chunk after that long without traffic, `jitter=ms` delays each
+    /// write by up to that long.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct ShapePolicy {
+        pub sizes: Vec<RangeInclusive<usize>>,
+        pub dummy: Option<Duration>,
+        pub jitter: Option<Duration>,
+    }
+
+    impl ShapePolicy {
+        pub fn from_options(options: &SpecOptions) -> Result<ShapePolicy, IOError> {
+            let sizes = options.get_str("sizes").unwrap_or(DEFAULT_SIZES);
+            let sizes = sizes
+                .split(',')
+                .map(|size| parse_size(size.trim()))
+                .collect::<Result<Vec<_>, IOError>>()?;
+            let millis = |key: &str| -> Result<Option<Duration>, IOError> {
+                Ok(options
+                    .get::<u64>(key)?
+                    .filter(|ms| *ms > 0)
+                    .map(Duration::from_millis))
+            };
+            Ok(ShapePolicy {
+                sizes,
+                dummy: millis("dummy")?,
+                jitter: millis("jitter")?,
+            })
+        }
+
+        fn chunk_size(&self) -> usize {
+            let mut rng = rand::thread_rng();
+            let range = self.sizes[rng.gen_range(0..self.sizes.len())].clone();
+            rng.gen_range(range)
+        }
+
+        fn delay(&self) -> Duration {
+            match self.jitter {
+                Some(jitter) => rand::thread_rng().gen_range(Duration::ZERO..=jitter),
+                None => Duration::ZERO,
+            }
+        }
+
+        /// Somewhere between half and one and a half `dummy`, so that
+        /// dummies do not tick like a clock.
+        fn idle_time(&self) -> Option<Duration> {
+            self.dummy
+                .map(|dummy| dummy.mul_f64(rand::thread_rng().gen_range(0.5..1.5)))
+        }
+    }
+
+    /// `shape:fw` / `shape:bw` cuts what goes in its work direction into
+    /// chunks of sizes drawn from its `ShapePolicy`, padding the last one,
+    /// and strips padding and dummies from what comes back from the peer
+    /// `shape:` step.
+    pub struct ShapeStep {
+        policy: ShapePolicy,
+        /// Chunks waiting for their jitter delay to pass, in order.
+        delayed: VecDeque<(Instant, Vec<u8>)>,
+        /// When a dummy chunk is due, once started.
+        next_dummy: Option<Instant>,
+        /// Chunk of the peer as far as it came.
+        received: Vec<u8>,
+        forward_buffer: Vec<u8>,
+        backward_buffer: Vec<u8>,
+        work_mode: PipelineDirection,
+        pipeline_direction: PipelineDirection,
+    }
+
+    impl PipelineStep for ShapeStep {
+        fn len(&mut self) -> std::io::Result<usize> {
+            match self.pipeline_direction {
+                PipelineDirection::Forward => Ok(self.forward_buffer.len()),
+                PipelineDirection::Backward => Ok(self.backward_buffer.len()),
+            }
+        }
+
+        fn set_pipeline_direction(&mut self, direction: PipelineDirection) {
+            self.pipeline_direction = direction;
+        }
+
+        fn start(&mut self) -> Result<(), IOError> {
+            self.next_dummy = self.policy.idle_time().map(|idle| Instant::now() + idle);
+            Ok(())
+        }
+
+        fn has_pending(&self, direction: PipelineDirection) -> bool {
+            let now = Instant::now();
+            direction == self.work_mode
+                && (self.delayed.front().is_some_and(|(at, _)| *at <= now)
+                    || self.next_dummy.is_some_and(|at| at <= now))
+        }
+
+        /// Chunks stand alone, so those still delayed go to the new peer of
+        /// `shape:fw`, while a chunk of the old one cut short is dropped.
+        /// `shape:bw` reconnects toward its destination, its peer stays.
+        fn on_reconnect(&mut self) -> Result<(), IOError> {
+            if self.work_mode == PipelineDirection::Forward {
+                self.received.clear();
+            }
+            Ok(())
+        }
+    }
+
+    impl BoxedClone for ShapeStep {
+        fn bclone(&self) -> Box<dyn PipelineStep> {
+            Box::new(ShapeStep::with(self.policy.clone(), self.work_mode))
+        }
+    }
+
+    impl Read for ShapeStep {
+        fn read(&mut self) -> Result<Vec<u8>, IOError> {
+            if self.work_mode == self.pipeline_direction {
+                self.release();
+            }
+            match self.pipeline_direction {
+                PipelineDirection::Forward => Ok(std::mem::take(&mut self.forward_buffer)),
+                PipelineDirection::Backward => Ok(std::mem::take(&mut self.backward_buffer)),
+            }
+        }
+    }
+
+    impl Write for ShapeStep {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.work_mode == self.pipeline_direction {
+                self.shape(buf);
+            } else {
+                self.unshape(buf)?;
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ShapeStep {
+        pub fn new(spec: &str) -> Result<ShapeStep, IOError> {
+            let config = spec.strip_prefix("shape:").unwrap_or("");
+            let work_mode = match config.split('?').next() {
+                Some("fw") => PipelineDirection::Forward,
+                Some("bw") => PipelineDirection::Backward,
+                mode => {
+                    return Err(IOError::InvalidStep(format!(
+                        "shape : unknown work mode : {}",
+                        mode.unwrap_or("")
+                    )))
+                }
+            };
+            let policy = ShapePolicy::from_options(&SpecOptions::parse(spec))?;
+            Ok(ShapeStep::with(policy, work_mode))
+        }
+
+        fn with(policy: ShapePolicy, work_mode: PipelineDirection) -> ShapeStep {
+            ShapeStep {
+                policy,
+                delayed: VecDeque::new(),
+                next_dummy: None,
+                received: Vec::new(),
+                forward_buffer: Vec::new(),
+                backward_buffer: Vec::new(),
+                work_mode,
+                pipeline_direction: PipelineDirection::Forward,
+            }
+        }
+
+        /// Cuts `data` into chunks held until the jitter delay of this write
+        /// passes. Chunks never overtake the ones of an earlier write.
+        fn shape(&mut self, data: &[u8]) {
+            let mut chunks = Vec::new();
+            let mut rest = data;
+            while !rest.is_empty() {
+                let size = self.policy.chunk_size();
+                let (data, tail) = rest.split_at(rest.len().min(size - HEADER_LEN));
+                chunks.extend(chunk(DATA, data, size));
+                rest = tail;
+            }
+
+            let mut at = Instant::now() + self.policy.delay();
+            if let Some((last, _)) = self.delayed.back() {
+                at = at.max(*last);
+            }
+            self.delayed.push_back((at, chunks));
+        }
+
+        /// Moves the chunks whose delay passed to the work direction buffer,
+        /// or a dummy chunk when nothing was sent for a while.
+        fn release(&mut self) {
+            let now = Instant::now();
+            let mut output = Vec::new();
+            while let Some((at, _)) = self.delayed.front() {
+                if *at > now {
+                    break;
+                }
+                output.extend(self.delayed.pop_front().unwrap().1);
+            }
+            if output.is_empty() && self.next_dummy.is_some_and(|at| at <= now) {
+                output = chunk(DUMMY, &[], self.policy.chunk_size());
+            }
+            if !output.is_empty() && self.next_dummy.is_some() {
+                self.next_dummy = self.policy.idle_time().map(|idle| now + idle);
+            }
+            match self.work_mode {
+                PipelineDirection::Forward => self.forward_buffer.extend(output),
+                PipelineDirection::Backward => self.backward_buffer.extend(output),
+            }
+        }
+
+        fn unshape(&mut self, data: &[u8]) -> io::Result<()> {
+            self.received.extend_from_slice(data);
+            let mut output = Vec::new();
+            let mut offset = 0;
+            while let Some(header) = self.received.get(offset..offset + HEADER_LEN) {
+                let kind = header[0];
+                let length = u16::from_be_bytes([header[1], header[2]]) as usize;
+                let padding = u16::from_be_bytes([header[3], header[4]]) as usize;
+                if kind > DUMMY || (kind == DUMMY && length != 0) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("shape : invalid chunk header : {:?}", header),
+                    ));
+                }
+                let start = offset + HEADER_LEN;
+                if self.received.len() < start + length + padding {
+                    break;
+                }
+                output.extend_from_slice(&self.received[start..start + length]);
+                offset = start + length + padding;
+            }
+            self.received.drain(..offset);
+            match self.pipeline_direction {
+                PipelineDirection::Forward => self.forward_buffer.extend(output),
+                PipelineDirection::Backward => self.backward_buffer.extend(output),
+            }
+            Ok(())
+        }
+    }
+
+    /// `kind | length | padding length | data | random padding`, `size`
+    /// bytes in all.
+    fn chunk(kind: u8, data: &[u8], size: usize) -> Vec<u8> {
+        let padding = size - HEADER_LEN - data.len();
+        let mut chunk = Vec::with_capacity(size);
+        chunk.push(kind);
+        chunk.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        chunk.extend_from_slice(&(padding as u16).to_be_bytes());
+        chunk.extend_from_slice(data);
+        let start = chunk.len();
+        chunk.resize(size, 0);
+        rand::thread_rng().fill(&mut chunk[start..]);
+        chunk
+    }
+
+    fn parse_size(size: &str) -> Result<RangeInclusive<usize>, IOError> {
+        let invalid = || {
+            IOError::InvalidConfig(format!(
+                "shape : invalid size : {}. use n or min-max, from {} to {}",
+                size, MIN_CHUNK, MAX_CHUNK
+            ))
+        };
+        let (min, max) = size.split_once('-').unwrap_or((size, size));
+        let min = min.trim().parse::<usize>().map_err(|_| invalid())?;
+        let max = max.trim().parse::<usize>().map_err(|_| invalid())?;
+        if min < MIN_CHUNK || max > MAX_CHUNK || min > max {
+            return Err(invalid());
+        }
+        Ok(min..=max)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn policy(spec: &str) -> ShapePolicy {
+            ShapePolicy::from_options(&SpecOptions::parse(spec)).unwrap()
+        }
+
+        fn pair(spec: &str) -> (ShapeStep, ShapeStep) {
+            let mut fw = ShapeStep::with(policy(spec), PipelineDirection::Forward);
+            let mut bw = ShapeStep::with(policy(spec), PipelineDirection::Backward);
+            fw.start().unwrap();
+            bw.start().unwrap();
+            (fw, bw)
+        }
+
+        fn shape(step: &mut ShapeStep, data: &[u8]) -> Vec<u8> {
+            step.set_pipeline_direction(PipelineDirection::Forward);
+            step.write(data).unwrap();
+            step.read().unwrap()
+        }
+
+        fn unshape(step: &mut ShapeStep, data: &[u8]) -> io::Result<Vec<u8>> {
+            step.set_pipeline_direction(PipelineDirection::Forward);
+            step.write(data)?;
+            Ok(step.read().unwrap())
+        }
+
+        #[test]
+        fn chunks_have_the_sizes_asked_for() {
+            let (mut fw, mut bw) = pair("shape:fw?sizes=100,300-310");
+            let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+            let shaped = shape(&mut fw, &data);
+
+            let mut offset = 0;
+            while offset < shaped.len() {
+                let length = u16::from_be_bytes([shaped[offset + 1], shaped[offset + 2]]);
+                let padding = u16::from_be_bytes([shaped[offset + 3], shaped[offset + 4]]);
+                let size = HEADER_LEN + length as usize + padding as usize;
+                assert!(size == 100 || (300..=310).contains(&size), "{}", size);
+                offset += size;
+            }
+            assert_eq!(offset, shaped.len());
+            assert_eq!(unshape(&mut bw, &shaped).unwrap(), data);
+        }
+
+        #[test]
+        fn unshape_cut_anywhere() {
+            let (mut fw, mut bw) = pair("shape:fw?sizes=6-64");
+            let shaped = shape(&mut fw, b"some data to cut into small chunks");
+            let mut output = Vec::new();
+            for piece in shaped.chunks(3) {
+                output.extend(unshape(&mut bw, piece).unwrap());
+            }
+            assert_eq!(output, b"some data to cut into small chunks");
+        }
+
+        #[test]
+        fn dummies_are_sent_when_idle_and_dropped() {
+            let (mut fw, mut bw) = pair("shape:fw?sizes=64&dummy=1");
+            std::thread::sleep(Duration::from_millis(5));
+            assert!(fw.has_pending(PipelineDirection::Forward));
+            assert!(!fw.has_pending(PipelineDirection::Backward));
+            fw.set_pipeline_direction(PipelineDirection::Forward);
+            let dummy = fw.read().unwrap();
+            assert_eq!(dummy.len(), 64);
+            assert_eq!(dummy[0], DUMMY);
+            assert!(unshape(&mut bw, &dummy).unwrap().is_empty());
+
+            let mut shaped = shape(&mut fw, b"data");
+            std::thread::sleep(Duration::from_millis(5));
+            shaped.extend(fw.read().unwrap());
+            shaped.extend(shape(&mut fw, b" after"));
+            assert_eq!(unshape(&mut bw, &shaped).unwrap(), b"data after");
+        }
+
+        #[test]
+        fn jitter_keeps_writes_in_order() {
+            let (mut fw, mut bw) = pair("shape:fw?sizes=64&jitter=5");
+            let mut shaped = Vec::new();
+            for word in [&b"one "[..], b"two ", b"three"] {
+                shaped.extend(shape(&mut fw, word));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            assert!(fw.has_pending(PipelineDirection::Forward));
+            shaped.extend(fw.read().unwrap());
+            assert_eq!(unshape(&mut bw, &shaped).unwrap(), b"one two three");
+        }
+
+        #[test]
+        fn invalid_chunks_are_rejected() {
+            let (_, mut bw) = pair("shape:fw");
+            assert!(unshape(&mut bw, &[2, 0, 0, 0, 0]).is_err());
+            let (_, mut bw) = pair("shape:fw");
+            assert!(unshape(&mut bw, &[DUMMY, 0, 1, 0, 0, 0]).is_err());
+        }
+
+        #[test]
+        fn sizes_are_checked() {
+            assert_eq!(parse_size("6").unwrap(), 6..=6);
+            assert_eq!(parse_size("100-200").unwrap(), 100..=200);
+            assert!(parse_size("5").is_err());
+            assert!(parse_size("200-100").is_err());
+            assert!(parse_size(&(MAX_CHUNK + 1).to_string()).is_err());
+        }
+    }
+}
@@ -0,0 +1,205 @@
+pub mod spec_tools {
+    use http::Uri;
+    use std::{collections::HashMap, str::FromStr};
+
+    use crate::IOError;
+
+    /// Options given to an entry or a step as `?key=value&...` after its
+    /// address.
+    pub struct SpecOptions {
+        spec: String,
+        values: HashMap<String, String>,
+    }
+
+    impl SpecOptions {
+        pub fn parse(spec: &str) -> SpecOptions {
+            let mut values = HashMap::new();
+            if let Some((_, query)) = spec.split_once('?') {
+                for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    values.insert(key.to_string(), percent_decode(value));
+                }
+            }
+            SpecOptions {
+                spec: spec.to_string(),
+                values,
+            }
+        }
+
+        pub fn contains(&self, key: &str) -> bool {
+            self.values.contains_key(key)
+        }
+
+        pub fn get_str(&self, key: &str) -> Option<&str> {
+            self.values.get(key).map(|value| value.as_str())
+        }
+
+        pub fn get<T: FromStr>(&self, key: &str) -> Result<Option<T>, IOError>
+        where
+            T::Err: std::fmt::Display,
+        {
+            match self.values.get(key) {
+                Some(value) => value.parse::<T>().map(Some).map_err(|e| {
+                    IOError::InvalidConfig(format!(
+                        "{} : invalid value for {} : {} : {}",
+                        redact(&self.spec),
+                        key,
+                        value,
+                        e
+                    ))
+                }),
+                None => Ok(None),
+            }
+        }
+    }
+
+    /// Options whose values are secrets.
+    const SECRET_OPTIONS: &[&str] = &["psk", "key"];
+
+    /// The spec with its secrets masked, to be logged or put in errors: the
+    /// `psk` and `key` options and the key of `hmac:<mode>-<key>`, which
+    /// is masked whole as it may hold a `?`.
+    pub fn redact(spec: &str) -> String {
+        if let Some((mode, _)) = spec
+            .strip_prefix("hmac:")
+            .and_then(|config| config.split_once('-'))
+        {
+            return format!("hmac:{}-***", mode);
+        }
+        let (address, query) = match spec.split_once('?') {
+            Some((address, query)) => (address, Some(query)),
+            None => (spec, None),
+        };
+        let mut redacted = address.to_string();
+        if let Some(query) = query {
+            let pairs: Vec<String> = query
+                .split('&')
+                .map(|pair| match pair.split_once('=') {
+                    Some((key, _)) if SECRET_OPTIONS.contains(&key) => format!("{}=***", key),
+                    _ => pair.to_string(),
+                })
+                .collect();
+            redacted.push('?');
+            redacted.push_str(&pairs.join("&"));
+        }
+        redacted
+    }
+
+    /// Escapes what an option value cannot hold as is, such as spaces, `&`
+    /// or `%`.
+    pub fn percent_encode(value: &str) -> String {
+        let mut encoded = String::with_capacity(value.len());
+        for byte in value.bytes() {
+            match byte {
+                b'A'..=b'Z'
+                | b'a'..=b'z'
+                | b'0'..=b'9'
+                | b'-'
+                | b'.'
+                | b'_'
+                | b'~'
+                | b'/'
+                | b','
+                | b':'
+                | b';'
+                | b'('
+                | b')'
+                | b'!'
+                | b'*'
+                | b'@' => encoded.push(byte as char),
+                _ => encoded.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+        encoded
+    }
+
+    /// Decodes the `%XX` escapes of an option value. A `%` not followed by
+    /// two hex digits is kept as is.
+    pub fn percent_decode(value: &str) -> String {
+        let bytes = value.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let escaped = bytes
+                .get(i + 1..i + 3)
+                .filter(|hex| bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match escaped {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 3;
+                }
+                None => {
+                    decoded.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+        String::from_utf8_lossy(&decoded).into_owned()
+    }
+
+    /// Splits `scheme://a:1,b:2/path?options` into one spec per upstream,
+    /// each keeping the scheme, path and options.
+    pub fn split_upstreams(spec: &str) -> Vec<String> {
+        let (scheme, rest) = match spec.split_once("://") {
+            Some(parts) => parts,
+            None => return vec![spec.to_string()],
+        };
+        let end = rest.find(|c| c == '/' || c == '?').unwrap_or(rest.len());
+        let (hosts, tail) = rest.split_at(end);
+        hosts
+            .split(',')
+            .map(|host| format!("{}://{}{}", scheme, host.trim(), tail))
+            .collect()
+    }
+
+    /// Parses a `scheme://host[:port]` spec and returns the `host:port` to
+    /// bind or connect to. The port falls back to the scheme's default.
+    pub fn socket_address(spec: &str) -> Result<String, IOError> {
+        let uri = spec
+            .parse::<Uri>()
+            .map_err(|e| IOError::InvalidConfig(format!("invalid address {} : {}", spec, e)))?;
+        let host = match uri.host() {
+            Some(host) if !host.is_empty() => host,
+            _ => {
+                return Err(IOError::InvalidConfig(format!(
+                    "invalid address {} : host is missing",
+                    spec
+                )));
+            }
+        };
+        let port = match (uri.port_u16(), uri.scheme_str()) {
+            (Some(port), _) => port,
+            (None, Some("ws")) | (None, Some("http")) => 80,
+            (None, Some("wss")) | (None, Some("https")) => 443,
+            (None, _) => {
+                return Err(IOError::InvalidConfig(format!(
+                    "invalid address {} : port is missing",
+                    spec
+                )));
+            }
+        };
+        Ok(format!("{}:{}", host, port))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn redact_masks_secrets() {
+            assert_eq!(
+                redact("kx:fw?psk=c2VjcmV0&peer=cHVibGlj"),
+                "kx:fw?psk=***&peer=cHVibGlj"
+            );
+            assert_eq!(redact("kx:bw?key=c2VjcmV0"), "kx:bw?key=***");
+            assert_eq!(redact("hmac:fw-secret"), "hmac:fw-***");
+            assert_eq!(redact("hmac:bw-se?cr&et"), "hmac:bw-***");
+            assert_eq!(
+                redact("tcp://127.0.0.1:80?reconnect=0"),
+                "tcp://127.0.0.1:80?reconnect=0"
+            );
+        }
+    }
+}
@@ -1,7 +1,7 @@
 pub mod io_entry {
     use crate::{
         pipeline_module::pipeline::{PipelineDirection, PipelineStep},
-        BoxedClone, Entry, IOError, Pipeline,
+        BoxedClone, Entry, EntryControl, IOError, Pipeline,
     };
     use std::{
         io::{stdin, stdout, Read, Write},
@@ -12,6 +12,7 @@ pub mod io_entry {
 
     pub struct STDioEntry {
         pipeline: Pipeline,
+        control: EntryControl,
         loop_time: u64,
     }
 
@@ -28,16 +29,21 @@ pub mod io_entry {
             }
         }
 
-        fn new(config: String, pipeline: crate::Pipeline, loop_time: u64) -> Self {
-            STDioEntry {
+        fn new(config: String, pipeline: crate::Pipeline, loop_time: u64) -> Result<Self, IOError> {
+            Ok(STDioEntry {
+                control: EntryControl::new(pipeline.clone()),
                 pipeline: pipeline,
                 loop_time: loop_time,
-            }
+            })
         }
 
         fn listen(&mut self) {
-            self.pipeline.start();
-            loop {
+            let _guard = self.control.connection();
+            if let Err(e) = self.pipeline.start() {
+                println!("{}", e);
+                return;
+            }
+            while !self.control.is_closing() {
                 let len = STDioEntry::len(&mut std::io::stdin()).unwrap();
                 if len > 0 {
                     let mut buf: Vec<u8> = vec![0; len];
@@ -52,6 +58,8 @@ pub mod io_entry {
                             | IOError::ParseError
                             | IOError::InvalidStep(_)
                             | IOError::InvalidData(_)
+                            | IOError::InvalidConfig(_)
+                            | IOError::Context { .. }
                             | IOError::Error(_) => {
                                 println!("{}", e);
                                 break;
@@ -77,6 +85,8 @@ pub mod io_entry {
                             | IOError::ParseError
                             | IOError::InvalidStep(_)
                             | IOError::InvalidData(_)
+                            | IOError::InvalidConfig(_)
+                            | IOError::Context { .. }
                             | IOError::Error(_) => {
                                 println!("{}", e);
                                 break;
@@ -87,13 +97,19 @@ pub mod io_entry {
                 }
                 thread::sleep(Duration::from_millis(self.loop_time));
             }
+            self.pipeline.close();
+        }
+
+        fn control(&self) -> EntryControl {
+            self.control.clone()
         }
     }
 
     impl Clone for STDioEntry {
         fn clone(&self) -> STDioEntry {
             STDioEntry {
-                pipeline: self.pipeline.clone(),
+                pipeline: self.control.pipeline(),
+                control: self.control.clone(),
                 loop_time: self.loop_time,
             }
         }
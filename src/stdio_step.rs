@@ -22,7 +22,9 @@ pub mod io_step {
         #[allow(unused_variables)]
         fn set_pipeline_direction(&mut self, direction: PipelineDirection) {}
 
-        fn start(&mut self) {}
+        fn start(&mut self) -> Result<(), IOError> {
+            Ok(())
+        }
     }
 
     impl BoxedClone for STDioStep {
@@ -10,14 +10,14 @@ pub mod tcp_entry {
         thread,
         time::Duration,
     };
-    use tungstenite::http::Uri;
 
-    use crate::{Entry, Pipeline};
+    use crate::{socket_address, Entry, EntryControl, IOError, Pipeline};
 
     pub struct TCPEntry {
         tcp_server: TcpListener,
         address: String,
         pipeline: Pipeline,
+        control: EntryControl,
         loop_time: u64,
     }
 
@@ -26,35 +26,35 @@ pub mod tcp_entry {
             Self {
                 tcp_server: self.tcp_server.try_clone().unwrap(),
                 address: self.address.clone(),
-                pipeline: self.pipeline.clone(),
+                pipeline: self.control.pipeline(),
+                control: self.control.clone(),
                 loop_time: self.loop_time
             }
         }
     }
 
     impl Entry for TCPEntry {
-        fn new(config: String, pipeline: crate::Pipeline, loop_time: u64) -> Self {
+        fn new(config: String, pipeline: crate::Pipeline, loop_time: u64) -> Result<Self, IOError> {
             let re = Regex::new(r"((https|wss|ws|http)?:\/\/)([^:/$]{1,})(?::(\d{1,}))").unwrap();
             if !re.is_match(&config) {
-                panic!(
-                    "unsupported config : {}. use with this format ws://host:port ",
+                return Err(IOError::InvalidConfig(format!(
+                    "unsupported entry : {}. use with this format ws://host:port",
                     config
-                )
+                )));
             }
 
-            let uri: Uri = config.parse::<Uri>().unwrap();
-            let mut addr = String::from(uri.host().unwrap());
-            addr.push_str(":");
-            addr.push_str(uri.port().unwrap().as_str());
-            let server = TcpListener::bind(addr.clone()).unwrap();
+            let addr = socket_address(&config)?;
+            let server = TcpListener::bind(&addr)
+                .map_err(|e| IOError::from(e).context("tcp entry", &addr))?;
             // server.set_nonblocking(true).expect("Cannot set non-blocking");
 
-            TCPEntry {
+            Ok(TCPEntry {
                 tcp_server: server,
                 address: config,
+                control: EntryControl::new(pipeline.clone()),
                 pipeline: pipeline,
                 loop_time: loop_time
-            }
+            })
         }
 
         fn len(stream: &mut dyn AsRawFd) -> std::io::Result<usize> {
@@ -71,6 +71,9 @@ pub mod tcp_entry {
 
         fn listen(&mut self) {
             for conn in self.tcp_server.incoming() {
+                if self.control.is_stopped() {
+                    break;
+                }
                 match conn {
                     Ok(conn) => {
                         println!("new client : {}", conn.peer_addr().unwrap());
@@ -87,6 +90,10 @@ pub mod tcp_entry {
                 }
             }
         }
+
+        fn control(&self) -> EntryControl {
+            self.control.clone()
+        }
     }
 
     impl TCPEntry {
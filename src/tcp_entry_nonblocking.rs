@@ -1,6 +1,8 @@
 pub mod tcp_entry_nonblocking {
-    use crate::{Entry, IOError, Pipeline};
-    use http::Uri;
+    use crate::{
+        shutdown_listener, socket_address, AccessList, ConnectionLimiter, Entry, EntryControl,
+        IOError, LimitPolicy, Pipeline, RateLimiter, RatePolicy, SpecOptions, Transfer,
+    };
     use polling::{Event, Events, Poller};
     use regex::Regex;
     use std::collections::HashMap;
@@ -14,40 +16,53 @@ pub mod tcp_entry_nonblocking {
         poller: Poller,
         listener: TcpListener,
         listener_key: usize,
+        /// Key of the last client accepted.
+        next_key: usize,
         connections: HashMap<usize, (TcpStream, SocketAddr)>,
         pipeline: Pipeline,
+        control: EntryControl,
         loop_time: u64,
+        rates: RateLimiter,
+        limits: ConnectionLimiter,
+        access: AccessList,
     }
 
     impl Entry for TcpEntryNonBlocking {
-        fn new(config: String, pipeline: Pipeline, loop_time: u64) -> Self {
+        fn new(config: String, pipeline: Pipeline, loop_time: u64) -> Result<Self, IOError> {
             let re = Regex::new(r"((https|wss|ws|http)?:\/\/)([^:/$]{1,})(?::(\d{1,}))").unwrap();
             if !re.is_match(&config) {
-                panic!(
-                    "unsupported config : {}. use with this format ws://host:port ",
+                return Err(IOError::InvalidConfig(format!(
+                    "unsupported entry : {}. use with this format ws://host:port",
                     config
-                )
+                )));
             }
 
-            let uri: Uri = config.parse::<Uri>().unwrap();
-            let mut addr = String::from(uri.host().unwrap());
-            addr.push_str(":");
-            addr.push_str(uri.port().unwrap().as_str());
-            let mut listener = TcpListener::bind(addr).unwrap();
-            let poller = Poller::new().unwrap();
+            let options = SpecOptions::parse(&config);
+            let rates = RatePolicy::from_options(&options)?;
+            let limits = LimitPolicy::from_options(&options)?;
+            let access = AccessList::from_options(&options)?;
+            let addr = socket_address(&config)?;
+            let listener = TcpListener::bind(&addr)
+                .map_err(|e| IOError::from(e).context("tcp entry", &addr))?;
+            let poller = Poller::new()?;
 
             unsafe {
-                poller.add(&listener, Event::readable(1)).unwrap();
+                poller.add(&listener, Event::readable(1))?;
             }
 
-            TcpEntryNonBlocking {
+            Ok(TcpEntryNonBlocking {
                 poller,
                 listener,
                 listener_key: 1,
+                next_key: 1,
                 connections: HashMap::new(),
+                control: EntryControl::new(pipeline.clone()),
                 pipeline: pipeline,
                 loop_time,
-            }
+                rates: RateLimiter::new(rates),
+                limits: ConnectionLimiter::new(limits),
+                access,
+            })
         }
 
         fn len(stream: &mut dyn AsRawFd) -> std::io::Result<usize> {
@@ -65,20 +80,63 @@ pub mod tcp_entry_nonblocking {
         fn listen(&mut self) {
             let mut events = Events::new();
 
-            loop {
-                self.poller.wait(&mut events, None).unwrap();
+            while !self.control.is_stopped() {
+                events.clear();
+                self.poller
+                    .wait(&mut events, Some(Duration::from_millis(self.loop_time)))
+                    .unwrap();
 
                 for ev in events.iter() {
                     if ev.key == self.listener_key {
-                        let (client, client_address) = self.listener.accept().unwrap();
-                        let client_key = self.connections.len() + self.listener_key + 1;
+                        let accepted = self.listener.accept();
+                        self.poller
+                            .modify(&self.listener, Event::readable(self.listener_key))
+                            .unwrap();
+                        let (client, client_address) = match accepted {
+                            Ok(connection) => connection,
+                            Err(e) => {
+                                println!("accept failed : {}", e);
+                                continue;
+                            }
+                        };
+                        if !self.access.permits(client_address.ip()) {
+                            println!(
+                                "client denied, address : {} ({} rejected so far)",
+                                client_address,
+                                self.control.reject()
+                            );
+                            let _ = client.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                        let permit = match self.limits.admit(client_address.ip()) {
+                            Ok(permit) => permit,
+                            Err(reason) => {
+                                println!(
+                                    "client rejected, address : {}, {} ({} so far)",
+                                    client_address,
+                                    reason,
+                                    self.control.reject()
+                                );
+                                let _ = client.shutdown(Shutdown::Both);
+                                continue;
+                            }
+                        };
+                        self.next_key += 1;
+                        let client_key = self.next_key;
 
                         self.connections
                             .insert(client_key, (client, client_address));
                         let mut cloned_self = self.clone();
+                        // the clone has its own handle, the accept loop keeps none
+                        self.connections.remove(&client_key);
+                        let guard = self.control.connection();
 
                         thread::spawn(move || {
-                            if let Err(e) = cloned_self.handle_connection(client_key) {
+                            let _guard = guard;
+                            let _permit = permit;
+                            let result = cloned_self.handle_connection(client_key);
+                            cloned_self.shutdown_client(client_key);
+                            if let Err(e) = result {
                                 match e {
                                     IOError::InvalidConnection
                                     | IOError::InvalidBindAddress
@@ -87,6 +145,8 @@ pub mod tcp_entry_nonblocking {
                                     | IOError::ParseError
                                     | IOError::InvalidStep(_)
                                     | IOError::InvalidData(_)
+                                    | IOError::InvalidConfig(_)
+                                    | IOError::Context { .. }
                                     | IOError::Error(_) => {
                                         println!("{}", e);
                                         return;
@@ -95,13 +155,16 @@ pub mod tcp_entry_nonblocking {
                                 }
                             }
                         });
-
-                        self.poller
-                            .modify(&self.listener, Event::readable(self.listener_key))
-                            .unwrap();
                     }
                 }
             }
+
+            self.poller.delete(&self.listener).unwrap();
+            shutdown_listener(&self.listener);
+        }
+
+        fn control(&self) -> EntryControl {
+            self.control.clone()
         }
     }
 
@@ -115,16 +178,29 @@ pub mod tcp_entry_nonblocking {
                 poller: Poller::new().unwrap(),
                 listener: self.listener.try_clone().unwrap(),
                 listener_key: self.listener_key.clone(),
+                next_key: self.next_key,
                 connections: connections,
-                pipeline: self.pipeline.clone(),
+                pipeline: self.control.pipeline(),
+                control: self.control.clone(),
                 loop_time: self.loop_time,
+                rates: self.rates.clone(),
+                limits: self.limits.clone(),
+                access: self.access.clone(),
             }
         }
     }
 
     impl TcpEntryNonBlocking {
+        /// Ends the connection even while other handles on the stream are
+        /// still around.
+        fn shutdown_client(&self, client_key: usize) {
+            if let Some(client) = self.connections.get(&client_key) {
+                let _ = client.0.shutdown(Shutdown::Both);
+            }
+        }
+
         fn handle_connection(&mut self, client_key: usize) -> Result<(), IOError> {
-            self.pipeline.start();
+            self.pipeline.start()?;
 
             let client = self.connections.get_mut(&client_key).unwrap();
             client.0.set_nonblocking(true)?;
@@ -139,21 +215,30 @@ pub mod tcp_entry_nonblocking {
             }
             let mut events = Events::new();
             let mut is_connected = true;
+            let mut rate = self.rates.client();
+            // read from the pipeline and over the download rate so far
+            let mut held: Vec<u8> = Vec::new();
 
             loop {
                 thread::sleep(Duration::from_millis(10));
+                if self.control.is_closing() {
+                    break;
+                }
                 self.poller.wait(&mut events, None)?;
 
                 for ev in events.iter() {
                     if ev.key == client_key {
-                        if ev.readable {
+                        // over the rate, what the client sends waits in the socket
+                        let allowance = rate.allowance(Transfer::Upload);
+                        if ev.readable && allowance > 0 {
                             match TcpEntryNonBlocking::len(&mut client.0) {
                                 Ok(len) => {
                                     if len > 0 {
-                                        let mut buf = vec![0; len];
+                                        let mut buf = vec![0; len.min(allowance)];
                                         match client.0.read_exact(&mut buf) {
                                             Ok(_) => {
                                                 let len = buf.len();
+                                                rate.take(Transfer::Upload, len);
                                                 match self.pipeline.write(buf) {
                                                     Ok(size) => {}
                                                     Err(e) => match e {
@@ -164,6 +249,8 @@ pub mod tcp_entry_nonblocking {
                                                         | IOError::ParseError
                                                         | IOError::InvalidStep(_)
                                                         | IOError::InvalidData(_)
+                                                        | IOError::InvalidConfig(_)
+                                                        | IOError::Context { .. }
                                                         | IOError::Error(_) => {
                                                             return Err(e);
                                                         }
@@ -192,23 +279,9 @@ pub mod tcp_entry_nonblocking {
                         }
 
                         if ev.writable {
-                            if self.pipeline.read_available() {
+                            if held.is_empty() && self.pipeline.read_available() {
                                 match self.pipeline.read() {
-                                    Ok(data) => {
-                                        if !data.is_empty() {
-                                            if let Err(e) = client.0.write(&data) {
-                                                println!("Error writing to stream: {}", e);
-                                                is_connected = false;
-                                                break;
-                                            }
-
-                                            if let Err(e) = client.0.flush() {
-                                                println!("Error flush stream: {}", e);
-                                                is_connected = false;
-                                                break;
-                                            }
-                                        }
-                                    }
+                                    Ok(data) => held = data,
                                     Err(e) => match e {
                                         IOError::InvalidConnection
                                         | IOError::InvalidBindAddress
@@ -217,6 +290,8 @@ pub mod tcp_entry_nonblocking {
                                         | IOError::ParseError
                                         | IOError::InvalidStep(_)
                                         | IOError::InvalidData(_)
+                                        | IOError::InvalidConfig(_)
+                                        | IOError::Context { .. }
                                         | IOError::Error(_) => {
                                             return Err(e);
                                         }
@@ -224,6 +299,26 @@ pub mod tcp_entry_nonblocking {
                                     },
                                 }
                             }
+
+                            let allowance = rate.allowance(Transfer::Download);
+                            if !held.is_empty() && allowance > 0 {
+                                let data = match held.len() > allowance {
+                                    true => held.drain(..allowance).collect(),
+                                    false => std::mem::take(&mut held),
+                                };
+                                rate.take(Transfer::Download, data.len());
+                                if let Err(e) = client.0.write(&data) {
+                                    println!("Error writing to stream: {}", e);
+                                    is_connected = false;
+                                    break;
+                                }
+
+                                if let Err(e) = client.0.flush() {
+                                    println!("Error flush stream: {}", e);
+                                    is_connected = false;
+                                    break;
+                                }
+                            }
                         } else {
                             // is_connected = false;
                             // break;
@@ -237,6 +332,7 @@ pub mod tcp_entry_nonblocking {
 
                 self.poller.modify(&client.0, Event::all(client_key))?;
             }
+            self.pipeline.close();
             client.0.shutdown(Shutdown::Both)?;
             println!(
                 "client disconnected, key : {}, address : {} ",
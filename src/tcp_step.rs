@@ -1,24 +1,40 @@
 #[allow(non_snake_case, unused_variables, dead_code)]
 pub mod tcp_step {
-    use std::io::{Read, Write};
-    use std::net::TcpStream;
+    use std::io::{self, Read, Write};
+    use std::net::{Shutdown, TcpStream};
     use std::os::fd::AsRawFd;
     use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::Duration;
     use tungstenite::client::IntoClientRequest;
-    use tungstenite::http::{Request, Uri};
+    use tungstenite::http::Request;
     use tungstenite::protocol::{Role, WebSocketContext};
     use tungstenite::{client, Message, WebSocket};
 
     use crate::pipeline_module::pipeline::{IOError, PipelineDirection, PipelineStep};
-    use crate::{BoxedClone,};
+    use crate::{
+        connect_timeout, peer_closed, BoxedClone, HealthCheck, ProbeKind, Reconnect,
+        ReconnectPolicy, SpecOptions, UpstreamGuard, Upstreams,
+    };
 
     pub struct TCPStep {
         tcp_stream: Option<TcpStream>,
         address: String,
+        socket_address: String,
+        upstreams: Arc<Upstreams>,
+        upstream: Option<UpstreamGuard>,
+        reconnect: Option<Reconnect>,
     }
 
     impl PipelineStep for TCPStep {
         fn len(&mut self) -> std::io::Result<usize> {
+            if self.reconnect.is_some() {
+                self.poll_connection()?;
+                if self.tcp_stream.is_none() {
+                    return Ok(0);
+                }
+            }
+
             let mut available: usize = 0;
             let result: i32 = unsafe {
                 libc::ioctl(
@@ -39,29 +55,58 @@ pub mod tcp_step {
             // println!("{}", direction);
         }
 
-        fn start(&mut self) {
-            let mut connection: Option<TcpStream> = None;
+        fn start(&mut self) -> Result<(), IOError> {
+            let (connection, upstream) = TCPStep::connect(&self.upstreams, self.connect_timeout())?;
 
-            let uri: Uri = self.address.parse::<Uri>().unwrap();
-            let mut addr = String::from(uri.host().unwrap());
-            let port = uri.port().unwrap().as_u16();
+            self.socket_address = upstream.address().to_string();
+            self.upstream = Some(upstream);
+            self.tcp_stream = Some(connection);
+            Ok(())
+        }
 
-            addr.push_str(":");
-            addr.push_str(port.to_string().as_str());
-            let connection = TcpStream::connect(addr).unwrap();
+        fn close(&mut self) {
+            if let Some(stream) = self.tcp_stream.take() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            self.upstream = None;
+        }
 
-            self.tcp_stream = Some(connection);
+        fn take_reconnected(&mut self) -> bool {
+            match self.reconnect.as_mut() {
+                Some(reconnect) => reconnect.take_reconnected(),
+                None => false,
+            }
+        }
+
+        fn set_replay(&mut self, replay: bool) {
+            if let Some(reconnect) = self.reconnect.as_mut() {
+                reconnect.set_replay(replay);
+            }
         }
     }
 
     impl BoxedClone for TCPStep {
         fn bclone(&self) -> Box<dyn PipelineStep> {
-            Box::new(TCPStep::new(&self.address))
+            Box::new(TCPStep {
+                tcp_stream: None,
+                address: self.address.clone(),
+                socket_address: self.socket_address.clone(),
+                upstreams: self.upstreams.clone(),
+                upstream: None,
+                reconnect: self.reconnect.as_ref().map(|r| Reconnect::new(r.policy())),
+            })
         }
     }
 
     impl crate::Read for TCPStep {
         fn read(&mut self) -> Result<Vec<u8>, IOError> {
+            if self.reconnect.is_some() {
+                self.poll_connection()?;
+                if self.tcp_stream.is_none() {
+                    return Err(IOError::EmptyData);
+                }
+            }
+
             let mut available: usize = 0;
             let result: i32 = unsafe {
                 libc::ioctl(
@@ -86,21 +131,110 @@ pub mod tcp_step {
 
     impl Write for TCPStep {
         fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-            self.get_stream().write(buf)
+            if self.reconnect.is_none() {
+                return self.get_stream().write(buf);
+            }
+
+            self.poll_connection()?;
+            let reconnect = self.reconnect.as_mut().unwrap();
+            if reconnect.is_stale() {
+                return Ok(reconnect.drop_stale(&self.socket_address, buf));
+            }
+            if let Some(stream) = self.tcp_stream.as_mut() {
+                match stream.write_all(buf) {
+                    Ok(()) => return Ok(buf.len()),
+                    Err(e) => {
+                        self.tcp_stream = None;
+                        self.upstream = None;
+                        reconnect.disconnected(&self.socket_address, &e.to_string());
+                    }
+                }
+            }
+            reconnect.buffer(buf)
         }
 
         fn flush(&mut self) -> std::io::Result<()> {
-            self.get_stream().flush()
+            match self.tcp_stream.as_mut() {
+                Some(stream) => stream.flush(),
+                None if self.reconnect.is_some() => Ok(()),
+                None => self.get_stream().flush(),
+            }
         }
     }
 
     #[allow(unreachable_code)]
     impl TCPStep {
-        pub fn new(address: &str) -> Self {
-            //handle errors
-            TCPStep {
+        pub fn new(address: &str) -> Result<Self, IOError> {
+            let options = SpecOptions::parse(address);
+            let upstreams = Upstreams::from_spec(address)?;
+            if let Some(check) = HealthCheck::from_options(&options)? {
+                if check.probe != ProbeKind::Tcp {
+                    return Err(IOError::InvalidConfig(
+                        "tcp step : only health=tcp probes are supported".to_string(),
+                    ));
+                }
+                upstreams.start_health_checks(check, |address, check| {
+                    connect_timeout(address, check.timeout).map(|_| ())
+                });
+            }
+            Ok(TCPStep {
                 tcp_stream: None,
                 address: String::from_str(address).unwrap(),
+                socket_address: upstreams.address(0).to_string(),
+                upstreams,
+                upstream: None,
+                reconnect: ReconnectPolicy::from_options(&options)?.map(Reconnect::new),
+            })
+        }
+
+        fn connect(
+            upstreams: &Arc<Upstreams>,
+            timeout: Option<Duration>,
+        ) -> Result<(TcpStream, UpstreamGuard), IOError> {
+            upstreams.connect("tcp step", |address| match timeout {
+                Some(timeout) => connect_timeout(address, timeout),
+                None => Ok(TcpStream::connect(address)?),
+            })
+        }
+
+        /// Reconnecting steps give up on silent upstreams, the others wait as
+        /// long as the system does.
+        fn connect_timeout(&self) -> Option<Duration> {
+            self.reconnect.as_ref().map(|r| r.policy().connect_timeout)
+        }
+
+        /// Notices when the upstream went away and, once the backoff delay is
+        /// over, connects again and replays what was written meanwhile.
+        fn poll_connection(&mut self) -> io::Result<()> {
+            let reconnect = match self.reconnect.as_mut() {
+                Some(reconnect) => reconnect,
+                None => return Ok(()),
+            };
+            reconnect.check()?;
+
+            if let Some(stream) = self.tcp_stream.as_ref() {
+                if !peer_closed(stream) {
+                    return Ok(());
+                }
+                self.tcp_stream = None;
+                self.upstream = None;
+                reconnect.disconnected(&self.socket_address, "closed by peer");
+            }
+            if !reconnect.should_attempt() {
+                return Ok(());
+            }
+
+            let timeout = Some(reconnect.policy().connect_timeout);
+            match TCPStep::connect(&self.upstreams, timeout) {
+                Ok((mut stream, upstream)) => {
+                    self.socket_address = upstream.address().to_string();
+                    let pending = reconnect.connected(&self.socket_address);
+                    stream.write_all(&pending)?;
+                    self.upstream = Some(upstream);
+                    self.tcp_stream = Some(stream);
+                    Ok(())
+                }
+                Err(e) => reconnect.failed(&self.socket_address, &e),
             }
         }
 
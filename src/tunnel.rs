@@ -0,0 +1,421 @@
+pub mod tunnel {
+    use std::{
+        collections::HashMap,
+        io,
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    };
+
+    use crate::config::config::{Config, TunnelConfig, DEFAULT_GRACE_PERIOD};
+    use crate::{
+        redact, Base64, CompressionStep, Entry, EntryControl, HmacStep, HttpEntryNonblocking,
+        HttpStep, IOError, KeyExchangeStep, Pipeline, PipelineStep, RSult, STDioEntry, STDioStep,
+        ShapeStep, TCPStep, TcpEntryNonBlocking, WSEntryNonBlocking, WebsocketDestination,
+        WssDestination,
+    };
+
+    /// How long closed connections get to send their goodbyes before exit.
+    const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+    pub struct Tunnel {
+        name: String,
+        control: EntryControl,
+        handle: JoinHandle<()>,
+    }
+
+    /// An entry of the tunnel bound to its address, not accepting yet.
+    enum BoundEntry {
+        Ws(WSEntryNonBlocking),
+        Stdio(STDioEntry),
+        Tcp(TcpEntryNonBlocking),
+        Http(HttpEntryNonblocking),
+    }
+
+    /// A tunnel whose listen address is taken but whose accept loop has not
+    /// started, so that a group of tunnels either all start or none does.
+    pub struct BoundTunnel {
+        name: String,
+        entry: BoundEntry,
+    }
+
+    impl BoundTunnel {
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        /// Runs the entry's accept loop on a dedicated thread.
+        pub fn run(self) -> Result<Tunnel, IOError> {
+            let name = self.name.as_str();
+            let (control, handle) = match self.entry {
+                BoundEntry::Ws(entry) => Tunnel::run(name, entry)?,
+                BoundEntry::Stdio(entry) => Tunnel::run(name, entry)?,
+                BoundEntry::Tcp(entry) => Tunnel::run(name, entry)?,
+                BoundEntry::Http(entry) => Tunnel::run(name, entry)?,
+            };
+            Ok(Tunnel {
+                name: self.name,
+                control,
+                handle,
+            })
+        }
+    }
+
+    impl Tunnel {
+        pub fn start(config: &TunnelConfig) -> Result<Tunnel, IOError> {
+            Tunnel::bind_config(config)?.run()
+        }
+
+        pub fn bind_config(config: &TunnelConfig) -> Result<BoundTunnel, IOError> {
+            Tunnel::bind(
+                &config.name,
+                config.entry.spec(),
+                &config.step_specs(),
+                config.buffer_size,
+                config.loop_time(),
+            )
+        }
+
+        /// Binds the tunnel and starts accepting right away.
+        pub fn spawn(
+            name: &str,
+            entry: String,
+            steps: &[String],
+            buffer_size: Option<usize>,
+            loop_time: u64,
+        ) -> Result<Tunnel, IOError> {
+            Tunnel::bind(name, entry, steps, buffer_size, loop_time)?.run()
+        }
+
+        /// Builds the pipeline template and the entry on the calling thread,
+        /// which binds its listen address without accepting anything yet.
+        pub fn bind(
+            name: &str,
+            entry: String,
+            steps: &[String],
+            buffer_size: Option<usize>,
+            loop_time: u64,
+        ) -> Result<BoundTunnel, IOError> {
+            let pipeline = create_pipeline(steps, buffer_size)?;
+            println!("tunnel {} : entry : {}", name, redact(&entry));
+
+            let protocol = entry.split(':').next().unwrap_or("");
+            let entry = match protocol {
+                "ws" => BoundEntry::Ws(WSEntryNonBlocking::new(entry, pipeline, loop_time)?),
+                "stdio" => BoundEntry::Stdio(STDioEntry::new(entry, pipeline, loop_time)?),
+                "tcp" => BoundEntry::Tcp(TcpEntryNonBlocking::new(entry, pipeline, loop_time)?),
+                "http" => BoundEntry::Http(HttpEntryNonblocking::new(entry, pipeline, loop_time)?),
+                _ => {
+                    return Err(IOError::InvalidConfig(format!("unknown entry : {}", entry)));
+                }
+            };
+
+            Ok(BoundTunnel {
+                name: name.to_string(),
+                entry,
+            })
+        }
+
+        fn run<E: Entry + Send + 'static>(
+            name: &str,
+            mut entry: E,
+        ) -> io::Result<(EntryControl, JoinHandle<()>)> {
+            let control = entry.control();
+            let handle = thread::Builder::new()
+                .name(name.to_string())
+                .spawn(move || entry.listen())?;
+            Ok((control, handle))
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        /// Replaces the pipeline template; only connections accepted from now
+        /// on use the new steps.
+        pub fn set_pipeline(&self, pipeline: Pipeline) {
+            self.control.set_pipeline(pipeline);
+        }
+
+        pub fn stop(&self) {
+            self.control.stop();
+        }
+
+        pub fn close(&self) {
+            self.control.close();
+        }
+
+        pub fn active_connections(&self) -> usize {
+            self.control.active_connections()
+        }
+
+        pub fn rejected_connections(&self) -> usize {
+            self.control.rejected_connections()
+        }
+
+        /// True while the entry still owns its listen address.
+        pub fn is_listening(&self) -> bool {
+            !self.handle.is_finished()
+        }
+
+        pub fn is_drained(&self) -> bool {
+            !self.is_listening() && self.control.active_connections() == 0
+        }
+
+        pub fn join(self) {
+            if self.handle.join().is_err() {
+                println!("tunnel {} : stopped unexpectedly", self.name);
+            }
+        }
+    }
+
+    /// A tunnel of the config bound to its address, about to start.
+    type Bound = (TunnelConfig, BoundTunnel);
+
+    /// The tunnels of a config file, kept in sync with it across reloads.
+    pub struct TunnelSet {
+        running: HashMap<String, (TunnelConfig, Tunnel)>,
+        draining: Vec<(TunnelConfig, Tunnel, Instant)>,
+        pending: Vec<TunnelConfig>,
+        grace_period: Duration,
+    }
+
+    impl Default for TunnelSet {
+        fn default() -> Self {
+            TunnelSet::new()
+        }
+    }
+
+    impl TunnelSet {
+        pub fn new() -> Self {
+            TunnelSet {
+                running: HashMap::new(),
+                draining: Vec::new(),
+                pending: Vec::new(),
+                grace_period: Duration::from_secs(DEFAULT_GRACE_PERIOD),
+            }
+        }
+
+        /// Diffs `config` against the running tunnels. Removed tunnels stop
+        /// accepting and drain, tunnels whose entry is unchanged get the new
+        /// pipeline template in place, and the rest are (re)started as soon as
+        /// their listen address is free. Pipelines are built and free
+        /// addresses bound before anything changes, so a config that fails
+        /// leaves the set as it was.
+        pub fn apply(&mut self, config: &Config) -> Result<(), IOError> {
+            config.validate()?;
+
+            let mut reloaded = Vec::new();
+            let mut stopped = Vec::new();
+            for (name, (old_config, _)) in self.running.iter() {
+                match config.tunnels.iter().find(|t| &t.name == name) {
+                    Some(new_config) if new_config == old_config => {}
+                    Some(new_config)
+                        if new_config.entry == old_config.entry
+                            && new_config.loop_time == old_config.loop_time =>
+                    {
+                        let pipeline =
+                            create_pipeline(&new_config.step_specs(), new_config.buffer_size)?;
+                        reloaded.push((new_config.clone(), pipeline));
+                    }
+                    _ => stopped.push(name.clone()),
+                }
+            }
+
+            let starting: Vec<TunnelConfig> = config
+                .tunnels
+                .iter()
+                .filter(|t| !self.running.contains_key(&t.name) || stopped.contains(&t.name))
+                .cloned()
+                .collect();
+            let released: Vec<String> = stopped
+                .iter()
+                .map(|name| self.running[name].0.entry.address().to_string())
+                .collect();
+            let (bound, pending) = self.bind_free(starting, &released)?;
+
+            self.grace_period = config.grace_period();
+            for (new_config, pipeline) in reloaded {
+                let (old_config, tunnel) = self.running.get_mut(&new_config.name).unwrap();
+                tunnel.set_pipeline(pipeline);
+                *old_config = new_config;
+                println!("tunnel {} : pipeline reloaded", tunnel.name());
+            }
+            for name in stopped {
+                let (old_config, tunnel) = self.running.remove(&name).unwrap();
+                tunnel.stop();
+                println!("tunnel {} : stopped, draining connections", name);
+                let deadline = Instant::now() + self.grace_period;
+                self.draining.push((old_config, tunnel, deadline));
+            }
+            self.pending = pending;
+            self.run_bound(bound)
+        }
+
+        /// Joins tunnels that finished draining, closes the ones whose grace
+        /// period is over and starts the ones that were waiting for their
+        /// address to be released.
+        pub fn reap(&mut self) -> Result<(), IOError> {
+            let mut index = 0;
+            while index < self.draining.len() {
+                let (_, tunnel, deadline) = &self.draining[index];
+                if tunnel.is_drained() {
+                    let (_, tunnel, _) = self.draining.remove(index);
+                    println!("tunnel {} : drained", tunnel.name());
+                    tunnel.join();
+                    continue;
+                }
+                if Instant::now() >= *deadline && !tunnel.control.is_closing() {
+                    println!(
+                        "tunnel {} : grace period is over, closing {} connections",
+                        tunnel.name(),
+                        tunnel.active_connections()
+                    );
+                    tunnel.close();
+                }
+                index += 1;
+            }
+            self.start_pending()
+        }
+
+        /// Stops every tunnel for good, see [`shutdown`].
+        pub fn shutdown(&mut self) {
+            self.pending.clear();
+            let tunnels: Vec<&Tunnel> = self
+                .running
+                .values()
+                .map(|(_, tunnel)| tunnel)
+                .chain(self.draining.iter().map(|(_, tunnel, _)| tunnel))
+                .collect();
+            shutdown(&tunnels, self.grace_period);
+        }
+
+        fn start_pending(&mut self) -> Result<(), IOError> {
+            let pending = std::mem::take(&mut self.pending);
+            let (bound, pending) = self.bind_free(pending, &[])?;
+            self.pending = pending;
+            self.run_bound(bound)
+        }
+
+        /// Binds every tunnel of `configs` whose address is free before any
+        /// of them accepts, so that they all start or, when one cannot be
+        /// bound, none does. Tunnels waiting on an address still held by a
+        /// draining tunnel, or by one of `released` about to be stopped, are
+        /// handed back to be started later.
+        fn bind_free(
+            &self,
+            configs: Vec<TunnelConfig>,
+            released: &[String],
+        ) -> Result<(Vec<Bound>, Vec<TunnelConfig>), IOError> {
+            let mut bound = Vec::new();
+            let mut waiting = Vec::new();
+            for tunnel_config in configs {
+                let address = tunnel_config.entry.address();
+                let busy = released.iter().any(|r| r == address)
+                    || self
+                        .draining
+                        .iter()
+                        .any(|(c, t, _)| c.entry.address() == address && t.is_listening());
+                if busy {
+                    waiting.push(tunnel_config);
+                    continue;
+                }
+
+                match Tunnel::bind_config(&tunnel_config) {
+                    Ok(tunnel) => bound.push((tunnel_config, tunnel)),
+                    Err(e) => {
+                        println!("tunnel {} : {}", tunnel_config.name, e);
+                        let names: Vec<&str> = bound.iter().map(|(c, _)| c.name.as_str()).collect();
+                        if !names.is_empty() {
+                            println!("tunnels not started : {}", names.join(", "));
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            Ok((bound, waiting))
+        }
+
+        fn run_bound(&mut self, bound: Vec<Bound>) -> Result<(), IOError> {
+            for (tunnel_config, tunnel) in bound {
+                let tunnel = tunnel.run()?;
+                self.running
+                    .insert(tunnel_config.name.clone(), (tunnel_config, tunnel));
+            }
+            Ok(())
+        }
+    }
+
+    /// Stops the tunnels from accepting, lets their connections finish for up
+    /// to `grace_period` and then closes whatever is still open.
+    pub fn shutdown(tunnels: &[&Tunnel], grace_period: Duration) {
+        for tunnel in tunnels {
+            tunnel.stop();
+            println!(
+                "tunnel {} : stopped, draining {} connections, {} rejected",
+                tunnel.name(),
+                tunnel.active_connections(),
+                tunnel.rejected_connections()
+            );
+        }
+
+        if !wait_drained(tunnels, grace_period) {
+            for tunnel in tunnels {
+                if !tunnel.is_drained() {
+                    println!(
+                        "tunnel {} : grace period is over, closing {} connections",
+                        tunnel.name(),
+                        tunnel.active_connections()
+                    );
+                }
+                tunnel.close();
+            }
+            wait_drained(tunnels, CLOSE_TIMEOUT);
+        }
+    }
+
+    fn wait_drained(tunnels: &[&Tunnel], timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if tunnels.iter().all(|tunnel| tunnel.is_drained()) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    pub fn create_step(spec: &str) -> Result<Box<dyn PipelineStep>, IOError> {
+        let (protocol, config) = match spec.split_once(':') {
+            Some((protocol, config)) => (protocol, Some(config)),
+            None => (spec, None),
+        };
+        match protocol {
+            "stdio" => Ok(Box::new(STDioStep::new())),
+            "ws" => Ok(Box::new(WebsocketDestination::new(spec)?)),
+            "wss" => Ok(Box::new(WssDestination::new(spec)?)),
+            "b64" => Ok(Box::new(Base64::new(config)?)),
+            "tcp" => Ok(Box::new(TCPStep::new(spec)?)),
+            "salt" => Ok(Box::new(RSult::new(config)?)),
+            "zstd" | "deflate" | "lz4" => Ok(Box::new(CompressionStep::new(spec)?)),
+            "kx" => Ok(Box::new(KeyExchangeStep::new(spec)?)),
+            "hmac" => Ok(Box::new(HmacStep::new(config)?)),
+            "shape" => Ok(Box::new(ShapeStep::new(spec)?)),
+            "http" => Ok(Box::new(HttpStep::new(spec)?)),
+            _ => Err(IOError::InvalidStep(format!(
+                "unknown step : {}",
+                redact(spec)
+            ))),
+        }
+    }
+
+    pub fn create_pipeline(steps: &[String], buffer_size: Option<usize>) -> Result<Pipeline, IOError> {
+        let mut pipeline_steps: Vec<Box<dyn PipelineStep>> = Vec::new();
+        for step in steps {
+            println!("step : {}", redact(step));
+            pipeline_steps.push(create_step(step)?);
+        }
+        Ok(Pipeline::new(pipeline_steps, buffer_size))
+    }
+}
@@ -0,0 +1,325 @@
+pub mod upstream {
+    use serde::Deserialize;
+    use std::{
+        net::{TcpStream, ToSocketAddrs},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Weak,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use crate::{socket_address, split_upstreams, IOError, SpecOptions};
+
+    pub const DEFAULT_HEALTH_INTERVAL: u64 = 5_000;
+    pub const DEFAULT_HEALTH_TIMEOUT: u64 = 2_000;
+    pub const DEFAULT_HEALTH_RISE: u32 = 2;
+    pub const DEFAULT_HEALTH_FALL: u32 = 3;
+
+    /// How a destination step with several upstreams picks one for each new
+    /// connection, given as `?policy=` on the step.
+    #[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum BalancePolicy {
+        RoundRobin,
+        LeastConn,
+        Failover,
+    }
+
+    impl BalancePolicy {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                BalancePolicy::RoundRobin => "round-robin",
+                BalancePolicy::LeastConn => "least-conn",
+                BalancePolicy::Failover => "failover",
+            }
+        }
+
+        pub fn from_options(options: &SpecOptions) -> Result<BalancePolicy, IOError> {
+            match options.get_str("policy") {
+                None | Some("round-robin") => Ok(BalancePolicy::RoundRobin),
+                Some("least-conn") => Ok(BalancePolicy::LeastConn),
+                Some("failover") => Ok(BalancePolicy::Failover),
+                Some(policy) => Err(IOError::InvalidConfig(format!(
+                    "unknown policy : {}. use round-robin, least-conn or failover",
+                    policy
+                ))),
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ProbeKind {
+        /// The upstream accepts a TCP connection.
+        Tcp,
+        /// The upstream completes a WebSocket handshake.
+        Ws,
+    }
+
+    impl ProbeKind {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                ProbeKind::Tcp => "tcp",
+                ProbeKind::Ws => "ws",
+            }
+        }
+    }
+
+    /// Active health checks of the upstreams, read from
+    /// `?health=tcp|ws&health_interval=<ms>&health_timeout=<ms>`
+    /// `&health_rise=<n>&health_fall=<n>`. An upstream is taken out of
+    /// rotation after `fall` failed probes in a row and put back after `rise`
+    /// successful ones.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct HealthCheck {
+        pub probe: ProbeKind,
+        pub interval: Duration,
+        pub timeout: Duration,
+        pub rise: u32,
+        pub fall: u32,
+    }
+
+    impl HealthCheck {
+        /// `None` when the spec does not ask for health checks.
+        pub fn from_options(options: &SpecOptions) -> Result<Option<HealthCheck>, IOError> {
+            let probe = match options.get_str("health") {
+                None => return Ok(None),
+                Some("tcp") => ProbeKind::Tcp,
+                Some("ws") => ProbeKind::Ws,
+                Some(probe) => {
+                    return Err(IOError::InvalidConfig(format!(
+                        "unknown health probe : {}. use tcp or ws",
+                        probe
+                    )));
+                }
+            };
+            let interval = options
+                .get::<u64>("health_interval")?
+                .unwrap_or(DEFAULT_HEALTH_INTERVAL);
+            let timeout = options
+                .get::<u64>("health_timeout")?
+                .unwrap_or(DEFAULT_HEALTH_TIMEOUT);
+            let rise = options.get::<u32>("health_rise")?.unwrap_or(DEFAULT_HEALTH_RISE);
+            let fall = options.get::<u32>("health_fall")?.unwrap_or(DEFAULT_HEALTH_FALL);
+            if interval == 0 || timeout == 0 || rise == 0 || fall == 0 {
+                return Err(IOError::InvalidConfig(
+                    "health_interval, health_timeout, health_rise and health_fall must be greater than zero"
+                        .to_string(),
+                ));
+            }
+
+            Ok(Some(HealthCheck {
+                probe,
+                interval: Duration::from_millis(interval),
+                timeout: Duration::from_millis(timeout),
+                rise,
+                fall,
+            }))
+        }
+    }
+
+    /// The upstreams of a destination step, shared by every pipeline cloned
+    /// from the same template so the policy sees all their connections.
+    pub struct Upstreams {
+        addresses: Vec<String>,
+        policy: BalancePolicy,
+        next: AtomicUsize,
+        active: Vec<AtomicUsize>,
+        healthy: Vec<AtomicBool>,
+    }
+
+    impl Upstreams {
+        /// Parses `scheme://host:port[,host:port...][?policy=...]`.
+        pub fn from_spec(spec: &str) -> Result<Arc<Upstreams>, IOError> {
+            let mut addresses = Vec::new();
+            for upstream in split_upstreams(spec) {
+                addresses.push(socket_address(&upstream)?);
+            }
+            let policy = BalancePolicy::from_options(&SpecOptions::parse(spec))?;
+            Ok(Arc::new(Upstreams {
+                active: addresses.iter().map(|_| AtomicUsize::new(0)).collect(),
+                healthy: addresses.iter().map(|_| AtomicBool::new(true)).collect(),
+                addresses,
+                policy,
+                next: AtomicUsize::new(0),
+            }))
+        }
+
+        pub fn len(&self) -> usize {
+            self.addresses.len()
+        }
+
+        pub fn address(&self, index: usize) -> &str {
+            &self.addresses[index]
+        }
+
+        pub fn policy(&self) -> BalancePolicy {
+            self.policy
+        }
+
+        pub fn is_healthy(&self, index: usize) -> bool {
+            self.healthy[index].load(Ordering::SeqCst)
+        }
+
+        /// Upstream indices in the order they should be tried. Unhealthy
+        /// upstreams are left out, unless none is healthy.
+        pub fn candidates(&self) -> Vec<usize> {
+            let count = self.addresses.len();
+            let mut candidates: Vec<usize> = match self.policy {
+                BalancePolicy::Failover => (0..count).collect(),
+                BalancePolicy::RoundRobin => {
+                    let start = self.next.fetch_add(1, Ordering::SeqCst) % count;
+                    (0..count).map(|i| (start + i) % count).collect()
+                }
+                BalancePolicy::LeastConn => {
+                    let mut candidates: Vec<usize> = (0..count).collect();
+                    candidates.sort_by_key(|&i| self.active[i].load(Ordering::SeqCst));
+                    candidates
+                }
+            };
+            if candidates.iter().any(|&i| self.is_healthy(i)) {
+                candidates.retain(|&i| self.is_healthy(i));
+            }
+            candidates
+        }
+
+        /// Probes every upstream each `check.interval` on a background thread,
+        /// which ends once the step template and all its clones are gone.
+        pub fn start_health_checks<P>(self: &Arc<Self>, check: HealthCheck, probe: P)
+        where
+            P: Fn(&str, &HealthCheck) -> Result<(), IOError> + Send + 'static,
+        {
+            let upstreams: Weak<Upstreams> = Arc::downgrade(self);
+            let count = self.len();
+            let spawned = thread::Builder::new()
+                .name("health-check".to_string())
+                .spawn(move || {
+                    // consecutive successes and failures of each upstream
+                    let mut streaks = vec![(0u32, 0u32); count];
+                    loop {
+                        thread::sleep(check.interval);
+                        let upstreams = match upstreams.upgrade() {
+                            Some(upstreams) => upstreams,
+                            None => return,
+                        };
+                        for (index, streak) in streaks.iter_mut().enumerate() {
+                            let result = probe(upstreams.address(index), &check);
+                            upstreams.record_probe(index, &check, streak, result);
+                        }
+                    }
+                });
+            if let Err(e) = spawned {
+                println!("failed to start health checks : {}", e);
+            }
+        }
+
+        fn record_probe(
+            &self,
+            index: usize,
+            check: &HealthCheck,
+            streak: &mut (u32, u32),
+            result: Result<(), IOError>,
+        ) {
+            let address = self.address(index);
+            match result {
+                Ok(()) => {
+                    *streak = (streak.0.saturating_add(1), 0);
+                    if !self.is_healthy(index) && streak.0 >= check.rise {
+                        self.healthy[index].store(true, Ordering::SeqCst);
+                        println!(
+                            "upstream {} is up after {} successful {} probes",
+                            address,
+                            streak.0,
+                            check.probe.as_str()
+                        );
+                    }
+                }
+                Err(e) => {
+                    *streak = (0, streak.1.saturating_add(1));
+                    if self.is_healthy(index) && streak.1 >= check.fall {
+                        self.healthy[index].store(false, Ordering::SeqCst);
+                        println!(
+                            "upstream {} is down after {} failed {} probes : {}",
+                            address,
+                            streak.1,
+                            check.probe.as_str(),
+                            e
+                        );
+                    }
+                }
+            }
+        }
+
+        /// Connects to the first candidate that accepts, logging the ones
+        /// that did not. Fails with the last error once every upstream was
+        /// tried.
+        pub fn connect<T>(
+            self: &Arc<Self>,
+            step: &str,
+            mut connect: impl FnMut(&str) -> Result<T, IOError>,
+        ) -> Result<(T, UpstreamGuard), IOError> {
+            let mut last_error = None;
+            for index in self.candidates() {
+                let address = self.address(index);
+                match connect(address) {
+                    Ok(connection) => return Ok((connection, self.acquire(index))),
+                    Err(e) => {
+                        let e = e.context(step, address);
+                        if self.len() > 1 {
+                            println!("{}, trying next upstream", e);
+                        }
+                        last_error = Some(e);
+                    }
+                }
+            }
+            Err(last_error.unwrap_or(IOError::InvalidConnection))
+        }
+
+        fn acquire(self: &Arc<Self>, index: usize) -> UpstreamGuard {
+            self.active[index].fetch_add(1, Ordering::SeqCst);
+            UpstreamGuard {
+                upstreams: self.clone(),
+                index,
+            }
+        }
+    }
+
+    /// Connects to `address`, giving up after `timeout` for each of the
+    /// addresses it resolves to.
+    pub fn connect_timeout(address: &str, timeout: Duration) -> Result<TcpStream, IOError> {
+        let mut last_error = None;
+        for address in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match last_error {
+            Some(e) => Err(e.into()),
+            None => Err(IOError::InvalidConfig(format!(
+                "{} does not resolve to any address",
+                address
+            ))),
+        }
+    }
+
+    /// Counts a connection against its upstream for as long as it is alive.
+    pub struct UpstreamGuard {
+        upstreams: Arc<Upstreams>,
+        index: usize,
+    }
+
+    impl UpstreamGuard {
+        pub fn address(&self) -> &str {
+            self.upstreams.address(self.index)
+        }
+    }
+
+    impl Drop for UpstreamGuard {
+        fn drop(&mut self) {
+            self.upstreams.active[self.index].fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+pub mod websocket_deflate {
+    use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+    use http::{header::SEC_WEBSOCKET_EXTENSIONS, HeaderMap};
+
+    use crate::{IOError, SpecOptions};
+
+    pub const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+
+    /// Ends every compressed message before the sender strips it, see
+    /// RFC 7692 section 7.2.1.
+    const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+    /// Window of our compressor. It cannot be made smaller, so offers that
+    /// limit it are declined.
+    const WINDOW_BITS: u8 = 15;
+
+    /// permessage-deflate compression of websocket messages (RFC 7692),
+    /// `?deflate=true`, with `server_no_context_takeover=true` and
+    /// `client_no_context_takeover=true` to start each message of that side
+    /// from an empty window, trading ratio for memory. When the peer does not
+    /// take the extension, messages go uncompressed.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct DeflatePolicy {
+        pub server_no_context_takeover: bool,
+        pub client_no_context_takeover: bool,
+    }
+
+    /// What both ends of a connection agreed on.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct DeflateParams {
+        pub server_no_context_takeover: bool,
+        pub client_no_context_takeover: bool,
+    }
+
+    impl DeflatePolicy {
+        /// `None` unless the spec asks for compression.
+        pub fn from_options(options: &SpecOptions) -> Result<Option<DeflatePolicy>, IOError> {
+            if !options.get::<bool>("deflate")?.unwrap_or(false) {
+                return Ok(None);
+            }
+            Ok(Some(DeflatePolicy {
+                server_no_context_takeover: options
+                    .get::<bool>("server_no_context_takeover")?
+                    .unwrap_or(false),
+                client_no_context_takeover: options
+                    .get::<bool>("client_no_context_takeover")?
+                    .unwrap_or(false),
+            }))
+        }
+
+        /// The `Sec-WebSocket-Extensions` value a client offers.
+        pub fn offer(&self) -> String {
+            let mut offer = PERMESSAGE_DEFLATE.to_string();
+            if self.server_no_context_takeover {
+                offer.push_str("; server_no_context_takeover");
+            }
+            if self.client_no_context_takeover {
+                offer.push_str("; client_no_context_takeover");
+            }
+            offer
+        }
+
+        /// Picks the first offer of a client's `headers` we can take, with
+        /// the `Sec-WebSocket-Extensions` value to answer. `None` declines.
+        pub fn accept(&self, headers: &HeaderMap) -> Option<(DeflateParams, String)> {
+            let params = extensions(headers)
+                .into_iter()
+                .filter(|(name, _)| name == PERMESSAGE_DEFLATE)
+                .find_map(|(_, params)| self.accept_offer(&params))?;
+
+            let mut answer = PERMESSAGE_DEFLATE.to_string();
+            if params.server_no_context_takeover {
+                answer.push_str("; server_no_context_takeover");
+            }
+            if params.client_no_context_takeover {
+                answer.push_str("; client_no_context_takeover");
+            }
+            Some((params, answer))
+        }
+
+        fn accept_offer(&self, offer: &[(String, Option<String>)]) -> Option<DeflateParams> {
+            let mut params = DeflateParams {
+                server_no_context_takeover: self.server_no_context_takeover,
+                client_no_context_takeover: self.client_no_context_takeover,
+            };
+            for (key, value) in offer {
+                match (key.as_str(), value) {
+                    ("server_no_context_takeover", None) => {
+                        params.server_no_context_takeover = true
+                    }
+                    ("client_no_context_takeover", None) => {
+                        params.client_no_context_takeover = true
+                    }
+                    ("server_max_window_bits", Some(bits)) => {
+                        if window_bits(bits)? != WINDOW_BITS {
+                            return None;
+                        }
+                    }
+                    // a hint we may ignore, the client then keeps its window
+                    ("client_max_window_bits", None) => {}
+                    ("client_max_window_bits", Some(bits)) => {
+                        window_bits(bits)?;
+                    }
+                    _ => return None,
+                }
+            }
+            Some(params)
+        }
+
+        /// What the server agreed on in its answer `headers`, `None` when it
+        /// declined compression.
+        pub fn agreed(&self, headers: &HeaderMap) -> Result<Option<DeflateParams>, IOError> {
+            let mut agreed = None;
+            for (name, answer) in extensions(headers) {
+                if name != PERMESSAGE_DEFLATE || agreed.is_some() {
+                    return Err(IOError::InvalidData(format!(
+                        "handshake answer has an extension that was not offered : {}",
+                        name
+                    )));
+                }
+                let mut params = DeflateParams::default();
+                for (key, value) in answer {
+                    match (key.as_str(), value) {
+                        ("server_no_context_takeover", None) => {
+                            params.server_no_context_takeover = true
+                        }
+                        ("client_no_context_takeover", None) => {
+                            params.client_no_context_takeover = true
+                        }
+                        // only limits what the server sends, any window inflates
+                        ("server_max_window_bits", Some(bits)) if window_bits(&bits).is_some() => {}
+                        (key, _) => {
+                            return Err(IOError::InvalidData(format!(
+                                "handshake answer has an invalid {} parameter : {}",
+                                PERMESSAGE_DEFLATE, key
+                            )))
+                        }
+                    }
+                }
+                // a server taking the offer takes this too, RFC 7692 7.1.1.1,
+                // or it keeps a window our inflater would drop
+                if self.server_no_context_takeover && !params.server_no_context_takeover {
+                    return Err(IOError::InvalidData(format!(
+                        "handshake answer accepts {} without server_no_context_takeover",
+                        PERMESSAGE_DEFLATE
+                    )));
+                }
+                // a client may always start its messages from an empty window
+                params.client_no_context_takeover |= self.client_no_context_takeover;
+                agreed = Some(params);
+            }
+            Ok(agreed)
+        }
+    }
+
+    impl DeflateParams {
+        /// Compression of what the client sends, and decompression of what it
+        /// receives.
+        pub fn client(&self) -> (Deflater, Inflater) {
+            (
+                Deflater::new(self.client_no_context_takeover),
+                Inflater::new(self.server_no_context_takeover),
+            )
+        }
+
+        pub fn server(&self) -> (Deflater, Inflater) {
+            (
+                Deflater::new(self.server_no_context_takeover),
+                Inflater::new(self.client_no_context_takeover),
+            )
+        }
+    }
+
+    /// Compresses the messages one end sends.
+    pub struct Deflater {
+        compress: Compress,
+        no_context_takeover: bool,
+    }
+
+    impl Deflater {
+        pub fn new(no_context_takeover: bool) -> Self {
+            Deflater {
+                compress: Compress::new(Compression::default(), false),
+                no_context_takeover,
+            }
+        }
+
+        /// The payload of a compressed message holding `data`.
+        pub fn deflate(&mut self, data: &[u8]) -> Result<Vec<u8>, IOError> {
+            let mut output = Vec::with_capacity(data.len() / 2 + 64);
+            let start = self.compress.total_in();
+            loop {
+                let consumed = (self.compress.total_in() - start) as usize;
+                if output.len() == output.capacity() {
+                    output.reserve(data.len() / 2 + 64);
+                }
+                self.compress
+                    .compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)
+                    .map_err(|e| IOError::InvalidData(format!("deflate failed : {}", e)))?;
+                // done once the sync flush fit in what was left of the output
+                if (self.compress.total_in() - start) as usize == data.len()
+                    && output.len() < output.capacity()
+                {
+                    break;
+                }
+            }
+            if output.ends_with(&TAIL) {
+                output.truncate(output.len() - TAIL.len());
+            }
+            if self.no_context_takeover {
+                self.compress.reset();
+            }
+            Ok(output)
+        }
+    }
+
+    /// Decompresses the messages one end receives.
+    pub struct Inflater {
+        decompress: Decompress,
+        no_context_takeover: bool,
+    }
+
+    impl Inflater {
+        pub fn new(no_context_takeover: bool) -> Self {
+            Inflater {
+                decompress: Decompress::new(false),
+                no_context_takeover,
+            }
+        }
+
+        /// The data of the compressed message `payload`, failing once it
+        /// grows past `limit` bytes.
+        pub fn inflate(&mut self, payload: &[u8], limit: usize) -> Result<Vec<u8>, IOError> {
+            let mut input = Vec::with_capacity(payload.len() + TAIL.len());
+            input.extend_from_slice(payload);
+            input.extend_from_slice(&TAIL);
+
+            let mut output = Vec::with_capacity(payload.len() * 2 + 64);
+            let start = self.decompress.total_in();
+            loop {
+                let consumed = (self.decompress.total_in() - start) as usize;
+                let produced = output.len();
+                if output.len() == output.capacity() {
+                    if output.len() >= limit {
+                        return Err(IOError::InvalidData(format!(
+                            "inflated websocket message larger than {} bytes",
+                            limit
+                        )));
+                    }
+                    output.reserve(output.len().min(limit - output.len()).max(64));
+                }
+                let status = self
+                    .decompress
+                    .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                    .map_err(|e| IOError::InvalidData(format!("inflate failed : {}", e)))?;
+                let done = (self.decompress.total_in() - start) as usize == input.len()
+                    && output.len() < output.capacity();
+                if done || status == Status::StreamEnd {
+                    break;
+                }
+                let stuck = (self.decompress.total_in() - start) as usize == consumed
+                    && output.len() == produced;
+                if stuck {
+                    return Err(IOError::InvalidData(
+                        "inflate failed : truncated message".to_string(),
+                    ));
+                }
+            }
+            if self.no_context_takeover {
+                self.decompress.reset(false);
+            }
+            Ok(output)
+        }
+    }
+
+    /// An extension of `Sec-WebSocket-Extensions` and its parameters.
+    type Extension = (String, Vec<(String, Option<String>)>);
+
+    fn extensions(headers: &HeaderMap) -> Vec<Extension> {
+        headers
+            .get_all(SEC_WEBSOCKET_EXTENSIONS)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|extension| {
+                let mut parts = extension.split(';').map(|part| part.trim());
+                let name = parts.next().filter(|name| !name.is_empty())?;
+                let params = parts
+                    .filter(|part| !part.is_empty())
+                    .map(|part| match part.split_once('=') {
+                        Some((key, value)) => (
+                            key.trim().to_ascii_lowercase(),
+                            Some(value.trim().trim_matches('"').to_string()),
+                        ),
+                        None => (part.to_ascii_lowercase(), None),
+                    })
+                    .collect();
+                Some((name.to_ascii_lowercase(), params))
+            })
+            .collect()
+    }
+
+    fn window_bits(value: &str) -> Option<u8> {
+        value
+            .parse::<u8>()
+            .ok()
+            .filter(|bits| (8..=15).contains(bits))
+    }
+}
@@ -1,9 +1,13 @@
 pub mod websocket_entry_nonblocking {
     use crate::http_tools::http_tools;
-    use crate::{get_available_bytes, read_request, write_response, Entry, IOError, Pipeline};
+    use crate::{
+        accept_key, get_available_bytes, read_request, shutdown_listener, socket_address,
+        write_response, AccessList, ConnectionLimiter, DeflateParams, DeflatePolicy, Entry,
+        EntryControl, FrameReader, FrameWriter, IOError, Keepalive, KeepalivePolicy, LimitPolicy,
+        Pipeline, RateLimiter, RatePolicy, SpecOptions, Transfer,
+    };
     use bytes::{self, BytesMut};
-    use http::{response, Request, Response, Version};
-    use openssl::sha::Sha1;
+    use http::{header::SEC_WEBSOCKET_EXTENSIONS, response, Request, Response, Version};
     use polling::{Event, Events, Poller};
     use regex::Regex;
     use std::collections::HashMap;
@@ -13,9 +17,9 @@ pub mod websocket_entry_nonblocking {
     use std::str;
     use std::thread;
     use std::time::Duration;
-    use tokio_util::codec::{Decoder, Encoder};
+    use tokio_util::codec::Encoder;
     use tungstenite::handshake;
-    use tungstenite::{error::ProtocolError, http::Uri, Error};
+    use tungstenite::{error::ProtocolError, Error};
     use websocket_codec::{self, Message, MessageCodec};
 
     pub struct WSEntryNonBlocking {
@@ -23,42 +27,61 @@ pub mod websocket_entry_nonblocking {
         address: String,
         listener: TcpListener,
         listener_key: usize,
+        /// Key of the last client accepted.
+        next_key: usize,
         connections: HashMap<usize, (TcpStream, SocketAddr)>,
         pipeline: Pipeline,
+        control: EntryControl,
         loop_time: u64,
+        keepalive: Option<KeepalivePolicy>,
+        deflate: Option<DeflatePolicy>,
+        rates: RateLimiter,
+        limits: ConnectionLimiter,
+        access: AccessList,
     }
 
     impl Entry for WSEntryNonBlocking {
-        fn new(config: String, pipeline: Pipeline, loop_time: u64) -> Self {
+        fn new(config: String, pipeline: Pipeline, loop_time: u64) -> Result<Self, IOError> {
             let re = Regex::new(r"((https|wss|ws|http)?:\/\/)([^:/$]{1,})(?::(\d{1,}))").unwrap();
             if !re.is_match(&config) {
-                panic!(
-                    "unsupported config : {}. use with this format ws://host:port ",
+                return Err(IOError::InvalidConfig(format!(
+                    "unsupported entry : {}. use with this format ws://host:port",
                     config
-                )
+                )));
             }
 
-            let uri: Uri = config.parse::<Uri>().unwrap();
-            let mut addr = String::from(uri.host().unwrap());
-            addr.push_str(":");
-            addr.push_str(uri.port().unwrap().as_str());
-            let listener = TcpListener::bind(addr).unwrap();
-            listener.set_nonblocking(true).unwrap();
-            let poller = Poller::new().unwrap();
+            let options = SpecOptions::parse(&config);
+            let keepalive = KeepalivePolicy::from_options(&options)?;
+            let deflate = DeflatePolicy::from_options(&options)?;
+            let rates = RatePolicy::from_options(&options)?;
+            let limits = LimitPolicy::from_options(&options)?;
+            let access = AccessList::from_options(&options)?;
+            let addr = socket_address(&config)?;
+            let listener = TcpListener::bind(&addr)
+                .map_err(|e| IOError::from(e).context("ws entry", &addr))?;
+            listener.set_nonblocking(true)?;
+            let poller = Poller::new()?;
 
             unsafe {
-                poller.add(&listener, Event::readable(1)).unwrap();
+                poller.add(&listener, Event::readable(1))?;
             }
 
-            WSEntryNonBlocking {
+            Ok(WSEntryNonBlocking {
                 poller,
                 address: config,
                 listener,
                 listener_key: 1,
+                next_key: 1,
                 connections: HashMap::new(),
+                control: EntryControl::new(pipeline.clone()),
                 pipeline: pipeline,
                 loop_time,
-            }
+                keepalive,
+                deflate,
+                rates: RateLimiter::new(rates),
+                limits: ConnectionLimiter::new(limits),
+                access,
+            })
         }
 
         fn len(stream: &mut dyn AsRawFd) -> std::io::Result<usize> {
@@ -76,28 +99,77 @@ pub mod websocket_entry_nonblocking {
         fn listen(&mut self) {
             let mut events = Events::new();
 
-            loop {
-                self.poller.wait(&mut events, None).unwrap();
+            while !self.control.is_stopped() {
+                events.clear();
+                self.poller
+                    .wait(&mut events, Some(Duration::from_millis(self.loop_time)))
+                    .unwrap();
 
                 for ev in events.iter() {
                     if ev.key == self.listener_key {
-                        let (client, client_address) = self.listener.accept().unwrap();
-                        let client_key = self.connections.len() + self.listener_key + 1;
+                        let accepted = self.listener.accept();
+                        self.poller
+                            .modify(&self.listener, Event::readable(self.listener_key))
+                            .unwrap();
+                        let (client, client_address) = match accepted {
+                            Ok(connection) => connection,
+                            Err(e) => {
+                                println!("accept failed : {}", e);
+                                continue;
+                            }
+                        };
+                        if !self.access.permits(client_address.ip()) {
+                            println!(
+                                "client denied, address : {} ({} rejected so far)",
+                                client_address,
+                                self.control.reject()
+                            );
+                            let _ = client.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                        let permit = match self.limits.admit(client_address.ip()) {
+                            Ok(permit) => permit,
+                            Err(reason) => {
+                                println!(
+                                    "client rejected, address : {}, {} ({} so far)",
+                                    client_address,
+                                    reason,
+                                    self.control.reject()
+                                );
+                                WSEntryNonBlocking::write_service_unavailable(client);
+                                continue;
+                            }
+                        };
+                        self.next_key += 1;
+                        let client_key = self.next_key;
 
                         self.connections
                             .insert(client_key, (client, client_address));
                         let mut cloned_self = self.clone();
+                        // the clone has its own handle, the accept loop keeps none
+                        self.connections.remove(&client_key);
+                        let guard = self.control.connection();
 
                         thread::spawn(move || {
-                            cloned_self.handle_connection(client_key);
+                            let _guard = guard;
+                            let _permit = permit;
+                            let result = cloned_self.handle_connection(client_key);
+                            cloned_self.shutdown_client(client_key);
+                            match result {
+                                Ok(()) | Err(IOError::EmptyData) => {}
+                                Err(e) => println!("{}", e),
+                            }
                         });
-
-                        self.poller
-                            .modify(&self.listener, Event::readable(self.listener_key))
-                            .unwrap();
                     }
                 }
             }
+
+            self.poller.delete(&self.listener).unwrap();
+            shutdown_listener(&self.listener);
+        }
+
+        fn control(&self) -> EntryControl {
+            self.control.clone()
         }
     }
 
@@ -112,15 +184,27 @@ pub mod websocket_entry_nonblocking {
                 address: self.address.clone(),
                 listener: self.listener.try_clone().unwrap(),
                 listener_key: self.listener_key.clone(),
+                next_key: self.next_key,
                 connections: connections,
-                pipeline: self.pipeline.clone(),
+                pipeline: self.control.pipeline(),
+                control: self.control.clone(),
                 loop_time: self.loop_time,
+                keepalive: self.keepalive,
+                deflate: self.deflate,
+                rates: self.rates.clone(),
+                limits: self.limits.clone(),
+                access: self.access.clone(),
             }
         }
     }
 
     impl WSEntryNonBlocking {
-        fn handshake(mut stream: TcpStream) -> std::io::Result<()> {
+        /// Answers the upgrade request of a client, taking its
+        /// permessage-deflate offer when `deflate` allows.
+        fn handshake(
+            mut stream: TcpStream,
+            deflate: Option<DeflatePolicy>,
+        ) -> std::io::Result<Option<DeflateParams>> {
             let read_size = get_available_bytes(&mut stream)?;
             let mut buffer = vec![0u8; read_size];
 
@@ -161,13 +245,10 @@ pub mod websocket_entry_nonblocking {
                 return Err(e);
             }
 
-            let magic_string = websocket_key + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-            let mut hasher = Sha1::new();
-            hasher.update(magic_string.as_bytes());
-            let result = hasher.finish();
-            let accept_key = base64::encode(result);
+            let accept_key = accept_key(&websocket_key);
+            let agreed = deflate.and_then(|deflate| deflate.accept(request.headers()));
 
-            let response = response::Builder::new()
+            let mut response = response::Builder::new()
                 .version(Version::HTTP_11)
                 .status(101)
                 .header("Connection", "Upgrade")
@@ -177,16 +258,41 @@ pub mod websocket_entry_nonblocking {
                 .header("Connection", "keep-alive")
                 .header("Keep-Alive", "timeout=6553600")
                 .header("Upgrade-Insecure-Requests", "1")
-                .header("custom-header", "1")
-                .body(vec![0u8; 0])
-                .unwrap();
+                .header("custom-header", "1");
+            if let Some((_, answer)) = &agreed {
+                response = response.header(SEC_WEBSOCKET_EXTENSIONS, answer.as_str());
+            }
+            let response = response.body(vec![0u8; 0]).unwrap();
 
             write_response(stream, response)?;
-            Ok(())
+            Ok(agreed.map(|(params, _)| params))
+        }
+
+        /// Turns a client away before its upgrade, without waiting for the
+        /// request so that the accept loop is not held up.
+        fn write_service_unavailable(stream: TcpStream) {
+            let response = response::Builder::new()
+                .version(Version::HTTP_11)
+                .status(503)
+                .header("Connection", "close")
+                .body("Service Unavailable".as_bytes().to_vec())
+                .unwrap();
+            if let Err(e) = write_response(&stream, response) {
+                println!("Error writing to stream: {}", e);
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        /// Ends the connection even while other handles on the stream are
+        /// still around.
+        fn shutdown_client(&self, client_key: usize) {
+            if let Some(client) = self.connections.get(&client_key) {
+                let _ = client.0.shutdown(Shutdown::Both);
+            }
         }
 
         fn handle_connection(&mut self, client_key: usize) -> Result<(), IOError> {
-            self.pipeline.start();
+            self.pipeline.start()?;
 
             let client = self.connections.get_mut(&client_key).unwrap();
             client.0.set_nonblocking(true)?;
@@ -203,46 +309,110 @@ pub mod websocket_entry_nonblocking {
 
             let mut handshaked = false;
             let mut is_connected = true;
+            let mut keepalive = self.keepalive.map(Keepalive::new);
+            let mut frames = FrameReader::new();
+            let mut writer = FrameWriter::server();
+            let mut rate = self.rates.client();
+            // read from the pipeline and over the download rate so far
+            let mut held: Vec<u8> = Vec::new();
 
             loop {
                 thread::sleep(Duration::from_millis(10));
+                if self.control.is_closing() {
+                    if handshaked {
+                        let msg = Message::close(Some((1001, "server shutting down".to_string())));
+                        let mut buf: BytesMut = BytesMut::new();
+                        MessageCodec::server().encode(&msg, &mut buf)?;
+                        let _ = client.0.write_all(&buf);
+                        let _ = client.0.flush();
+                    }
+                    break;
+                }
+                if handshaked {
+                    if let Some(keepalive) = keepalive.as_mut() {
+                        let pinged = keepalive.poll().and_then(|payload| match payload {
+                            Some(payload) => {
+                                let mut buf: BytesMut = BytesMut::new();
+                                MessageCodec::server()
+                                    .encode(&Message::ping(payload), &mut buf)
+                                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                                client.0.write_all(&buf)
+                            }
+                            None => Ok(()),
+                        });
+                        if let Err(e) = pinged {
+                            println!("{} : {}", client.1, e);
+                            break;
+                        }
+                    }
+                }
                 self.poller.wait(&mut events, None)?;
 
                 for ev in events.iter() {
                     if ev.key == client_key {
-                        if ev.readable {
+                        // over the rate, what the client sends waits in the socket
+                        let allowance = rate.allowance(Transfer::Upload);
+                        if ev.readable && (!handshaked || allowance > 0) {
                             if !handshaked {
-                                if let Err(e) = WSEntryNonBlocking::handshake(client.0.try_clone()?)
-                                {
-                                    is_connected = false;
-                                    break;
-                                } else {
-                                    handshaked = true;
-                                    continue;
+                                match WSEntryNonBlocking::handshake(
+                                    client.0.try_clone()?,
+                                    self.deflate,
+                                ) {
+                                    Err(e) => {
+                                        is_connected = false;
+                                        break;
+                                    }
+                                    Ok(agreed) => {
+                                        let (deflater, inflater) =
+                                            agreed.map(|params| params.server()).unzip();
+                                        frames.set_inflater(inflater);
+                                        writer.set_deflater(deflater);
+                                        handshaked = true;
+                                        if let Some(keepalive) = keepalive.as_mut() {
+                                            keepalive.reset();
+                                        }
+                                        continue;
+                                    }
                                 }
                             }
                             match WSEntryNonBlocking::len(&mut client.0) {
                                 Ok(len) => {
                                     if len > 0 {
                                         let mut buf: BytesMut = BytesMut::new();
-                                        buf.resize(len, 0u8);
-                                        if let Err(e) = client.0.read(buf.as_mut()) {
+                                        buf.resize(len.min(allowance), 0u8);
+                                        match client.0.read(buf.as_mut()) {
+                                            Ok(read) => {
+                                                buf.truncate(read);
+                                                rate.take(Transfer::Upload, read);
+                                            }
+                                            Err(e) => {
+                                                println!("Error reading from stream: {}", e);
+                                                is_connected = false;
+                                                break;
+                                            }
+                                        }
+
+                                        if let Err(e) = frames.push(&buf) {
                                             println!("Error reading from stream: {}", e);
                                             is_connected = false;
                                             break;
                                         }
 
-                                        let mut msgc = websocket_codec::MessageCodec::server()
-                                            .decode(&mut buf);
-
-                                        match &mut msgc {
-                                            Ok(msgc) => match msgc {
-                                                Some(msg) => match msg.opcode() {
-                                                    websocket_codec::Opcode::Text
-                                                    | websocket_codec::Opcode::Binary => match self
-                                                        .pipeline
-                                                        .write(msg.data().to_vec())
-                                                    {
+                                        // a read may end inside a frame, or hold several
+                                        loop {
+                                            let msg = match frames.next_message() {
+                                                Ok(Some(msg)) => msg,
+                                                Ok(None) => break,
+                                                Err(e) => {
+                                                    println!("Error reading from stream: {}", e);
+                                                    is_connected = false;
+                                                    break;
+                                                }
+                                            };
+                                            match msg.opcode() {
+                                                websocket_codec::Opcode::Text
+                                                | websocket_codec::Opcode::Binary => {
+                                                    match self.pipeline.write(msg.data().to_vec()) {
                                                         Ok(size) => {}
                                                         Err(e) => match e {
                                                             IOError::InvalidConnection
@@ -252,34 +422,40 @@ pub mod websocket_entry_nonblocking {
                                                             | IOError::ParseError
                                                             | IOError::InvalidStep(_)
                                                             | IOError::InvalidData(_)
+                                                            | IOError::InvalidConfig(_)
+                                                            | IOError::Context { .. }
                                                             | IOError::Error(_) => {
                                                                 return Err(e);
                                                             }
                                                             IOError::EmptyData => {}
                                                         },
-                                                    },
-                                                    websocket_codec::Opcode::Close => {
-                                                        is_connected = false;
-                                                        break;
                                                     }
-                                                    websocket_codec::Opcode::Ping
-                                                    | websocket_codec::Opcode::Pong => {}
-                                                },
-                                                None => {
-                                                    println!(
-                                                        "Error reading from stream: {}",
-                                                        "Invalid Websocket Message"
-                                                    );
+                                                }
+                                                websocket_codec::Opcode::Close => {
                                                     is_connected = false;
                                                     break;
                                                 }
-                                            },
-                                            Err(e) => {
-                                                println!("Error reading from stream: {}", e);
-                                                is_connected = false;
-                                                break;
+                                                websocket_codec::Opcode::Ping => {
+                                                    let pong = Message::pong(msg.data().clone());
+                                                    let mut buf: BytesMut = BytesMut::new();
+                                                    MessageCodec::server()
+                                                        .encode(&pong, &mut buf)?;
+                                                    if let Err(e) = client.0.write_all(&buf) {
+                                                        println!("Error writing to stream: {}", e);
+                                                        is_connected = false;
+                                                        break;
+                                                    }
+                                                }
+                                                websocket_codec::Opcode::Pong => {
+                                                    if let Some(keepalive) = keepalive.as_mut() {
+                                                        keepalive.pong(msg.data());
+                                                    }
+                                                }
                                             }
                                         }
+                                        if !is_connected {
+                                            break;
+                                        }
                                     } else {
                                         println!("Error reading from stream: {}", "Zero Length");
                                         is_connected = false;
@@ -295,27 +471,9 @@ pub mod websocket_entry_nonblocking {
                         }
 
                         if ev.writable && handshaked {
-                            if self.pipeline.read_available() {
+                            if held.is_empty() && self.pipeline.read_available() {
                                 match self.pipeline.read() {
-                                    Ok(data) => {
-                                        if !data.is_empty() {
-                                            let msg = Message::binary(data);
-                                            let mut buf: BytesMut = BytesMut::new();
-                                            MessageCodec::server().encode(&msg, &mut buf)?;
-
-                                            if let Err(e) = client.0.write(buf.to_vec().as_slice())
-                                            {
-                                                println!("Error writing to stream: {}", e);
-                                                is_connected = false;
-                                                break;
-                                            }
-                                            if let Err(e) = client.0.flush() {
-                                                println!("Error flush stream: {}", e);
-                                                is_connected = false;
-                                                break;
-                                            }
-                                        }
-                                    }
+                                    Ok(data) => held = data,
                                     Err(e) => match e {
                                         IOError::InvalidConnection
                                         | IOError::InvalidBindAddress
@@ -324,6 +482,8 @@ pub mod websocket_entry_nonblocking {
                                         | IOError::ParseError
                                         | IOError::InvalidStep(_)
                                         | IOError::InvalidData(_)
+                                        | IOError::InvalidConfig(_)
+                                        | IOError::Context { .. }
                                         | IOError::Error(_) => {
                                             client.0.shutdown(Shutdown::Both)?;
                                             return Err(e);
@@ -332,6 +492,27 @@ pub mod websocket_entry_nonblocking {
                                     },
                                 }
                             }
+
+                            let allowance = rate.allowance(Transfer::Download);
+                            if !held.is_empty() && allowance > 0 {
+                                let data = match held.len() > allowance {
+                                    true => held.drain(..allowance).collect(),
+                                    false => std::mem::take(&mut held),
+                                };
+                                rate.take(Transfer::Download, data.len());
+                                let buf = writer.encode(&Message::binary(data))?;
+
+                                if let Err(e) = client.0.write(buf.as_slice()) {
+                                    println!("Error writing to stream: {}", e);
+                                    is_connected = false;
+                                    break;
+                                }
+                                if let Err(e) = client.0.flush() {
+                                    println!("Error flush stream: {}", e);
+                                    is_connected = false;
+                                    break;
+                                }
+                            }
                         } else {
                             // is_connected = false;
                             // break;
@@ -350,6 +531,7 @@ pub mod websocket_entry_nonblocking {
                 "client disconnected, key : {}, address : {} ",
                 client_key, client.1
             );
+            self.pipeline.close();
             client.0.shutdown(Shutdown::Both)?;
             Ok(())
         }
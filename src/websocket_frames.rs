@@ -0,0 +1,371 @@
+pub mod websocket_frames {
+    use bytes::{Buf, BytesMut};
+    use websocket_codec::{Message, Opcode};
+
+    use crate::{Deflater, IOError, Inflater};
+
+    /// Most undecoded bytes a connection may hold, so a peer announcing a
+    /// huge frame cannot make us buffer without end.
+    pub const MAX_FRAME_BUFFER: usize = 16 * 1024 * 1024;
+
+    /// Marks the first frame of a compressed message.
+    const RSV1: u8 = 0x40;
+    const RSV2_RSV3: u8 = 0x30;
+
+    /// A data message still waiting for its last frame.
+    struct Partial {
+        opcode: Opcode,
+        compressed: bool,
+        data: BytesMut,
+    }
+
+    /// Decodes the messages of one websocket connection from what it reads.
+    /// Frames split across reads and fragmented messages are kept until they
+    /// are complete, several frames in one read are all returned. Compressed
+    /// messages are inflated once permessage-deflate was agreed on.
+    #[derive(Default)]
+    pub struct FrameReader {
+        buffer: BytesMut,
+        partial: Option<Partial>,
+        inflater: Option<Inflater>,
+    }
+
+    impl FrameReader {
+        pub fn new() -> Self {
+            FrameReader::default()
+        }
+
+        /// Forgets what was read so far and the compression, for a new
+        /// connection.
+        pub fn reset(&mut self) {
+            *self = FrameReader::new();
+        }
+
+        /// Inflates the messages that come compressed from now on.
+        pub fn set_inflater(&mut self, inflater: Option<Inflater>) {
+            self.inflater = inflater;
+        }
+
+        pub fn push(&mut self, data: &[u8]) -> Result<(), IOError> {
+            if self.buffer.len() + data.len() > MAX_FRAME_BUFFER {
+                return Err(IOError::InvalidData(format!(
+                    "websocket frame larger than {} bytes",
+                    MAX_FRAME_BUFFER
+                )));
+            }
+            self.buffer.extend_from_slice(data);
+            Ok(())
+        }
+
+        /// The next complete message, `None` until more data is pushed.
+        pub fn next_message(&mut self) -> Result<Option<Message>, IOError> {
+            loop {
+                let (header_len, data_len, mask) = match parse_header(&self.buffer) {
+                    Some(header) => header,
+                    None => return Ok(None),
+                };
+                if data_len > MAX_FRAME_BUFFER {
+                    return Err(invalid(format!("frame of {} bytes", data_len)));
+                }
+                if self.buffer.len() < header_len + data_len {
+                    return Ok(None);
+                }
+
+                let first = self.buffer[0];
+                let mut data = self.buffer.split_to(header_len + data_len);
+                data.advance(header_len);
+                if let Some(mask) = mask {
+                    for (i, byte) in data.iter_mut().enumerate() {
+                        *byte ^= mask[i % 4];
+                    }
+                }
+
+                let fin = first & 0x80 != 0;
+                let compressed = first & RSV1 != 0;
+                if first & RSV2_RSV3 != 0 || (compressed && self.inflater.is_none()) {
+                    return Err(invalid(format!("reserved bits set : 0x{:x}", first & 0x70)));
+                }
+
+                let opcode = match first & 0x0f {
+                    0 => None,
+                    code => Some(
+                        Opcode::try_from(code)
+                            .ok_or_else(|| invalid(format!("unknown opcode {}", code)))?,
+                    ),
+                };
+
+                match (opcode, self.partial.as_mut()) {
+                    (Some(opcode), _) if opcode.is_control() => {
+                        if !fin || compressed || data_len > 125 {
+                            return Err(invalid(format!("malformed {:?} frame", opcode)));
+                        }
+                        return self.message(opcode, false, data).map(Some);
+                    }
+                    (Some(opcode), None) => {
+                        if fin {
+                            return self.message(opcode, compressed, data).map(Some);
+                        }
+                        self.partial = Some(Partial {
+                            opcode,
+                            compressed,
+                            data,
+                        });
+                    }
+                    (None, Some(partial)) => {
+                        if compressed {
+                            return Err(invalid("continuation frame with RSV1".to_string()));
+                        }
+                        if partial.data.len() + data.len() > MAX_FRAME_BUFFER {
+                            return Err(invalid(format!(
+                                "message larger than {} bytes",
+                                MAX_FRAME_BUFFER
+                            )));
+                        }
+                        partial.data.extend_from_slice(&data);
+                        if fin {
+                            let partial = self.partial.take().unwrap();
+                            return self
+                                .message(partial.opcode, partial.compressed, partial.data)
+                                .map(Some);
+                        }
+                    }
+                    (Some(opcode), Some(_)) => {
+                        return Err(invalid(format!(
+                            "{:?} frame inside a fragmented message",
+                            opcode
+                        )))
+                    }
+                    (None, None) => {
+                        return Err(invalid("continuation frame without a message".to_string()))
+                    }
+                }
+            }
+        }
+
+        fn message(
+            &mut self,
+            opcode: Opcode,
+            compressed: bool,
+            data: BytesMut,
+        ) -> Result<Message, IOError> {
+            let data = match self.inflater.as_mut() {
+                Some(inflater) if compressed => inflater.inflate(&data, MAX_FRAME_BUFFER)?.into(),
+                _ => data.freeze(),
+            };
+            Message::new(opcode, data).map_err(|e| invalid(e.to_string()))
+        }
+    }
+
+    /// Encodes the messages one end of a connection sends, masked when it is
+    /// the client, and compressed once permessage-deflate was agreed on.
+    pub struct FrameWriter {
+        masked: bool,
+        deflater: Option<Deflater>,
+    }
+
+    impl FrameWriter {
+        pub fn client() -> Self {
+            FrameWriter {
+                masked: true,
+                deflater: None,
+            }
+        }
+
+        pub fn server() -> Self {
+            FrameWriter {
+                masked: false,
+                deflater: None,
+            }
+        }
+
+        /// Compresses the data messages written from now on.
+        pub fn set_deflater(&mut self, deflater: Option<Deflater>) {
+            self.deflater = deflater;
+        }
+
+        pub fn encode(&mut self, msg: &Message) -> Result<Vec<u8>, IOError> {
+            let opcode = msg.opcode();
+            let (first, payload) = match self.deflater.as_mut() {
+                Some(deflater) if !opcode.is_control() => (
+                    0x80 | RSV1 | u8::from(opcode),
+                    deflater.deflate(msg.data())?,
+                ),
+                _ => (0x80 | u8::from(opcode), msg.data().to_vec()),
+            };
+
+            let mut frame = Vec::with_capacity(payload.len() + 14);
+            frame.push(first);
+            let mask_bit = if self.masked { 0x80 } else { 0 };
+            match payload.len() {
+                len if len < 126 => frame.push(mask_bit | len as u8),
+                len if len <= u16::MAX as usize => {
+                    frame.push(mask_bit | 126);
+                    frame.extend_from_slice(&(len as u16).to_be_bytes());
+                }
+                len => {
+                    frame.push(mask_bit | 127);
+                    frame.extend_from_slice(&(len as u64).to_be_bytes());
+                }
+            }
+            if self.masked {
+                let mask: [u8; 4] = rand::random();
+                frame.extend_from_slice(&mask);
+                frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            } else {
+                frame.extend_from_slice(&payload);
+            }
+            Ok(frame)
+        }
+    }
+
+    /// Header length, payload length and mask of the frame `buffer` starts
+    /// with, `None` while the header is incomplete.
+    fn parse_header(buffer: &[u8]) -> Option<(usize, usize, Option<[u8; 4]>)> {
+        if buffer.len() < 2 {
+            return None;
+        }
+        let masked = buffer[1] & 0x80 != 0;
+        let (mut header_len, data_len) = match buffer[1] & 0x7f {
+            126 => (
+                4,
+                u16::from_be_bytes(buffer.get(2..4)?.try_into().ok()?) as u64,
+            ),
+            127 => (10, u64::from_be_bytes(buffer.get(2..10)?.try_into().ok()?)),
+            len => (2, len as u64),
+        };
+        let mask = if masked {
+            let mask: [u8; 4] = buffer.get(header_len..header_len + 4)?.try_into().ok()?;
+            header_len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        Some((
+            header_len,
+            usize::try_from(data_len).unwrap_or(usize::MAX),
+            mask,
+        ))
+    }
+
+    fn invalid(reason: String) -> IOError {
+        IOError::InvalidData(format!("invalid websocket frame : {}", reason))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// An unmasked frame with the first header byte `first`.
+        fn frame(first: u8, payload: &[u8]) -> Vec<u8> {
+            let mut frame = vec![first, payload.len() as u8];
+            frame.extend_from_slice(payload);
+            frame
+        }
+
+        fn binary(data: &[u8]) -> Message {
+            Message::new(Opcode::Binary, data.to_vec()).unwrap()
+        }
+
+        #[test]
+        fn frames_split_across_reads() {
+            let mut writer = FrameWriter::client();
+            let mut bytes = writer.encode(&binary(b"hello")).unwrap();
+            bytes.extend(writer.encode(&binary(&[7u8; 300])).unwrap());
+
+            let mut reader = FrameReader::new();
+            let mut messages = Vec::new();
+            for byte in bytes {
+                reader.push(&[byte]).unwrap();
+                while let Some(msg) = reader.next_message().unwrap() {
+                    messages.push(msg);
+                }
+            }
+            assert_eq!(messages.len(), 2);
+            assert_eq!(&messages[0].data()[..], b"hello");
+            assert_eq!(&messages[1].data()[..], &[7u8; 300][..]);
+        }
+
+        #[test]
+        fn fragmented_message_with_control_frame_inside() {
+            let mut reader = FrameReader::new();
+            reader.push(&frame(0x02, b"abc")).unwrap();
+            reader.push(&frame(0x89, b"hi")).unwrap();
+            reader.push(&frame(0x00, b"def")).unwrap();
+            reader.push(&frame(0x80, b"ghi")).unwrap();
+
+            let ping = reader.next_message().unwrap().unwrap();
+            assert_eq!(ping.opcode(), Opcode::Ping);
+            assert_eq!(&ping.data()[..], b"hi");
+            let msg = reader.next_message().unwrap().unwrap();
+            assert_eq!(msg.opcode(), Opcode::Binary);
+            assert_eq!(&msg.data()[..], b"abcdefghi");
+            assert!(reader.next_message().unwrap().is_none());
+        }
+
+        #[test]
+        fn data_frame_inside_a_fragmented_message() {
+            let mut reader = FrameReader::new();
+            reader.push(&frame(0x02, b"abc")).unwrap();
+            reader.push(&frame(0x82, b"def")).unwrap();
+            assert!(reader.next_message().is_err());
+        }
+
+        #[test]
+        fn malformed_control_frames() {
+            let mut reader = FrameReader::new();
+            reader.push(&frame(0x09, b"hi")).unwrap();
+            assert!(reader.next_message().is_err());
+
+            let mut reader = FrameReader::new();
+            reader.push(&frame(0x80, b"abc")).unwrap();
+            assert!(reader.next_message().is_err());
+        }
+
+        #[test]
+        fn reserved_bits() {
+            for first in [0x82 | 0x20, 0x82 | 0x10, 0x82 | RSV1] {
+                let mut reader = FrameReader::new();
+                reader.push(&frame(first, b"abc")).unwrap();
+                assert!(reader.next_message().is_err(), "0x{:x}", first);
+            }
+
+            let mut reader = FrameReader::new();
+            reader.set_inflater(Some(Inflater::new(false)));
+            reader.push(&frame(0x02 | RSV1, b"abc")).unwrap();
+            reader.push(&frame(0x80 | RSV1, b"def")).unwrap();
+            assert!(reader.next_message().is_err());
+        }
+
+        #[test]
+        fn compressed_round_trip() {
+            let mut writer = FrameWriter::server();
+            writer.set_deflater(Some(Deflater::new(false)));
+            let mut reader = FrameReader::new();
+            reader.set_inflater(Some(Inflater::new(false)));
+
+            for _ in 0..3 {
+                let bytes = writer.encode(&binary(&[42u8; 1000])).unwrap();
+                assert!(bytes[0] & RSV1 != 0 && bytes.len() < 1000);
+                reader.push(&bytes).unwrap();
+                let msg = reader.next_message().unwrap().unwrap();
+                assert_eq!(&msg.data()[..], &[42u8; 1000][..]);
+            }
+        }
+
+        #[test]
+        fn oversized_frame() {
+            let mut header = vec![0x82, 127];
+            header.extend_from_slice(&(MAX_FRAME_BUFFER as u64 + 1).to_be_bytes());
+            let mut reader = FrameReader::new();
+            reader.push(&header).unwrap();
+            assert!(reader.next_message().is_err());
+        }
+
+        #[test]
+        fn buffer_is_capped() {
+            let mut reader = FrameReader::new();
+            reader.push(&vec![0u8; MAX_FRAME_BUFFER]).unwrap();
+            assert!(reader.push(&[0]).is_err());
+        }
+    }
+}
@@ -0,0 +1,156 @@
+pub mod websocket_handshake {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use http::{
+        header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS, UPGRADE},
+        HeaderMap, Method, Request, Response, StatusCode,
+    };
+    use openssl::sha::Sha1;
+    use std::{
+        io::{Read, Write},
+        os::fd::AsRawFd,
+    };
+
+    use crate::{
+        read_response, read_response_head, write_request, DeflateParams, DeflatePolicy, IOError,
+    };
+
+    /// Appended to the client key before hashing, see RFC 6455 section 1.3.
+    const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+    /// The `Sec-WebSocket-Accept` value a server answers `key` with.
+    pub fn accept_key(key: &str) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(key.as_bytes());
+        hasher.update(WEBSOCKET_GUID.as_bytes());
+        STANDARD.encode(hasher.finish())
+    }
+
+    /// A fresh random `Sec-WebSocket-Key`.
+    pub fn generate_key() -> Result<String, IOError> {
+        let mut key = [0u8; 16];
+        openssl::rand::rand_bytes(&mut key).map_err(|e| IOError::UnknownError(e.to_string()))?;
+        Ok(STANDARD.encode(key))
+    }
+
+    /// Upgrades `stream` to a WebSocket connection with `host` and checks the
+    /// server really accepted it. Frames the server sends right after its
+    /// answer are left in the stream. With `deflate`, offers permessage-deflate
+    /// and returns what the server agreed on, `None` when it did not take it.
+    pub fn client_handshake<T: Read + Write + AsRawFd>(
+        stream: &mut T,
+        host: &str,
+        deflate: Option<&DeflatePolicy>,
+    ) -> Result<Option<DeflateParams>, IOError> {
+        let (key, request) = handshake_request(host, "/", deflate)?;
+        write_request(&mut *stream, &request)?;
+        let response = read_response(stream, &Method::GET)?;
+        handshake_answer(&response, &key, deflate)
+    }
+
+    /// `client_handshake` for `path` over a stream that cannot be peeked,
+    /// such as a TLS one. The body of a refusal is left unread.
+    pub fn tls_client_handshake<T: Read + Write>(
+        stream: &mut T,
+        host: &str,
+        path: &str,
+        deflate: Option<&DeflatePolicy>,
+    ) -> Result<Option<DeflateParams>, IOError> {
+        let (key, request) = handshake_request(host, path, deflate)?;
+        write_request(&mut *stream, &request)?;
+        let response = read_response_head(stream)?;
+        handshake_answer(&response, &key, deflate)
+    }
+
+    /// The upgrade request, with the key the answer must be derived from.
+    fn handshake_request(
+        host: &str,
+        path: &str,
+        deflate: Option<&DeflatePolicy>,
+    ) -> Result<(String, Request<Vec<u8>>), IOError> {
+        let key = generate_key()?;
+        let mut request = Request::builder()
+            .method(Method::GET)
+            .uri(path)
+            .header("Host", host)
+            .header("Accept", "text/html; charset=utf-8")
+            .header("Keep-Alive", "timeout=6553600")
+            .header(
+                "User-Agent",
+                "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0",
+            )
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Key", key.as_str())
+            .header("Sec-WebSocket-Version", "13")
+            .header("Upgrade-Insecure-Requests", "1");
+        if let Some(deflate) = deflate {
+            request = request.header(SEC_WEBSOCKET_EXTENSIONS, deflate.offer());
+        }
+        let request: Request<Vec<u8>> = request
+            .body(vec![0; 0])
+            .map_err(|e| IOError::InvalidData(e.to_string()))?;
+        Ok((key, request))
+    }
+
+    fn handshake_answer(
+        response: &Response<Vec<u8>>,
+        key: &str,
+        deflate: Option<&DeflatePolicy>,
+    ) -> Result<Option<DeflateParams>, IOError> {
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            let msg = std::str::from_utf8(response.body()).unwrap_or("");
+            return Err(IOError::InvalidData(format!(
+                "handshake rejected with {} {}",
+                response.status(),
+                msg
+            )));
+        }
+        check_response(response.headers(), key)?;
+
+        match deflate {
+            Some(deflate) => deflate.agreed(response.headers()),
+            None if response.headers().contains_key(SEC_WEBSOCKET_EXTENSIONS) => {
+                Err(IOError::InvalidData(
+                    "handshake answer has an extension that was not offered".to_string(),
+                ))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn check_response(headers: &HeaderMap, key: &str) -> Result<(), IOError> {
+        if !has_token(headers, UPGRADE.as_str(), "websocket") {
+            return Err(IOError::InvalidData(
+                "handshake answer does not upgrade to websocket".to_string(),
+            ));
+        }
+        if !has_token(headers, CONNECTION.as_str(), "upgrade") {
+            return Err(IOError::InvalidData(
+                "handshake answer does not upgrade the connection".to_string(),
+            ));
+        }
+
+        let expected = accept_key(key);
+        match headers.get(SEC_WEBSOCKET_ACCEPT) {
+            Some(accept) if accept.as_bytes() == expected.as_bytes() => Ok(()),
+            Some(accept) => Err(IOError::InvalidData(format!(
+                "handshake answer has a wrong Sec-WebSocket-Accept : {}, expected {}",
+                String::from_utf8_lossy(accept.as_bytes()),
+                expected
+            ))),
+            None => Err(IOError::InvalidData(
+                "handshake answer has no Sec-WebSocket-Accept".to_string(),
+            )),
+        }
+    }
+
+    /// Whether one of the comma separated values of header `name` is `token`.
+    fn has_token(headers: &HeaderMap, name: &str, token: &str) -> bool {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    }
+}
@@ -0,0 +1,111 @@
+pub mod websocket_keepalive {
+    use std::{
+        io,
+        time::{Duration, Instant},
+    };
+
+    use crate::{IOError, SpecOptions};
+
+    pub const DEFAULT_PING_INTERVAL: u64 = 30_000;
+    pub const DEFAULT_PONG_TIMEOUT: u64 = 10_000;
+
+    /// How often a websocket pings its peer and how long it waits for the
+    /// pong, read from `?ping_interval=<ms, 0 to disable>&pong_timeout=<ms>`.
+    /// Pings are off unless one of them is given, since peers that do not
+    /// answer them would be dropped.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct KeepalivePolicy {
+        pub interval: Duration,
+        pub timeout: Duration,
+    }
+
+    impl KeepalivePolicy {
+        /// `None` when the spec does not ask for pings, or turns them off.
+        pub fn from_options(options: &SpecOptions) -> Result<Option<KeepalivePolicy>, IOError> {
+            let interval = options.get::<u64>("ping_interval")?;
+            let timeout = options.get::<u64>("pong_timeout")?;
+            if interval.is_none() && timeout.is_none() {
+                return Ok(None);
+            }
+            let interval = interval.unwrap_or(DEFAULT_PING_INTERVAL);
+            if interval == 0 {
+                return Ok(None);
+            }
+            let timeout = timeout.unwrap_or(DEFAULT_PONG_TIMEOUT);
+            if timeout == 0 {
+                return Err(IOError::InvalidConfig(
+                    "pong_timeout must be greater than zero".to_string(),
+                ));
+            }
+
+            Ok(Some(KeepalivePolicy {
+                interval: Duration::from_millis(interval),
+                timeout: Duration::from_millis(timeout),
+            }))
+        }
+    }
+
+    /// Keepalive state of one websocket connection : when the next ping is
+    /// due and which one is still waiting for its pong.
+    pub struct Keepalive {
+        policy: KeepalivePolicy,
+        next_ping: Instant,
+        awaiting: Option<(Instant, Vec<u8>)>,
+        sent: u64,
+    }
+
+    impl Keepalive {
+        pub fn new(policy: KeepalivePolicy) -> Self {
+            Keepalive {
+                policy,
+                next_ping: Instant::now() + policy.interval,
+                awaiting: None,
+                sent: 0,
+            }
+        }
+
+        pub fn policy(&self) -> KeepalivePolicy {
+            self.policy
+        }
+
+        /// Starts over, for a new connection.
+        pub fn reset(&mut self) {
+            self.next_ping = Instant::now() + self.policy.interval;
+            self.awaiting = None;
+        }
+
+        /// Ends the wait when `payload` answers the last ping. Unsolicited
+        /// pongs do not count.
+        pub fn pong(&mut self, payload: &[u8]) {
+            if let Some((_, expected)) = &self.awaiting {
+                if expected.as_slice() == payload {
+                    self.awaiting = None;
+                }
+            }
+        }
+
+        /// The payload of the ping to send now, if one is due. Fails once the
+        /// last ping went unanswered for longer than the timeout.
+        pub fn poll(&mut self) -> io::Result<Option<Vec<u8>>> {
+            let now = Instant::now();
+            if let Some((sent_at, _)) = &self.awaiting {
+                if now.duration_since(*sent_at) > self.policy.timeout {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no pong within {} ms", self.policy.timeout.as_millis()),
+                    ));
+                }
+                return Ok(None);
+            }
+            if now < self.next_ping {
+                return Ok(None);
+            }
+
+            self.sent += 1;
+            let payload = self.sent.to_be_bytes().to_vec();
+            self.awaiting = Some((now, payload.clone()));
+            self.next_ping = now + self.policy.interval;
+            Ok(Some(payload))
+        }
+    }
+}
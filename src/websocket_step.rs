@@ -1,32 +1,62 @@
 #[allow(non_snake_case, unused_variables, dead_code)]
 pub mod ws_destination {
     use bytes::BytesMut;
-    use http::{response, StatusCode, Version};
-    use hyper::{body::Body, Method, Request, Response, Uri};
+    use http::{response, Version};
+    use hyper::{body::Body, Response};
     use openssl::error;
     use polling::{Event, Events, Poller};
     use std::fmt::{Display, Error};
     use std::io::{self, Read, Write};
-    use std::net::TcpStream;
+    use std::net::{Shutdown, TcpStream};
     use std::os::fd::AsRawFd;
-    use std::str::{self, FromStr};
+    use std::str;
+    use std::sync::Arc;
     use std::time::Duration;
-    use tokio_util::codec::{Decoder, Encoder};
+    use tokio_util::codec::Encoder;
     use websocket_codec::{Message, MessageCodec};
 
     use crate::pipeline_module::pipeline::{IOError, PipelineDirection, PipelineStep};
     use crate::{
-        get_available_bytes, http_tools, read_response, write_request, BoxedClone,
-        WssDestination,
+        client_handshake, connect_timeout, get_available_bytes, http_tools, peer_closed,
+        BoxedClone, DeflateParams, DeflatePolicy, FrameReader, FrameWriter, HealthCheck, Keepalive,
+        KeepalivePolicy, ProbeKind, Reconnect, ReconnectPolicy, SpecOptions, UpstreamGuard,
+        Upstreams, WssDestination,
     };
 
     pub struct WebsocketDestination {
         tcp_stream: Option<TcpStream>,
         address: String,
+        socket_address: String,
+        upstreams: Arc<Upstreams>,
+        upstream: Option<UpstreamGuard>,
+        reconnect: Option<Reconnect>,
+        keepalive: Option<Keepalive>,
+        /// What was read of a message not complete yet.
+        frames: FrameReader,
+        writer: FrameWriter,
+        /// permessage-deflate offered to the server.
+        deflate: Option<DeflatePolicy>,
+        /// The server sent a close frame, reported once the data before it
+        /// is read.
+        closed_by_server: bool,
     }
 
     impl PipelineStep for WebsocketDestination {
         fn len(&mut self) -> std::io::Result<usize> {
+            if self.closed_by_server {
+                return Err(server_disconnected());
+            }
+            if self.reconnect.is_some() {
+                self.poll_connection()?;
+                if self.tcp_stream.is_none() {
+                    return Ok(0);
+                }
+            }
+            self.keep_alive()?;
+            if self.tcp_stream.is_none() {
+                return Ok(0);
+            }
+
             let mut available: usize = 0;
             let result: i32 = unsafe {
                 libc::ioctl(
@@ -47,43 +77,84 @@ pub mod ws_destination {
             // println!("{}", direction);
         }
 
-        fn start(&mut self) {
-            let uri: Uri = self.address.parse::<Uri>().unwrap();
-            let mut addr = String::from(uri.host().unwrap());
-            let mut port = 0;
-            if uri.port() != None {
-                port = uri.port().unwrap().as_u16();
-            } else {
-                port = match uri.scheme_str() {
-                    Some("ws") => 80,
-                    Some("wss") => 443,
-                    Some("http") => 80,
-                    Some("https") => 443,
-                    None | _ => {
-                        panic!("unknow uri scheme")
-                    }
-                };
+        fn start(&mut self) -> Result<(), IOError> {
+            let ((connection, agreed), upstream) = WebsocketDestination::connect(
+                &self.upstreams,
+                self.deflate.as_ref(),
+                self.connect_timeout(),
+            )?;
+            self.socket_address = upstream.address().to_string();
+            self.upstream = Some(upstream);
+            self.tcp_stream = Some(connection);
+            self.use_compression(agreed);
+            self.closed_by_server = false;
+            if let Some(keepalive) = self.keepalive.as_mut() {
+                keepalive.reset();
             }
+            Ok(())
+        }
 
-            addr.push_str(":");
-            addr.push_str(port.to_string().as_str());
-            let mut connection = TcpStream::connect(&addr).unwrap();
-            connection.set_nonblocking(false).unwrap();
+        fn close(&mut self) {
+            if let Some(mut stream) = self.tcp_stream.take() {
+                let msg = Message::close(Some((1001, "going away".to_string())));
+                let mut bytebuf: BytesMut = BytesMut::new();
+                if MessageCodec::client().encode(&msg, &mut bytebuf).is_ok() {
+                    let _ = stream.write_all(&bytebuf);
+                    let _ = stream.flush();
+                }
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            self.upstream = None;
+        }
 
-            WebsocketDestination::handshake(&mut connection, addr).unwrap();
+        fn take_reconnected(&mut self) -> bool {
+            match self.reconnect.as_mut() {
+                Some(reconnect) => reconnect.take_reconnected(),
+                None => false,
+            }
+        }
 
-            self.tcp_stream = Some(connection);
+        fn set_replay(&mut self, replay: bool) {
+            if let Some(reconnect) = self.reconnect.as_mut() {
+                reconnect.set_replay(replay);
+            }
         }
     }
 
     impl BoxedClone for WebsocketDestination {
         fn bclone(&self) -> Box<dyn PipelineStep> {
-            Box::new(WebsocketDestination::new(&self.address))
+            Box::new(WebsocketDestination {
+                tcp_stream: None,
+                address: self.address.clone(),
+                socket_address: self.socket_address.clone(),
+                upstreams: self.upstreams.clone(),
+                upstream: None,
+                reconnect: self.reconnect.as_ref().map(|r| Reconnect::new(r.policy())),
+                keepalive: self.keepalive.as_ref().map(|k| Keepalive::new(k.policy())),
+                frames: FrameReader::new(),
+                writer: FrameWriter::client(),
+                deflate: self.deflate,
+                closed_by_server: false,
+            })
         }
     }
 
     impl crate::Read for WebsocketDestination {
         fn read(&mut self) -> Result<Vec<u8>, IOError> {
+            if self.closed_by_server {
+                return Err(IOError::IoError(server_disconnected()));
+            }
+            if self.reconnect.is_some() {
+                self.poll_connection()?;
+                if self.tcp_stream.is_none() {
+                    return Err(IOError::EmptyData);
+                }
+            }
+            self.keep_alive()?;
+            if self.tcp_stream.is_none() {
+                return Err(IOError::EmptyData);
+            }
+
             let mut available: usize = 0;
             let result: i32 = unsafe {
                 libc::ioctl(
@@ -95,59 +166,85 @@ pub mod ws_destination {
 
             if result == -1 {
                 let errno = std::io::Error::last_os_error();
-                Err(IOError::IoError(errno))
+                return Err(IOError::IoError(errno));
             } else if available == 0 {
-                Err(IOError::EmptyData)
-            } else {
-                let mut byteData = BytesMut::new();
-                byteData.resize(available, 0);
-                if let Err(e) = self.get_stream().read(byteData.as_mut()) {
-                    return Err(IOError::IoError(e));
-                }
+                return Err(IOError::EmptyData);
+            }
 
-                match MessageCodec::client().decode(&mut byteData) {
-                    Ok(msg) => match msg {
-                        Some(msg) => match msg.opcode() {
-                            websocket_codec::Opcode::Text | websocket_codec::Opcode::Binary => {
-                                return Ok(msg.data().to_vec());
-                            }
-                            websocket_codec::Opcode::Close => {
-                                let e = io::Error::new(
-                                    io::ErrorKind::ConnectionAborted,
-                                    "server disconnected",
-                                );
-                                return Err(IOError::IoError(e));
-                            }
-                            websocket_codec::Opcode::Ping | websocket_codec::Opcode::Pong => {
-                                Err(IOError::EmptyData)
-                            }
-                        },
-                        None => {
-                            let e = io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                "no valid message found",
-                            );
-                            return Err(IOError::IoError(e));
+            let mut received = vec![0u8; available];
+            let size = self.get_stream().read(&mut received)?;
+            self.frames.push(&received[..size])?;
+
+            // every complete message, the tunnel carries a byte stream so
+            // data messages are simply joined
+            let mut data = Vec::new();
+            while let Some(msg) = self.frames.next_message()? {
+                match msg.opcode() {
+                    websocket_codec::Opcode::Text | websocket_codec::Opcode::Binary => {
+                        data.extend_from_slice(msg.data());
+                    }
+                    websocket_codec::Opcode::Close if self.reconnect.is_some() => {
+                        self.tcp_stream = None;
+                        self.upstream = None;
+                        if let Some(reconnect) = self.reconnect.as_mut() {
+                            reconnect.disconnected(&self.socket_address, "closed by server");
+                        }
+                        break;
+                    }
+                    websocket_codec::Opcode::Close => {
+                        // hand over what came before, and fail on the next call
+                        self.closed_by_server = true;
+                        break;
+                    }
+                    websocket_codec::Opcode::Ping => {
+                        self.send_frame(&Message::pong(msg.data().clone()))?;
+                    }
+                    websocket_codec::Opcode::Pong => {
+                        if let Some(keepalive) = self.keepalive.as_mut() {
+                            keepalive.pong(msg.data());
                         }
-                    },
-                    Err(e) => {
-                        let e = format!("{}", e);
-                        let e = io::Error::new(io::ErrorKind::Other, e);
-                        return Err(IOError::IoError(e));
                     }
                 }
             }
+
+            if data.is_empty() {
+                if self.closed_by_server {
+                    return Err(IOError::IoError(server_disconnected()));
+                }
+                return Err(IOError::EmptyData);
+            }
+            Ok(data)
         }
     }
 
     impl Write for WebsocketDestination {
         fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-            let vec_buf = Vec::from(buf);
-            let msg = Message::binary(vec_buf);
-            let mut bytebuf: BytesMut = BytesMut::new();
-            MessageCodec::client().encode(&msg, &mut bytebuf).unwrap();
+            if self.reconnect.is_some() {
+                self.poll_connection()?;
+                if let Some(reconnect) = self.reconnect.as_ref().filter(|r| r.is_stale()) {
+                    return Ok(reconnect.drop_stale(&self.socket_address, buf));
+                }
+                // encoded once connected, compression depends on the connection
+                let bytebuf = match self.tcp_stream {
+                    Some(_) => self.encode(buf)?,
+                    None => Vec::new(),
+                };
+                let reconnect = self.reconnect.as_mut().unwrap();
+                if let Some(stream) = self.tcp_stream.as_mut() {
+                    match stream.write_all(&bytebuf).and_then(|_| stream.flush()) {
+                        Ok(()) => return Ok(buf.len()),
+                        Err(e) => {
+                            self.tcp_stream = None;
+                            self.upstream = None;
+                            reconnect.disconnected(&self.socket_address, &e.to_string());
+                        }
+                    }
+                }
+                return reconnect.buffer(buf);
+            }
 
-            match self.get_stream().write(bytebuf.to_vec().as_slice()) {
+            let bytebuf = self.encode(buf)?;
+            match self.get_stream().write(bytebuf.as_slice()) {
                 Ok(size) => {
                     if let Err(e) = self.get_stream().flush() {
                         return Err(e);
@@ -159,112 +256,262 @@ pub mod ws_destination {
         }
 
         fn flush(&mut self) -> std::io::Result<()> {
-            self.get_stream().flush()
+            match self.tcp_stream.as_mut() {
+                Some(stream) => stream.flush(),
+                None if self.reconnect.is_some() => Ok(()),
+                None => self.get_stream().flush(),
+            }
         }
     }
 
     #[allow(unreachable_code)]
     impl WebsocketDestination {
-        pub fn new(address: &str) -> Self {
-            WebsocketDestination {
+        pub fn new(address: &str) -> Result<Self, IOError> {
+            let options = SpecOptions::parse(address);
+            let upstreams = Upstreams::from_spec(address)?;
+            if let Some(check) = HealthCheck::from_options(&options)? {
+                upstreams.start_health_checks(check, |address, check| match check.probe {
+                    ProbeKind::Tcp => connect_timeout(address, check.timeout).map(|_| ()),
+                    ProbeKind::Ws => WebsocketDestination::probe(address, check),
+                });
+            }
+            Ok(WebsocketDestination {
                 tcp_stream: None,
-                address: String::from_str(address).unwrap(),
-            }
-        }
-
-        fn handshake(mut stream: &mut TcpStream, address: String) -> Result<(), IOError> {
-            //send request
-            let mut rand_buf = [0u8; 16];
-            openssl::rand::rand_bytes(&mut rand_buf).unwrap();
-            let sec_websocket_key = base64::encode(rand_buf);
-
-            let request: Request<Vec<u8>> = Request::builder()
-                .method(Method::GET)
-                .uri("/")
-                .header("Host", address)
-                .header("Accept", "text/html; charset=utf-8")
-                .header("Keep-Alive","timeout=6553600")
-                .header("User-Agent", "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0")
-                .header("Upgrade", "websocket")
-                .header("Connection", "Upgrade")
-                .header("Sec-WebSocket-Key", sec_websocket_key)
-                .header("Sec-WebSocket-Version", "13")
-                .header("Upgrade-Insecure-Requests", "1")
-                .body(vec![0; 0])
-                .unwrap();
-
-            write_request(&mut stream, &request)?;
-            
-            let res = read_response(stream)?;
-
-            if res.status() != StatusCode::from_u16(101).unwrap() {
-                println!(
-                    "response: {}",
-                    std::str::from_utf8(res.body()).unwrap_or("")
-                );
-                let msg : String = std::str::from_utf8(res.body()).unwrap_or("").to_string();
-                return Err(IOError::InvalidData(msg));
+                address: address.to_string(),
+                socket_address: upstreams.address(0).to_string(),
+                upstreams,
+                upstream: None,
+                reconnect: ReconnectPolicy::from_options(&options)?.map(Reconnect::new),
+                keepalive: KeepalivePolicy::from_options(&options)?.map(Keepalive::new),
+                frames: FrameReader::new(),
+                writer: FrameWriter::client(),
+                deflate: DeflatePolicy::from_options(&options)?,
+                closed_by_server: false,
+            })
+        }
+
+        /// Reads and writes what follows with the compression the handshake
+        /// of a new connection agreed on.
+        fn use_compression(&mut self, agreed: Option<DeflateParams>) {
+            let (deflater, inflater) = agreed.map(|params| params.client()).unzip();
+            self.frames.reset();
+            self.frames.set_inflater(inflater);
+            self.writer.set_deflater(deflater);
+        }
+
+        fn encode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+            self.writer
+                .encode(&Message::binary(data.to_vec()))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+        }
+
+        fn send_frame(&mut self, msg: &Message) -> io::Result<()> {
+            let mut bytebuf: BytesMut = BytesMut::new();
+            MessageCodec::client()
+                .encode(msg, &mut bytebuf)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            match self.tcp_stream.as_mut() {
+                Some(stream) => stream.write_all(&bytebuf).and_then(|_| stream.flush()),
+                None => Ok(()),
             }
+        }
 
+        /// Pings the server when it is time, and gives up on the connection
+        /// once a ping goes unanswered, reconnecting when asked to.
+        fn keep_alive(&mut self) -> io::Result<()> {
+            let keepalive = match self.keepalive.as_mut() {
+                Some(keepalive) if self.tcp_stream.is_some() => keepalive,
+                _ => return Ok(()),
+            };
+            let result = match keepalive.poll() {
+                Ok(Some(payload)) => self.send_frame(&Message::ping(payload)),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            match (result, self.reconnect.as_mut()) {
+                (Err(e), Some(reconnect)) => {
+                    if let Some(stream) = self.tcp_stream.take() {
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+                    self.upstream = None;
+                    reconnect.disconnected(&self.socket_address, &e.to_string());
+                    Ok(())
+                }
+                (result, _) => result,
+            }
+        }
+
+        /// Connects and handshakes with the first upstream that accepts.
+        #[allow(clippy::type_complexity)]
+        fn connect(
+            upstreams: &Arc<Upstreams>,
+            deflate: Option<&DeflatePolicy>,
+            timeout: Option<Duration>,
+        ) -> Result<((TcpStream, Option<DeflateParams>), UpstreamGuard), IOError> {
+            upstreams.connect("ws step", |address| {
+                let mut connection = match timeout {
+                    Some(timeout) => connect_timeout(address, timeout)?,
+                    None => TcpStream::connect(address)?,
+                };
+                connection.set_nonblocking(false)?;
+                // the handshake is bounded too, then reads block as before
+                connection.set_read_timeout(timeout)?;
+                connection.set_write_timeout(timeout)?;
+                let agreed = client_handshake(&mut connection, address, deflate)?;
+                connection.set_read_timeout(None)?;
+                connection.set_write_timeout(None)?;
+                Ok((connection, agreed))
+            })
+        }
+
+        /// Reconnecting steps give up on silent upstreams, the others wait as
+        /// long as the system does.
+        fn connect_timeout(&self) -> Option<Duration> {
+            self.reconnect.as_ref().map(|r| r.policy().connect_timeout)
+        }
+
+        /// Health probe : a full handshake followed by a close frame.
+        fn probe(address: &str, check: &HealthCheck) -> Result<(), IOError> {
+            let mut connection = connect_timeout(address, check.timeout)?;
+            connection.set_read_timeout(Some(check.timeout))?;
+            connection.set_write_timeout(Some(check.timeout))?;
+            client_handshake(&mut connection, address, None)?;
+            let mut bytebuf: BytesMut = BytesMut::new();
+            if MessageCodec::client()
+                .encode(&Message::close(None), &mut bytebuf)
+                .is_ok()
+            {
+                let _ = connection.write_all(&bytebuf);
+            }
+            let _ = connection.shutdown(Shutdown::Both);
             Ok(())
         }
 
+        /// Notices when the server went away and, once the backoff delay is
+        /// over, connects and handshakes again, then replays what was written
+        /// meanwhile as a single message.
+        fn poll_connection(&mut self) -> io::Result<()> {
+            let reconnect = match self.reconnect.as_mut() {
+                Some(reconnect) => reconnect,
+                None => return Ok(()),
+            };
+            reconnect.check()?;
+
+            if let Some(stream) = self.tcp_stream.as_ref() {
+                if !peer_closed(stream) {
+                    return Ok(());
+                }
+                self.tcp_stream = None;
+                self.upstream = None;
+                reconnect.disconnected(&self.socket_address, "closed by peer");
+            }
+            if !reconnect.should_attempt() {
+                return Ok(());
+            }
+
+            match WebsocketDestination::connect(
+                &self.upstreams,
+                self.deflate.as_ref(),
+                Some(reconnect.policy().connect_timeout),
+            ) {
+                Ok(((mut connection, agreed), upstream)) => {
+                    self.socket_address = upstream.address().to_string();
+                    self.upstream = Some(upstream);
+                    let pending = reconnect.connected(&self.socket_address);
+                    self.use_compression(agreed);
+                    if let Some(keepalive) = self.keepalive.as_mut() {
+                        keepalive.reset();
+                    }
+                    if !pending.is_empty() {
+                        let bytebuf = self.encode(&pending)?;
+                        connection.write_all(&bytebuf)?;
+                    }
+                    self.tcp_stream = Some(connection);
+                    Ok(())
+                }
+                Err(e) => reconnect.failed(&self.socket_address, &e),
+            }
+        }
+
         fn get_stream(&self) -> &TcpStream {
             self.tcp_stream.as_ref().unwrap()
         }
     }
+
+    fn server_disconnected() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionAborted, "server disconnected")
+    }
 }
 
 pub mod wss_destination {
-    use core::panic;
     use openssl::ssl::{SslConnector, SslConnectorBuilder, SslMethod, SslStream};
-    use std::io::{Read, Write};
-    use std::net::TcpStream;
+    use std::io::{self, Read, Write};
+    use std::net::{Shutdown, TcpStream};
     use std::os::fd::AsRawFd;
-    use std::str::FromStr;
-    use tungstenite::client::IntoClientRequest;
-    use tungstenite::http::{HeaderName, Request, Uri};
-    use tungstenite::protocol::{Role, WebSocketContext};
-    use tungstenite::{client, Message, WebSocket};
+    use tungstenite::http::Uri;
+    use websocket_codec::{Message, Opcode};
 
-    use crate::{BoxedClone, IOError, PipelineStep};
+    use crate::{
+        socket_address, tls_client_handshake, BoxedClone, DeflateParams, DeflatePolicy,
+        FrameReader, FrameWriter, IOError, Keepalive, KeepalivePolicy, PipelineStep, SpecOptions,
+    };
+
+    /// Largest TLS record, read at once.
+    const READ_SIZE: usize = 16 * 1024;
 
     pub struct WssDestination {
-        tcp_stream: TcpStream,
-        ssl_stream: WebSocket<SslStream<TcpStream>>,
-        context: WebSocketContext,
+        tcp_stream: Option<TcpStream>,
+        ssl_stream: Option<SslStream<TcpStream>>,
         address: String,
+        socket_address: String,
+        keepalive: Option<Keepalive>,
+        /// What was read of a message not complete yet.
+        frames: FrameReader,
+        writer: FrameWriter,
+        /// permessage-deflate offered to the server.
+        deflate: Option<DeflatePolicy>,
+        /// Set once the server sent a close frame, after the data before it
+        /// is read.
+        closed_by_server: bool,
     }
 
     impl WssDestination {
-        pub fn new(address: &str) -> WssDestination {
-            let uri: Uri = address.parse::<Uri>().unwrap();
-            let mut addr = String::from(uri.host().unwrap());
-            let mut port = 0;
-            if uri.port() != None {
-                port = uri.port().unwrap().as_u16();
-            } else {
-                port = match uri.scheme_str() {
-                    Some("ws") => 80,
-                    Some("wss") => 443,
-                    Some("http") => 80,
-                    Some("https") => 443,
-                    None | _ => {
-                        panic!("unknow uri scheme")
-                    }
-                };
-            }
+        /// Only checks the address; the connection is made by `start`.
+        pub fn new(address: &str) -> Result<WssDestination, IOError> {
+            let socket_address = socket_address(address)?;
+            let options = SpecOptions::parse(address);
+            Ok(Self {
+                ssl_stream: None,
+                tcp_stream: None,
+                address: address.to_string(),
+                socket_address,
+                keepalive: KeepalivePolicy::from_options(&options)?.map(Keepalive::new),
+                frames: FrameReader::new(),
+                writer: FrameWriter::client(),
+                deflate: DeflatePolicy::from_options(&options)?,
+                closed_by_server: false,
+            })
+        }
 
-            addr.push_str(":");
-            addr.push_str(port.to_string().as_str());
-            let connection = TcpStream::connect(addr.clone()).unwrap();
+        #[allow(clippy::type_complexity)]
+        fn connect(
+            &self,
+        ) -> Result<(TcpStream, SslStream<TcpStream>, Option<DeflateParams>), IOError> {
+            // the options are ours, not part of the server url
+            let url = self.address.split('?').next().unwrap_or("");
+            let uri: Uri = url
+                .parse::<Uri>()
+                .map_err(|e| IOError::InvalidConfig(format!("invalid url {} : {}", url, e)))?;
+            let host = uri.host().unwrap_or("");
+            let connection = TcpStream::connect(&self.socket_address)?;
 
             #[cfg(feature = "has_not_builder")]
             let mut ssl_connector_builder: SslConnectorBuilder =
-                SslConnector::ConnectConfigurationbuilder(SslMethod::tls()).unwrap();
+                SslConnector::ConnectConfigurationbuilder(SslMethod::tls())
+                    .map_err(|e| IOError::Error(Box::new(e)))?;
             #[cfg(feature = "has_builder")]
             let mut ssl_connector_builder: SslConnectorBuilder =
-                SslConnector::builder(SslMethod::tls()).unwrap();
+                SslConnector::builder(SslMethod::tls()).map_err(|e| IOError::Error(Box::new(e)))?;
 
             // ssl_connector_builder.set_verify(SslVerifyMode::NONE);
             // ssl_connector_builder.set_verify_callback(SslVerifyMode::NONE, |r, context|{
@@ -275,104 +522,187 @@ pub mod wss_destination {
             // });
 
             let mut ssl_connector = ssl_connector_builder.build();
-            let ssl_connection = ssl_connector
+            let mut ssl_connection = ssl_connector
                 .configure()
-                .unwrap()
+                .map_err(|e| IOError::Error(Box::new(e)))?
                 // .verify_hostname(false)
                 // .use_server_name_indication(false)
-                .connect(uri.host().unwrap(), connection.try_clone().unwrap())
-                .unwrap();
+                .connect(host, connection.try_clone()?)
+                .map_err(|e| IOError::UnknownError(format!("tls handshake failed : {}", e)))?;
+
+            let authority = uri.authority().map(|a| a.as_str()).unwrap_or(host);
+            let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+            let deflate = self.deflate.as_ref();
+            let agreed = tls_client_handshake(&mut ssl_connection, authority, path, deflate)
+                .map_err(|e| {
+                    IOError::UnknownError(format!("websocket handshake failed : {}", e))
+                })?;
+
+            Ok((connection, ssl_connection, agreed))
+        }
 
-            let req: tungstenite::http::Request<()> = uri.into_client_request().unwrap();
-            // let r = ssl_connection.get_mut();
-            let (socket, _response) = client(req, ssl_connection).unwrap();
+        fn get_stream(&mut self) -> io::Result<&mut SslStream<TcpStream>> {
+            self.ssl_stream.as_mut().ok_or_else(not_connected)
+        }
 
-            Self {
-                ssl_stream: socket,
-                tcp_stream: connection,
-                context: WebSocketContext::new(Role::Client, None),
-                address: String::from_str(address).unwrap(),
+        fn send_message(&mut self, msg: &Message) -> io::Result<()> {
+            let frame = self
+                .writer
+                .encode(msg)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            let stream = self.get_stream()?;
+            stream.write_all(&frame)?;
+            stream.flush()
+        }
+
+        /// Pings the server when it is time, and fails once a ping goes
+        /// unanswered.
+        fn keep_alive(&mut self) -> io::Result<()> {
+            let payload = match self.keepalive.as_mut() {
+                Some(keepalive) => keepalive.poll()?,
+                None => None,
+            };
+            if let Some(payload) = payload {
+                self.send_message(&Message::ping(payload))?;
             }
+            Ok(())
         }
 
-        pub fn get_websocket(&mut self) -> WebSocket<&mut SslStream<TcpStream>> {
-            WebSocket::from_raw_socket(self.ssl_stream.get_mut(), Role::Client, None)
+        /// Bytes waiting, in the socket or already decrypted by the TLS
+        /// layer.
+        fn available(&mut self) -> io::Result<usize> {
+            let fd = match self.tcp_stream.as_ref() {
+                Some(stream) => stream.as_raw_fd(),
+                None => return Err(not_connected()),
+            };
+            let mut available: usize = 0;
+            let result: i32 = unsafe { libc::ioctl(fd, libc::FIONREAD, &mut available) };
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(available + self.get_stream()?.ssl().pending())
         }
     }
 
     impl PipelineStep for WssDestination {
         fn len(&mut self) -> std::io::Result<usize> {
-            let mut available: usize = 0;
-            let result: i32 =
-                unsafe { libc::ioctl(self.tcp_stream.as_raw_fd(), libc::FIONREAD, &mut available) };
-            if result == -1 {
-                let errno = std::io::Error::last_os_error();
-                Err(errno)
-            } else {
-                Ok(available)
+            if self.closed_by_server {
+                return Err(server_disconnected());
             }
+            self.keep_alive()?;
+            self.available()
         }
 
-        fn set_pipeline_direction(&mut self, direction: crate::PipelineDirection) {}
+        fn set_pipeline_direction(&mut self, _direction: crate::PipelineDirection) {}
 
-        fn start(&mut self) {}
+        fn start(&mut self) -> Result<(), IOError> {
+            let (connection, socket, agreed) = self
+                .connect()
+                .map_err(|e| e.context("wss step", &self.socket_address))?;
+            let (deflater, inflater) = agreed.map(|params| params.client()).unzip();
+            self.frames.reset();
+            self.frames.set_inflater(inflater);
+            self.writer.set_deflater(deflater);
+            self.tcp_stream = Some(connection);
+            self.ssl_stream = Some(socket);
+            if let Some(keepalive) = self.keepalive.as_mut() {
+                keepalive.reset();
+            }
+            Ok(())
+        }
+
+        fn close(&mut self) {
+            if self.ssl_stream.is_some() && !self.closed_by_server {
+                let _ = self.send_message(&Message::close(None));
+            }
+            if let Some(mut socket) = self.ssl_stream.take() {
+                let _ = socket.shutdown();
+            }
+            if let Some(stream) = self.tcp_stream.take() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
     }
 
     impl BoxedClone for WssDestination {
         fn bclone(&self) -> Box<dyn PipelineStep> {
-            Box::new(WssDestination::new(&self.address))
+            Box::new(WssDestination {
+                tcp_stream: None,
+                ssl_stream: None,
+                address: self.address.clone(),
+                socket_address: self.socket_address.clone(),
+                keepalive: self.keepalive.as_ref().map(|k| Keepalive::new(k.policy())),
+                frames: FrameReader::new(),
+                writer: FrameWriter::client(),
+                deflate: self.deflate,
+                closed_by_server: false,
+            })
         }
     }
 
     impl crate::Read for WssDestination {
         fn read(&mut self) -> Result<Vec<u8>, IOError> {
-            let mut available: usize = 0;
-            let result: i32 =
-                unsafe { libc::ioctl(self.tcp_stream.as_raw_fd(), libc::FIONREAD, &mut available) };
+            if self.closed_by_server {
+                return Err(IOError::IoError(server_disconnected()));
+            }
+            self.keep_alive()?;
+            if self.available()? == 0 {
+                return Err(IOError::EmptyData);
+            }
 
-            if result == -1 {
-                let errno = std::io::Error::last_os_error();
-                Err(IOError::IoError(errno))
-            } else if available == 0 {
-                Err(IOError::EmptyData)
-            } else {
-                let m = &mut self.get_websocket().read().unwrap();
-                // let mut m = &mut self
-                //     .context
-                //     .read::<TcpStream>(&mut self.tcp_stream)
-                //     .unwrap();
-                match m {
-                    Message::Text(data) => unsafe { Ok(data.as_bytes().to_vec()) },
-                    Message::Binary(data) => unsafe { Ok(data.clone()) },
-                    Message::Close(e) => {
-                        let errno = std::io::Error::last_os_error();
-                        Err(IOError::IoError(errno))
+            let mut received = vec![0u8; READ_SIZE];
+            let size = self.get_stream()?.read(&mut received)?;
+            if size == 0 {
+                return Err(IOError::IoError(server_disconnected()));
+            }
+            self.frames.push(&received[..size])?;
+
+            // every complete message, the tunnel carries a byte stream so
+            // data messages are simply joined
+            let mut data = Vec::new();
+            while let Some(msg) = self.frames.next_message()? {
+                match msg.opcode() {
+                    Opcode::Text | Opcode::Binary => data.extend_from_slice(msg.data()),
+                    Opcode::Close => {
+                        // hand over what came before, and fail on the next call
+                        self.closed_by_server = true;
+                        break;
                     }
-                    Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {
-                        Err(IOError::EmptyData)
+                    Opcode::Ping => self.send_message(&Message::pong(msg.data().clone()))?,
+                    Opcode::Pong => {
+                        if let Some(keepalive) = self.keepalive.as_mut() {
+                            keepalive.pong(msg.data());
+                        }
                     }
                 }
-                // self.tcp_stream.read(buf)
             }
+
+            if data.is_empty() {
+                if self.closed_by_server {
+                    return Err(IOError::IoError(server_disconnected()));
+                }
+                return Err(IOError::EmptyData);
+            }
+            Ok(data)
         }
     }
 
     impl Write for WssDestination {
         fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-            let vec = Vec::from(buf);
-            let msg = Message::Binary(vec);
-            let result = self.get_websocket().send(msg);
-            match result {
-                Ok(_) => Ok(buf.len()),
-                Err(error) => {
-                    panic!("{}", error);
-                }
-            }
+            self.send_message(&Message::binary(buf.to_vec()))?;
+            Ok(buf.len())
         }
 
         fn flush(&mut self) -> std::io::Result<()> {
-            self.get_websocket().flush().unwrap();
-            Ok(())
+            self.get_stream()?.flush()
         }
     }
+
+    fn not_connected() -> io::Error {
+        io::Error::new(io::ErrorKind::NotConnected, "wss step is not started")
+    }
+
+    fn server_disconnected() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionAborted, "server disconnected")
+    }
 }